@@ -18,10 +18,16 @@ mod pxe;
 mod intrins;
 
 use core::sync::atomic::{AtomicU64, AtomicU32, Ordering};
+use core::convert::TryInto;
+use alloc::vec::Vec;
 use serial::SerialPort;
 use boot_args::{BootArgs, KERNEL_PHYS_WINDOW_SIZE, KERNEL_STACKS_BASE};
 use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_STACK_SIZE, KERNEL_STACK_PAD};
-use pe_parser::PeParser;
+use boot_args::{KASLR_ALIGN, KERNEL_VMEM_BASE, KERNEL_VMEM_RANDOM_SIZE};
+use boot_args::{KERNEL_IMAGE_RANDOM_BASE, KERNEL_IMAGE_RANDOM_SIZE};
+use boot_args::{KERNEL_STACKS_RANDOM_BASE, KERNEL_STACKS_RANDOM_SIZE};
+use boot_args::{Role, ROLE_DHCP_OPTION};
+use pe_parser::{PeParser, Relocation};
 use lockcell::LockCell;
 use page_table::{VirtAddr, PageType, PageTable, PAGE_PRESENT, PAGE_WRITE};
 use page_table::PAGE_SIZE;
@@ -40,6 +46,7 @@ static CORE_ID: AtomicU32 = AtomicU32::new(0);
 impl lockcell::InterruptState for LockInterrupts {
     fn in_interrupt() -> bool { false }
     fn in_exception() -> bool { false }
+    fn interrupt_depth() -> u32 { 0 }
     fn core_id() -> u32 { CORE_ID.load(Ordering::SeqCst) }
     fn enter_lock() {}
     fn exit_lock() {}
@@ -57,8 +64,44 @@ pub static BOOT_ARGS: BootArgs<LockInterrupts> = BootArgs {
     stack_vaddr:           AtomicU64::new(KERNEL_STACKS_BASE),
     print_lock:            LockCell::new_no_preempt(()),
     soft_reboot_addr:      AtomicU64::new(0),
+    microcode_addr:        AtomicU64::new(0),
+    microcode_size:        AtomicU64::new(0),
+    kernel_slide:          AtomicU64::new(0),
+    vmem_base:             AtomicU64::new(KERNEL_VMEM_BASE),
+    kernel_image_base:     AtomicU64::new(0),
+    kernel_image_size:     AtomicU64::new(0),
+    role:                  AtomicU64::new(Role::FuzzWorker as u64),
+    tunables_addr:         AtomicU64::new(0),
+    tunables_size:         AtomicU64::new(0),
+    command_key_addr:      AtomicU64::new(0),
+    command_key_size:      AtomicU64::new(0),
+    memory_map:            LockCell::new(None),
+    crash_ring_addr:       AtomicU64::new(0),
 };
 
+/// Get a random 64-bit number. This uses `rdrand` if it is available,
+/// otherwise it falls back to the TSC, which is better than nothing.
+fn random(features: &cpu::CPUFeatures) -> u64 {
+    if features.rdrand {
+        // Get a random 32-bit number, retrying a few times if the hardware
+        // RNG is not ready
+        let rand32 = || {
+            (0..16).find_map(|_| unsafe { cpu::rdrand32() })
+                .expect("Failed to get random number from rdrand")
+        };
+
+        ((rand32() as u64) << 32) | rand32() as u64
+    } else {
+        cpu::rdtsc()
+    }
+}
+
+/// Get a random `KASLR_ALIGN`ed address in the region starting at `base` for
+/// `size` bytes
+fn random_base(features: &cpu::CPUFeatures, base: u64, size: u64) -> u64 {
+    base + ((random(features) % size) & !(KASLR_ALIGN - 1))
+}
+
 /// Rust entry point for the bootloader
 ///
 /// * `bootloader_end`    - One byte past the end of the bootloader
@@ -117,7 +160,7 @@ extern fn entry(bootloader_end: usize, soft_reboot_entry: usize,
             BOOT_ARGS.serial.lock().as_mut().unwrap()
                 .write(b"Downloading kernel...\n");
 
-            let kernel = loop {
+            let mut kernel = loop {
                 // Download the kernel
                 if let Some(kern) = pxe::download("chocolate_milk.kern") {
                     // Downloaded the kernel, return it out of the loop
@@ -132,7 +175,143 @@ extern fn entry(bootloader_end: usize, soft_reboot_entry: usize,
             BOOT_ARGS.serial.lock().as_mut().unwrap()
                 .write(b"Kernel download complete!\n");
 
-            // Parse the PE from the kernel
+            // Attempt to download the SHA-256 of the kernel. This is optional,
+            // but if it is present the kernel must match it.
+            if let Some(digest) = pxe::download("chocolate_milk.kern.sha256") {
+                assert!(digest.len() == 32 &&
+                    digest[..] == hashes::sha256(&kernel)[..],
+                    "Kernel failed SHA-256 verification");
+
+                BOOT_ARGS.serial.lock().as_mut().unwrap()
+                    .write(b"Kernel SHA-256 verified!\n");
+            }
+
+            // Attempt to download a microcode update for the kernel to apply.
+            // This is optional, thus we only try once.
+            if let Some(microcode) = pxe::download("chocolate_milk.ucode") {
+                BOOT_ARGS.serial.lock().as_mut().unwrap()
+                    .write(b"Microcode download complete!\n");
+
+                // Save the location of the microcode for the kernel
+                BOOT_ARGS.microcode_addr.store(
+                    microcode.as_ptr() as u64, Ordering::SeqCst);
+                BOOT_ARGS.microcode_size.store(
+                    microcode.len() as u64, Ordering::SeqCst);
+
+                // The microcode must live forever as the kernel will use it
+                core::mem::forget(microcode);
+            }
+
+            // Get the role of this node from the boot arguments the DHCP
+            // server gave us, falling back to the optional role file. Without
+            // either the node is a fuzz worker.
+            let role = pxe::dhcp_option(ROLE_DHCP_OPTION)
+                .or_else(|| pxe::download("chocolate_milk.role"))
+                .and_then(|name| {
+                    let role = Role::from_name(&name);
+                    if role.is_none() {
+                        BOOT_ARGS.serial.lock().as_mut().unwrap()
+                            .write(b"Invalid node role, ignoring\n");
+                    }
+                    role
+                })
+                .unwrap_or(Role::FuzzWorker);
+            BOOT_ARGS.role.store(role as u64, Ordering::SeqCst);
+
+            // Attempt to download overrides for kernel tunables. This is
+            // optional, thus we only try once.
+            if let Some(tunables) = pxe::download("chocolate_milk.tunables") {
+                // Save the location of the tunables for the kernel
+                BOOT_ARGS.tunables_addr.store(
+                    tunables.as_ptr() as u64, Ordering::SeqCst);
+                BOOT_ARGS.tunables_size.store(
+                    tunables.len() as u64, Ordering::SeqCst);
+
+                // The tunables must live forever as the kernel will use them
+                core::mem::forget(tunables);
+            }
+
+            // Attempt to download the key for the remote command channel.
+            // This is optional, without it the channel stays disabled.
+            if let Some(key) = pxe::download("chocolate_milk.cmdkey") {
+                // Save the location of the key for the kernel
+                BOOT_ARGS.command_key_addr.store(
+                    key.as_ptr() as u64, Ordering::SeqCst);
+                BOOT_ARGS.command_key_size.store(
+                    key.len() as u64, Ordering::SeqCst);
+
+                // The key must live forever as the kernel will use it
+                core::mem::forget(key);
+            }
+
+            // Get the support CPU features
+            let features = cpu::get_cpu_features();
+
+            // Pick a random base to load the kernel at, and apply
+            // relocations to the raw kernel image such that it can execute at
+            // this base
+            let slide = {
+                // Parse the PE from the kernel
+                let pe = PeParser::parse(&kernel)
+                    .expect("Failed to parse PE");
+
+                if pe.relocatable() {
+                    // Compute the slide from the linked base to the random
+                    // base
+                    let slide = random_base(&features,
+                        KERNEL_IMAGE_RANDOM_BASE, KERNEL_IMAGE_RANDOM_SIZE)
+                        .wrapping_sub(pe.image_base);
+
+                    // Get all relocations for the kernel
+                    let mut relocs = Vec::new();
+                    pe.relocations(|offset, typ| {
+                        relocs.push((offset, typ));
+                        Some(())
+                    }).expect("Failed to parse kernel relocations");
+
+                    // Apply all of the relocations to the raw image
+                    for (offset, typ) in relocs {
+                        match typ {
+                            Relocation::Dir64 => {
+                                let field = &mut kernel[offset..offset + 8];
+                                let val = u64::from_le_bytes(
+                                    (&*field).try_into().unwrap());
+                                field.copy_from_slice(
+                                    &val.wrapping_add(slide).to_le_bytes());
+                            }
+                            Relocation::HighLow => {
+                                let field = &mut kernel[offset..offset + 4];
+                                let val = u32::from_le_bytes(
+                                    (&*field).try_into().unwrap());
+                                field.copy_from_slice(
+                                    &val.wrapping_add(slide as u32)
+                                    .to_le_bytes());
+                            }
+                        }
+                    }
+
+                    slide
+                } else {
+                    // The kernel was linked with relocations stripped, thus
+                    // we have to load it at its linked base
+                    BOOT_ARGS.serial.lock().as_mut().unwrap()
+                        .write(b"Kernel is not relocatable, KASLR disabled\n");
+                    0
+                }
+            };
+
+            // Save the slide such that the kernel can symbolize addresses
+            BOOT_ARGS.kernel_slide.store(slide, Ordering::SeqCst);
+
+            // Randomize the kernel stacks and dynamic virtual memory bases
+            BOOT_ARGS.stack_vaddr.store(random_base(&features,
+                KERNEL_STACKS_RANDOM_BASE, KERNEL_STACKS_RANDOM_SIZE),
+                Ordering::SeqCst);
+            BOOT_ARGS.vmem_base.store(random_base(&features,
+                KERNEL_VMEM_BASE, KERNEL_VMEM_RANDOM_SIZE),
+                Ordering::SeqCst);
+
+            // Parse the PE from the relocated kernel
             let pe = PeParser::parse(&kernel).expect("Failed to parse PE");
 
             // Get exclusive access to physical memory
@@ -165,9 +344,6 @@ extern fn entry(bootloader_end: usize, soft_reboot_entry: usize,
             // Create a new page table
             let mut table = PageTable::new(&mut pmem);
 
-            // Get the support CPU features
-            let features = cpu::get_cpu_features();
-
             // Create the linear map of physical memory, using the largest page
             // size available on this processor
             if features.gbyte_pages {
@@ -226,23 +402,44 @@ extern fn entry(bootloader_end: usize, soft_reboot_entry: usize,
                 }
             }
 
+            // Track the bounds of the loaded kernel image
+            let mut image_start = !0u64;
+            let mut image_end   = 0u64;
+
             // Load all the sections from the PE into the new page table
             pe.sections(|vaddr, vsize, raw, read, write, execute| {
+                // Get the address this section is loaded at
+                let vaddr = vaddr.wrapping_add(slide);
+
+                // Never allow a section to be both writable and executable
+                assert!(!(write && execute), "Kernel section is RWX");
+
                 // Create a new virtual mapping for the PE range and initialize
                 // it to the raw bytes from the PE file, otherwise to zero for
                 // all bytes that were not initialized in the file.
-                table.map_init(&mut pmem, VirtAddr(vaddr),
-                    PageType::Page4K,
+                table.map_init(&mut pmem,
+                    VirtAddr(vaddr), PageType::Page4K,
                     vsize as u64, read, write, execute,
                     Some(|off| {
                         raw.get(off as usize).copied().unwrap_or(0)
                     }));
 
+                // Update the image bounds, rounding up to the page size which
+                // the section was mapped with
+                image_start = core::cmp::min(image_start, vaddr);
+                image_end   = core::cmp::max(image_end,
+                    vaddr + ((vsize as u64 + 0xfff) & !0xfff));
+
                 Some(())
             }).unwrap();
 
+            // Save the bounds of the kernel image
+            BOOT_ARGS.kernel_image_base.store(image_start, Ordering::SeqCst);
+            BOOT_ARGS.kernel_image_size.store(image_end - image_start,
+                                              Ordering::SeqCst);
+
             // Set up the entry point and page table
-            *kernel_entry = Some(pe.entry_point);
+            *kernel_entry = Some(pe.entry_point.wrapping_add(slide));
             *page_table   = Some(table);
            
             // Save the trampoline table address
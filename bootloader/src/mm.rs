@@ -2,10 +2,12 @@
 
 use core::convert::TryInto;
 use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::Ordering;
 
 use crate::realmode::{RegisterState, invoke_realmode};
 
 use crate::BOOT_ARGS;
+use boot_args::CRASH_RING_SIZE;
 use page_table::{PhysAddr, PhysMem};
 use rangeset::{Range, RangeSet};
 
@@ -159,6 +161,9 @@ pub fn init() {
         }
     }
 
+    // Save the sanitized memory map such that the kernel knows where RAM is
+    *BOOT_ARGS.memory_map.lock() = Some(free_memory);
+
     // Remove the first 1 MiB of memory for use. The BIOS does some weird stuff
     // we can't really trust the memory map in this area. Especially with
     // option ROMs potentially using some of this RAM.
@@ -167,6 +172,13 @@ pub fn init() {
         end:   1024 * 1024 - 1,
     });
 
+    // Reserve the crash ring. This happens before anything else is allocated
+    // and the memory map is the same every boot, thus the crash ring ends up
+    // at the same address every boot, and its contents survive soft reboots.
+    let crash_ring = free_memory.allocate(CRASH_RING_SIZE, 4096)
+        .expect("Failed to allocate crash ring");
+    BOOT_ARGS.crash_ring_addr.store(crash_ring as u64, Ordering::SeqCst);
+
     // Set up the global physical memory state with the free memory we have
     // tracked.
     *pmem = Some(free_memory);
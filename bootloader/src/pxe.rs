@@ -16,14 +16,9 @@ fn segoff_to_linear(seg: u16, off: u16) -> usize {
     ((seg as usize) << 4) + off as usize
 }
 
-/// Download a file with the `filename` over TFTP with the PXE 16-bit API
-pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
-    // Lock access to PXE
-    let _guard = PXE_GUARD.lock();
-
-    // Convert the filename to a slice of bytes
-    let filename: &[u8] = filename.as_ref();
-
+/// Find the 16-bit PXE API entry point, returning its segment and offset.
+/// Returns `None` if there is no usable PXE API.
+fn entry_point() -> Option<(u16, u16)> {
     // Invoke the PXE installation check with int 0x1a
     let mut regs = RegisterState::default();
     regs.eax = 0x5650;
@@ -77,46 +72,102 @@ pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
         return None;
     }
 
-    // Determine the server IP from the cached information used during the PXE
-    // boot process. We grab the DHCP ACK packet and extract the server IP
-    // field from it.
-    let server_ip: [u8; 4] = {
-        const PXE_OPCODE_GET_CACHED_INFO: u16 = 0x71;
-        const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
+    Some((ep_seg, ep_off))
+}
 
-        #[derive(Default)]
-        #[repr(C)]
-        struct GetCachedInfo {
-            status:       u16,
-            packet_type:  u16,
-            buffer_size:  u16,
-            buffer_off:   u16,
-            buffer_seg:   u16,
-            buffer_limit: u16,
-        }
+/// Get the DHCP ACK packet which was cached during the PXE boot process,
+/// using the PXE API at `ep_seg:ep_off`
+fn dhcp_ack(ep_seg: u16, ep_off: u16) -> Option<&'static [u8]> {
+    const PXE_OPCODE_GET_CACHED_INFO: u16 = 0x71;
+    const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
+
+    #[derive(Default)]
+    #[repr(C)]
+    struct GetCachedInfo {
+        status:       u16,
+        packet_type:  u16,
+        buffer_size:  u16,
+        buffer_off:   u16,
+        buffer_seg:   u16,
+        buffer_limit: u16,
+    }
 
-        // Request the DHCP ACK packet
-        let mut st = GetCachedInfo::default();
-        st.packet_type = PXENV_PACKET_TYPE_DHCP_ACK;
-        unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_GET_CACHED_INFO,
-                0, &mut st as *mut _ as u16);
-        }
+    // Request the DHCP ACK packet
+    let mut st = GetCachedInfo::default();
+    st.packet_type = PXENV_PACKET_TYPE_DHCP_ACK;
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_GET_CACHED_INFO,
+            0, &mut st as *mut _ as u16);
+    }
 
-        // Make sure this call was successful
-        if st.status != 0 {
-            return None;
+    // Make sure this call was successful
+    if st.status != 0 {
+        return None;
+    }
+
+    Some(unsafe {
+        core::slice::from_raw_parts(
+            segoff_to_linear(st.buffer_seg, st.buffer_off) as *const u8,
+            st.buffer_size as usize)
+    })
+}
+
+/// Get the contents of the DHCP option `code` which the DHCP server gave us
+/// in the DHCP ACK during the PXE boot process. Returns `None` if the option
+/// is not present.
+pub fn dhcp_option(code: u8) -> Option<Vec<u8>> {
+    /// Offset of the magic cookie in a DHCP packet, options follow it
+    const COOKIE_OFFSET: usize = 236;
+
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    let (ep_seg, ep_off) = entry_point()?;
+    let ack = dhcp_ack(ep_seg, ep_off)?;
+
+    // Make sure this is a DHCP packet rather than plain BOOTP
+    if ack.get(COOKIE_OFFSET..COOKIE_OFFSET + 4)? != [99, 130, 83, 99] {
+        return None;
+    }
+
+    // Walk the options until we find the one we're looking for
+    let mut options = ack.get(COOKIE_OFFSET + 4..)?;
+    loop {
+        match *options.first()? {
+            // Padding
+            0 => options = &options[1..],
+
+            // End of the options
+            255 => return None,
+
+            opt => {
+                let len  = *options.get(1)? as usize;
+                let data = options.get(2..2 + len)?;
+                if opt == code {
+                    return Some(data.to_vec());
+                }
+                options = &options[2 + len..];
+            }
         }
+    }
+}
 
-        let pkt_buf = unsafe {
-            core::slice::from_raw_parts(
-                segoff_to_linear(st.buffer_seg, st.buffer_off) as *const u8,
-                st.buffer_size as usize)
-        };
+/// Download a file with the `filename` over TFTP with the PXE 16-bit API
+pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
 
-        // Extract the server IP
-        pkt_buf[0x14..0x18].try_into().ok()?
-    };
+    // Convert the filename to a slice of bytes
+    let filename: &[u8] = filename.as_ref();
+
+    // Get the PXE API entry point
+    let (ep_seg, ep_off) = entry_point()?;
+
+    // Determine the server IP from the cached information used during the PXE
+    // boot process. We grab the DHCP ACK packet and extract the server IP
+    // field from it.
+    let server_ip: [u8; 4] =
+        dhcp_ack(ep_seg, ep_off)?.get(0x14..0x18)?.try_into().ok()?;
 
     // Get the file size for the next stage
     let file_size = {
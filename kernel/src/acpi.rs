@@ -7,11 +7,60 @@ use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
 
 use crate::mm;
+use crate::physmap::{self, RegionType};
+use crate::tunables::Tunable;
 use page_table::PhysAddr;
 
 /// Maximum number of cores allowed on the system
 pub const MAX_CORES: usize = 1024;
 
+/// Maximum number of cores to bring up, including the BSP. Zero means all
+/// cores are used.
+pub static CORE_LIMIT: Tunable = Tunable::new("core_limit", 0);
+
+/// How hardware threads which share a physical core are used, the raw value
+/// of a `SmtPolicy`
+pub static SMT_POLICY: Tunable = Tunable::new("smt_policy", 0);
+
+/// Policies for using the hardware threads of a physical core
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SmtPolicy {
+    /// Every hardware thread is brought up and used the same way
+    All = 0,
+
+    /// Only the first hardware thread of every physical core is brought up
+    PhysicalCores = 1,
+
+    /// Every hardware thread is brought up. The first thread of every
+    /// physical core runs workloads, and its siblings are service cores
+    /// which run background work for it.
+    Paired = 2,
+}
+
+/// Get the SMT policy selected by the `smt_policy` tunable. An invalid value
+/// falls back to using every hardware thread, as a typo in a tunables file
+/// shouldn't keep a node from booting.
+pub fn smt_policy() -> SmtPolicy {
+    match SMT_POLICY.get() {
+        0 => SmtPolicy::All,
+        1 => SmtPolicy::PhysicalCores,
+        2 => SmtPolicy::Paired,
+        x => {
+            print!("Invalid smt_policy tunable {}, using all hardware \
+                    threads\n", x);
+            SmtPolicy::All
+        }
+    }
+}
+
+/// Returns `true` if the current core is a service core under the SMT
+/// policy, rather than a core which runs workloads
+#[allow(dead_code)]
+pub fn is_service_core() -> bool {
+    smt_policy() == SmtPolicy::Paired &&
+        crate::cpu_features::get().smt_thread(core!().apic_id().unwrap()) != 0
+}
+
 /// Different states for APICs to be in
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
@@ -31,6 +80,10 @@ pub enum ApicState {
 
     /// This APIC ID has disabled interrupts and halted forever
     Halted = 5,
+
+    /// The core has been parked on request. It is halted and not running any
+    /// tasks, but it can be unparked to bring it back online.
+    Parked = 6,
 }
 
 impl From<u8> for ApicState {
@@ -42,6 +95,7 @@ impl From<u8> for ApicState {
             3 => ApicState::Offline,
             4 => ApicState::None,
             5 => ApicState::Halted,
+            6 => ApicState::Parked,
             _ => panic!("Invalid ApicState from `u8`"),
         }
     }
@@ -160,9 +214,9 @@ unsafe fn parse_header(addr: PhysAddr) -> (Header, PhysAddr, usize) {
     (head, PhysAddr(addr.0 + size_of::<Header>() as u64), payload_len as usize)
 }
 
-/// Initialize the ACPI subsystem. Mainly looking for APICs and memory maps.
-/// Brings up all cores on the system
-pub unsafe fn init() {
+/// Locate the RSDP and return the physical addresses of all tables described
+/// by the RSDT
+unsafe fn tables() -> Vec<PhysAddr> {
     // Specification says we have to scan the first 1 KiB of the EBDA and the
     // range from 0xe0000 to 0xfffff
 
@@ -230,6 +284,38 @@ pub unsafe fn init() {
         "Invalid table size for RSDT");
     let rsdt_entries = rsdt_size / size_of::<u32>();
 
+    // Get the pointers to each table described by the RSDT
+    (0..rsdt_entries).map(|entry| {
+        // Get the physical address of the RSDP table entry
+        let entry_paddr = rsdt_payload.0 as usize + entry * size_of::<u32>();
+
+        // Get the pointer to the table
+        let table_ptr: u32 = mm::read_phys(PhysAddr(entry_paddr as u64));
+        PhysAddr(table_ptr as u64)
+    }).collect()
+}
+
+/// Find the ACPI table with `signature`, returning the physical address and
+/// size of the payload following the table header. Returns `None` if no such
+/// table exists.
+pub unsafe fn find_table(signature: &[u8; 4])
+        -> Option<(PhysAddr, usize)> {
+    // Go through each table described by the RSDT
+    for table_ptr in tables() {
+        // Check the signature for the table
+        let table_sig: [u8; 4] = mm::read_phys(table_ptr);
+        if &table_sig == signature {
+            let (_, payload, payload_len) = parse_header(table_ptr);
+            return Some((payload, payload_len));
+        }
+    }
+
+    None
+}
+
+/// Initialize the ACPI subsystem. Mainly looking for APICs and memory maps.
+/// Brings up all cores on the system
+pub unsafe fn init() {
     // Set up the structures we're interested as parsing out as `None` as some
     // of them may or may not be present.
     let mut apics          = None;
@@ -237,25 +323,27 @@ pub unsafe fn init() {
     let mut memory_domains = None;
 
     // Go through each table described by the RSDT
-    for entry in 0..rsdt_entries {
-        // Get the physical address of the RSDP table entry
-        let entry_paddr = rsdt_payload.0 as usize + entry * size_of::<u32>();
-
-        // Get the pointer to the table
-        let table_ptr: u32 = mm::read_phys(PhysAddr(entry_paddr as u64));
-
+    for table_ptr in tables() {
         // Get the signature for the table
-        let signature: [u8; 4] = mm::read_phys(PhysAddr(table_ptr as u64));
+        let signature: [u8; 4] = mm::read_phys(table_ptr);
+
+        // Reserve the memory holding the table. Tables in the first 1 MiB
+        // are part of the BIOS area, which is already reserved.
+        if table_ptr.0 >= 1024 * 1024 {
+            let header = mm::read_phys::<Header>(table_ptr);
+            physmap::reserve("ACPI table", RegionType::Memory, table_ptr,
+                             header.length as u64);
+        }
 
         if &signature == b"APIC" {
             // Parse the MADT
             assert!(apics.is_none(), "Multiple MADT ACPI table entries");
-            apics = Some(parse_madt(PhysAddr(table_ptr as u64)));
+            apics = Some(parse_madt(table_ptr));
         } else if &signature == b"SRAT" {
             // Parse the SRAT
             assert!(apic_domains.is_none() && memory_domains.is_none(),
                 "Multiple SRAT ACPI table entries");
-            let (ad, md) = parse_srat(PhysAddr(table_ptr as u64));
+            let (ad, md) = parse_srat(table_ptr);
             apic_domains   = Some(ad);
             memory_domains = Some(md);
         }
@@ -266,6 +354,36 @@ pub unsafe fn init() {
         crate::mm::register_numa_nodes(ad, md);
     }
 
+    if let Some(apics) = &mut apics {
+        let our_apic_id = core!().apic_id().unwrap();
+        let detected    = apics.len();
+
+        // Drop the sibling hardware threads if we only use physical cores
+        if smt_policy() == SmtPolicy::PhysicalCores {
+            let features = crate::cpu_features::get();
+            apics.retain(|&apic_id| {
+                apic_id == our_apic_id || features.smt_thread(apic_id) == 0
+            });
+        }
+
+        // Only bring up as many cores as we're allowed to use, always keeping
+        // ourselves
+        let limit = CORE_LIMIT.get() as usize;
+        if limit != 0 && apics.len() > limit {
+            let mut kept = 1;
+            apics.retain(|&apic_id| {
+                if apic_id == our_apic_id { return true; }
+                kept += 1;
+                kept <= limit
+            });
+        }
+
+        if apics.len() != detected {
+            print!("Using {} of {} cores ({:?} SMT policy)\n",
+                   apics.len(), detected, smt_policy());
+        }
+    }
+
     // Set the total core count based on the number of detected APICs on the
     // system. If no APICs were mentioned by ACPI, then we can simply say there
     // is only one core.
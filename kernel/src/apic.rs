@@ -4,11 +4,10 @@
 use core::mem::size_of;
 use core::convert::TryInto;
 
-use crate::mm::alloc_virt_addr_4k;
+use crate::mmio::{self, Mmio};
 use crate::interrupts::{InterruptFrame, AllRegs};
 
-use page_table::{PageType, PAGE_NX, PAGE_WRITE, PAGE_PRESENT};
-use page_table::PAGE_CACHE_DISABLE;
+use page_table::PhysAddr;
 
 /// The x2apic enable bit in the `IA32_APIC_BASE` MSR
 const IA32_APIC_BASE_EXTD: u64 = 1 << 10;
@@ -28,6 +27,17 @@ const APIC_TIMER_VECTOR: u8 = 0xe0;
 /// The mask bit for LVT entries
 const LVT_MASK: u32 = 1 << 16;
 
+/// Low 32-bits of the interrupt command register in xAPIC mode. Writing this
+/// sends the IPI.
+const ICR_LOW: mmio::Register<u32> = mmio::Register::new(0x300);
+
+/// High 32-bits of the interrupt command register in xAPIC mode
+const ICR_HIGH: mmio::Register<u32> = mmio::Register::new(0x310);
+
+/// MSR which holds the TSC value at which the APIC timer fires in
+/// TSC-deadline mode
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
 /// APIC registers (offsets into MMIO space)
 #[derive(Clone, Copy)]
 #[repr(usize)]
@@ -131,12 +141,15 @@ pub struct Apic {
 
     /// Original APIC timer state
     orig_timer_state: TimerState,
+
+    /// Set if the APIC timer has been programmed in TSC-deadline mode
+    tsc_deadline: bool,
 }
 
 /// The different modes of the APIC
 enum ApicMode {
     /// APIC has been set to normal APIC mode
-    Apic(&'static mut [u32]),
+    Apic(Mmio),
 
     /// APIC supports and has been programmed to use x2apic mode
     X2Apic,
@@ -177,12 +190,10 @@ impl Apic {
         match &mut self.mode {
             ApicMode::Apic(mapping) => {
                 // Write the high part
-                core::ptr::write_volatile(&mut mapping[0x310 / 4],
-                                          (val >> 32) as u32);
+                mapping.write(ICR_HIGH, (val >> 32) as u32);
 
                 // Write the low part, causing the interrupt to be sent
-                core::ptr::write_volatile(&mut mapping[0x300 / 4],
-                                          (val >>  0) as u32);
+                mapping.write(ICR_LOW, (val >>  0) as u32);
             }
             ApicMode::X2Apic => {
                 // Write the entire 64-bit value in one shot to MSR 0x830
@@ -199,7 +210,7 @@ impl Apic {
         match &self.mode {
             ApicMode::Apic(mapping) => {
                 // Read the value using the APIC memory map
-                core::ptr::read_volatile(&mapping[offset / 4])
+                mapping.read(mmio::Register::<u32>::new(offset))
             }
             ApicMode::X2Apic => {
                 // Read the value using the x2apic MSRs
@@ -217,7 +228,7 @@ impl Apic {
         match &mut self.mode {
             ApicMode::Apic(mapping) => {
                 // Write the value using the APIC memory map
-                core::ptr::write_volatile(&mut mapping[offset / 4], val);
+                mapping.write(mmio::Register::<u32>::new(offset), val);
             }
             ApicMode::X2Apic => {
                 // Write the value using the x2apic MSRs
@@ -281,6 +292,13 @@ impl Apic {
                               _error: usize, _regs: &mut AllRegs) -> bool {
         crate::panic::attempt_soft_reboot();
 
+        // Sample where this core was executing
+        #[cfg(feature = "profile")]
+        crate::profile::sample(_frame.rip as u64);
+
+        // Dispatch deadlines and program the next tick
+        crate::timer::tick();
+
         true
     }
 
@@ -307,9 +325,12 @@ impl Apic {
         }
     }
 
-    /// Enable the APIC timer
+    /// Enable the APIC timer. If the TSC-deadline mode is supported the timer
+    /// is used in that mode such that it can be programmed to fire at
+    /// arbitrary deadlines, otherwise it is set up as a periodic tick.
     pub unsafe fn enable_timer(&mut self) {
-        const PERIODIC_MODE: u32 = 1 << 17;
+        const PERIODIC_MODE:     u32 = 1 << 17;
+        const TSC_DEADLINE_MODE: u32 = 2 << 17;
 
         // Disable the timer by setting the initial count to zero
         self.write_apic(Register::InitialCount, 0);
@@ -320,6 +341,19 @@ impl Apic {
                 APIC_TIMER_VECTOR, Self::timer_interrupt, true);
         }
 
+        if crate::cpu_features::get().tsc_deadline {
+            // Program the APIC timer to TSC-deadline mode and use interrupt
+            // vector `APIC_TIMER_VECTOR`
+            self.write_apic(Register::LvtTimer,
+                            TSC_DEADLINE_MODE | APIC_TIMER_VECTOR as u32);
+            self.tsc_deadline = true;
+
+            // Schedule the first tick
+            self.set_timer_deadline(
+                crate::time::future(crate::timer::TICK_MICROSECONDS.get()));
+            return;
+        }
+
         // Set the timer divide register to divide by 2 (0 means 2)
         self.write_apic(Register::DivideConfiguration, 0);
 
@@ -333,6 +367,18 @@ impl Apic {
         // will be fired
         self.write_apic(Register::InitialCount, 10_000_000);
     }
+
+    /// Program the APIC timer to fire when the TSC reaches `tsc`. This
+    /// replaces any previously programmed deadline.
+    ///
+    /// If the APIC timer is not in TSC-deadline mode, this does nothing and
+    /// the timer keeps firing periodically.
+    pub unsafe fn set_timer_deadline(&mut self, tsc: u64) {
+        if self.tsc_deadline {
+            // Writing zero disarms the timer, make sure we never do that
+            cpu::wrmsr(IA32_TSC_DEADLINE, core::cmp::max(tsc, 1));
+        }
+    }
     
     /// Disable the APIC timer
     #[allow(unused)]
@@ -343,6 +389,12 @@ impl Apic {
 
         // Disable the timer by setting the initial count to zero
         self.write_apic(Register::InitialCount, 0);
+
+        // Disarm the TSC deadline
+        if self.tsc_deadline {
+            cpu::wrmsr(IA32_TSC_DEADLINE, 0);
+            self.tsc_deadline = false;
+        }
         
         // Deregister an interrupt handler for `APIC_TIMER_VECTOR`
         core!().interrupts.try_lock()
@@ -359,6 +411,11 @@ impl Drop for Apic {
             self.write_apic(Register::LvtTimer,
                 LVT_MASK | self.read_apic(Register::LvtTimer));
 
+            // Disarm the TSC deadline
+            if self.tsc_deadline {
+                cpu::wrmsr(IA32_TSC_DEADLINE, 0);
+            }
+
             // It is possible that we're dropping the `Apic` from a timer
             // interrupt handler. In this case, there may be an interrupt which
             // is currently in the servicing state. We will EOI on behalf of
@@ -461,7 +518,7 @@ pub unsafe fn init() {
     assert!(apic.is_none(), "APIC was already initialized");
 
     // Get the CPU features for this system
-    let cpu_features = cpu::get_cpu_features();
+    let cpu_features = crate::cpu_features::get();
 
     // We require that the APIC is supported on this system
     assert!(cpu_features.apic, "APIC is not available on this system");
@@ -510,27 +567,9 @@ pub unsafe fn init() {
         // If we're in normal xAPIC mode, we want to virtually map in the
         // APIC physical memory as uncacheable and update the APIC enum state
       
-        // Get a virtual address capable of holding a 4 KiB mapping
-        let vaddr = alloc_virt_addr_4k(4096);
-        
-        // Get access to physical memory allocations
-        let mut pmem = crate::mm::PhysicalMemory;
-
-        // Get access to the current page table
-        let mut page_table = core!().boot_args.page_table.lock();
-        let page_table = page_table.as_mut().unwrap();
-
-        let mapping = {
-            // Map `vaddr` to the APIC base, as non-executable, writable,
-            // readable, and cache disabled
-            page_table.map_raw(&mut pmem, vaddr, PageType::Page4K,
-                               APIC_BASE | PAGE_NX | PAGE_WRITE | 
-                               PAGE_CACHE_DISABLE | PAGE_PRESENT)
-                .expect("Failed to map in APIC to virtual memory");
-
-            // Convert the APIC virtual memory into a Rust slice
-            core::slice::from_raw_parts_mut(vaddr.0 as *mut u32, 1024)
-        };
+        // Map the APIC as non-executable, writable, readable, and cache
+        // disabled
+        let mapping = Mmio::map("local APIC", PhysAddr(APIC_BASE), 4096);
 
         ApicMode::Apic(mapping)
     } else {
@@ -546,6 +585,7 @@ pub unsafe fn init() {
         orig_pic_a1,
         orig_svr: 0,
         orig_timer_state: Default::default(),
+        tsc_deadline: false,
     };
 
     // Save off the original SVR
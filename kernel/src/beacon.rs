@@ -0,0 +1,82 @@
+//! Boot-time configuration beacon
+//!
+//! Once a network device has a lease, we announce our node ID to the DHCP
+//! server and give it a chance to push configuration for this node. The
+//! configuration is a set of tunable overrides in the same `name = value`
+//! format as the `chocolate_milk.tunables` file, applied on top of it. As
+//! this happens during device probing, before the other cores are launched,
+//! tunables such as `core_limit` still take effect.
+//!
+//! Beacon: `CMBEACON` | node ID (u64 LE) | MAC address
+//! Reply:  `CMCONFIG` | node ID (u64 LE) | configuration text
+
+use core::convert::TryInto;
+use alloc::vec::Vec;
+
+use crate::net::{NetDevice, UdpSocket, Ipv4Addr};
+use crate::tunables::{self, Tunable};
+
+/// UDP port the server listens for beacons on, and replies from
+const BEACON_PORT: u16 = 1338;
+
+/// Magic at the start of a beacon
+const BEACON_MAGIC: &[u8; 8] = b"CMBEACON";
+
+/// Magic at the start of a configuration reply
+const CONFIG_MAGIC: &[u8; 8] = b"CMCONFIG";
+
+/// Time to wait for the server to reply with our configuration, in
+/// microseconds
+pub static BEACON_TIMEOUT: Tunable = Tunable::new("beacon_timeout", 500_000);
+
+/// Announce this node with ID `node_id` to `server_ip` over `device` and
+/// apply the configuration it replies with, if any
+pub fn announce(device: &NetDevice, server_ip: Ipv4Addr, node_id: u64) {
+    let timeout  = BEACON_TIMEOUT.get();
+    let deadline = crate::time::future(timeout);
+
+    let server_mac = match device.resolve(server_ip, timeout) {
+        Some(mac) => mac,
+        None      => return,
+    };
+
+    let bind = UdpSocket::bind(device, 0)
+        .expect("Could not bind to a port for the beacon");
+
+    // Send the beacon
+    bind.send_to(server_mac, server_ip, BEACON_PORT, |packet| {
+        let payload = packet.put(8 + 8 + 6);
+        payload[..8].copy_from_slice(BEACON_MAGIC);
+        payload[8..16].copy_from_slice(&node_id.to_le_bytes());
+        payload[16..].copy_from_slice(&device.mac());
+    });
+
+    // Wait for our configuration
+    while cpu::rdtsc() < deadline {
+        let config = bind.recv(|_, udp| {
+            let payload = udp.payload;
+            if udp.src_port != BEACON_PORT || payload.len() < 16 ||
+                    &payload[..8] != CONFIG_MAGIC ||
+                    u64::from_le_bytes(payload[8..16].try_into().unwrap()) !=
+                        node_id {
+                return None;
+            }
+
+            Some(payload[16..].iter().copied().collect::<Vec<u8>>())
+        });
+
+        if let Some(config) = config {
+            match core::str::from_utf8(&config) {
+                Ok(config) => {
+                    print!("Applying configuration from {:?}\n", server_ip);
+                    tunables::apply(config);
+                }
+                Err(_) => print!("Beacon configuration is not valid UTF-8, \
+                                  ignoring\n"),
+            }
+            return;
+        }
+    }
+
+    print!("No configuration from {:?}, using defaults\n", server_ip);
+}
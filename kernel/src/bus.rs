@@ -0,0 +1,198 @@
+//! Cross-core message bus with priority bands
+//!
+//! Every core has a mailbox with one queue per priority band. Messages are
+//! closures which are run on the destination core by its executor, urgent
+//! messages first, such that control messages never wait behind bulk
+//! traffic. The bulk and normal bands are only drained a batch at a time, and
+//! the urgent band is checked again between every message.
+//!
+//! Messages are picked up the next time the destination core runs its
+//! executor loop, which at the latest is on its next APIC timer tick. Parked
+//! cores still handle urgent messages.
+//!
+//! The stats report gathers per-core stats from every core over the bulk
+//! band.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use mpmc::Queue;
+use lockcell::LockCell;
+
+use crate::{acpi, time};
+use crate::acpi::MAX_CORES;
+use crate::core_locals::LockInterrupts;
+
+/// Number of messages which can be queued in each band of a mailbox
+const BAND_CAPACITY: usize = 256;
+
+/// Maximum number of normal messages handled per executor iteration
+const NORMAL_BATCH: usize = 32;
+
+/// Maximum number of bulk messages handled per executor iteration
+const BULK_BATCH: usize = 8;
+
+/// Mailboxes of all cores, indexed by core ID. Null until the core has
+/// called `init()`.
+static MAILBOXES: [AtomicPtr<Mailbox>; MAX_CORES] =
+    [AtomicPtr::new(core::ptr::null_mut()); MAX_CORES];
+
+/// A message, which is run on the core it was sent to
+pub type Message = Box<dyn FnOnce() + Send>;
+
+/// Priority band of a message
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    /// Control messages which must be handled as soon as possible
+    Urgent = 0,
+
+    /// Regular messages
+    Normal = 1,
+
+    /// Large volumes of messages which can be delayed, like statistics
+    Bulk = 2,
+}
+
+/// Queues of pending messages for a core, one for each priority band
+struct Mailbox {
+    bands: [Queue<Message>; 3],
+}
+
+/// Create the mailbox for the current core. Kernel statics survive a soft
+/// reboot, thus this replaces the mailbox left over from the previous boot.
+pub fn init() {
+    let mailbox = Box::new(Mailbox {
+        bands: [
+            Queue::new(BAND_CAPACITY),
+            Queue::new(BAND_CAPACITY),
+            Queue::new(BAND_CAPACITY),
+        ],
+    });
+
+    // The old mailbox is leaked rather than freed, as it may hold messages
+    // which were sent by the previous kernel and can't be dropped by us
+    MAILBOXES[core!().id as usize]
+        .store(Box::into_raw(mailbox), Ordering::SeqCst);
+}
+
+/// Get the mailbox of the core with ID `core_id`, if it has one
+fn mailbox(core_id: u32) -> Option<&'static Mailbox> {
+    let ptr = MAILBOXES.get(core_id as usize)?.load(Ordering::SeqCst);
+
+    // Mailboxes are never freed once they have been created
+    unsafe { ptr.as_ref() }
+}
+
+/// Send `message` to the core with ID `core_id` in the band `priority`. If
+/// the core has no mailbox or the band is full, the message is given back.
+pub fn send(core_id: u32, priority: Priority, message: Message)
+        -> Result<(), Message> {
+    match mailbox(core_id) {
+        Some(mailbox) => mailbox.bands[priority as usize].try_push(message),
+        None          => Err(message),
+    }
+}
+
+/// Send a closure `func` to the core with ID `core_id`, returning `false` if
+/// it could not be queued
+pub fn send_fn<F>(core_id: u32, priority: Priority, func: F) -> bool
+        where F: FnOnce() + Send + 'static {
+    send(core_id, priority, Box::new(func)).is_ok()
+}
+
+/// Handle all pending urgent messages for the current core, returning the
+/// number of messages handled
+pub fn dispatch_urgent() -> usize {
+    let mailbox = match mailbox(core!().id) {
+        Some(mailbox) => mailbox,
+        None          => return 0,
+    };
+
+    let mut handled = 0;
+    while let Some(message) = mailbox.bands[Priority::Urgent as usize]
+            .try_pop() {
+        message();
+        handled += 1;
+    }
+
+    handled
+}
+
+/// Handle pending messages for the current core, returning the number of
+/// messages handled. All urgent messages are handled, and up to a batch of
+/// the normal and bulk messages.
+pub fn dispatch() -> usize {
+    let mailbox = match mailbox(core!().id) {
+        Some(mailbox) => mailbox,
+        None          => return 0,
+    };
+
+    let mut handled = dispatch_urgent();
+
+    for &(band, batch) in &[
+        (Priority::Normal, NORMAL_BATCH),
+        (Priority::Bulk,   BULK_BATCH),
+    ] {
+        for _ in 0..batch {
+            let message = match mailbox.bands[band as usize].try_pop() {
+                Some(message) => message,
+                None          => break,
+            };
+
+            message();
+            handled += 1;
+
+            // Don't let urgent messages which came in meanwhile wait
+            handled += dispatch_urgent();
+        }
+    }
+
+    handled
+}
+
+/// Run `func` on every core over the band `priority`, returning what it
+/// returned on each core along with the core's ID, sorted by core ID. Cores
+/// which don't answer within `timeout` microseconds are left out, like parked
+/// cores for all bands but the urgent one.
+pub fn gather<T, F>(priority: Priority, timeout: u64, func: F)
+        -> Vec<(u32, T)>
+        where T: Send + 'static, F: Fn() -> T + Send + Sync + 'static {
+    let answers: Arc<LockCell<Vec<(u32, T)>, LockInterrupts>> =
+        Arc::new(LockCell::new(Vec::new()));
+    let func = Arc::new(func);
+
+    // Answer for ourselves directly, as we don't handle our own messages
+    // while we wait
+    answers.lock().push((core!().id, func()));
+    let mut expected = 1;
+
+    for core_id in 0..acpi::num_cores() {
+        if core_id == core!().id { continue; }
+
+        let answers = answers.clone();
+        let func    = func.clone();
+        let sent = send_fn(core_id, priority, move || {
+            let answer = func();
+            answers.lock().push((core!().id, answer));
+        });
+
+        if sent {
+            expected += 1;
+        }
+    }
+
+    // Wait for the answers, handling urgent messages to us meanwhile such
+    // that a core gathering from us at the same time isn't held up
+    let deadline = time::future(timeout);
+    while answers.lock().len() < expected && cpu::rdtsc() < deadline {
+        dispatch_urgent();
+        core::hint::spin_loop();
+    }
+
+    // Answers which come in late are dropped along with the last reference
+    let mut answers = core::mem::take(&mut *answers.lock());
+    answers.sort_by_key(|x| x.0);
+    answers
+}
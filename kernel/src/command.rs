@@ -0,0 +1,448 @@
+//! Remote command channel
+//!
+//! The first configured network device listens for commands from the server
+//! on UDP port 1339, such that nodes can be managed without physical access.
+//! Commands are authenticated with an HMAC-SHA256 keyed with the 256-bit key
+//! in the `chocolate_milk.cmdkey` file the bootloader downloads. The key is
+//! deliberately not a tunable, as tunables can be changed over the network.
+//! The channel is disabled if there is no key.
+//!
+//! Every boot picks a random nonce, and commands carry the nonce of the boot
+//! they're meant for and a sequence number which must increase with every
+//! command of that boot. Thus captured commands can't be replayed, neither
+//! during the same boot nor after a reboot. Authentic commands with the
+//! wrong nonce are not performed, they're answered with `StaleNonce` which
+//! tells the server the current nonce. Every reply carries the nonce too.
+//!
+//! Command: `CMCOMMND` | node ID (u64 LE) | nonce (u64 LE) | sequence (u64 LE)
+//!          | command (u8) | arguments | HMAC-SHA256 of everything before it
+//! Reply:   `CMRESULT` | node ID (u64 LE) | nonce (u64 LE) | sequence (u64 LE)
+//!          | status (u8) | text
+
+use core::fmt::Write;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+
+use crate::net::{NetDevice, UdpSocket, Udp, Ipv4Addr};
+use crate::executor::{self, TaskStatus};
+use crate::bus::{self, Priority};
+use crate::tunables;
+use crate::{acpi, cpu_features, time};
+use crate::acpi::ApicState;
+
+/// UDP port we listen for commands on
+const COMMAND_PORT: u16 = 1339;
+
+/// Magic at the start of a command
+const COMMAND_MAGIC: &[u8; 8] = b"CMCOMMND";
+
+/// Magic at the start of a reply
+const REPLY_MAGIC: &[u8; 8] = b"CMRESULT";
+
+/// Size of the magic, node ID, nonce, sequence number, and command
+const HEADER_SIZE: usize = 8 + 8 + 8 + 8 + 1;
+
+/// Size of the HMAC at the end of a command
+const MAC_SIZE: usize = 32;
+
+/// Size of the key commands are authenticated with
+const KEY_SIZE: usize = 32;
+
+/// Maximum number of bytes of text in a reply
+const MAX_REPLY_TEXT: usize = 1024;
+
+/// Time to wait for every core to report its stats, in microseconds
+const STATS_TIMEOUT: u64 = 50_000;
+
+/// Interval at which we check for commands, in microseconds
+const POLL_INTERVAL: u64 = 10_000;
+
+/// Time to wait after replying to a reboot command before rebooting, in
+/// microseconds
+const REBOOT_DELAY: u64 = 10_000;
+
+/// Set once a device is listening for commands
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Commands the server can send
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Command {
+    /// Reset the machine
+    Reboot = 0,
+
+    /// Soft reboot into a freshly downloaded kernel
+    SoftReboot = 1,
+
+    /// Switch the fuzz target this node is working on
+    SwitchTarget = 2,
+
+    /// Apply tunables given as `name = value` lines in the arguments
+    SetTunable = 3,
+
+    /// Report node statistics
+    DumpStats = 4,
+
+    /// Report the registered locks which are currently held
+    DumpLocks = 5,
+
+    /// Stop and wait for a debugger
+    EnterGdb = 6,
+
+    /// Park the core whose ID is given as a u32 LE argument
+    Park = 7,
+
+    /// Unpark the core whose ID is given as a u32 LE argument
+    Unpark = 8,
+}
+
+impl Command {
+    /// Get the command with the number `num`
+    fn from_u8(num: u8) -> Option<Self> {
+        Some(match num {
+            0 => Command::Reboot,
+            1 => Command::SoftReboot,
+            2 => Command::SwitchTarget,
+            3 => Command::SetTunable,
+            4 => Command::DumpStats,
+            5 => Command::DumpLocks,
+            6 => Command::EnterGdb,
+            7 => Command::Park,
+            8 => Command::Unpark,
+            _ => return None,
+        })
+    }
+}
+
+/// An authenticated command received from the server
+struct Request {
+    /// Address and port the command came from, which the reply is sent to
+    src_mac:  [u8; 6],
+    src_ip:   Ipv4Addr,
+    src_port: u16,
+
+    /// Nonce of the boot the command is meant for
+    nonce: u64,
+
+    /// Sequence number of the command
+    sequence: u64,
+
+    /// Raw command number
+    command: u8,
+
+    /// Arguments of the command
+    args: Vec<u8>,
+}
+
+/// Result of a command, sent back in the reply
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Status {
+    /// The command was performed
+    Ok = 0,
+
+    /// The command is not supported by this node
+    Unsupported = 1,
+
+    /// The arguments of the command were invalid
+    InvalidArguments = 2,
+
+    /// The command was meant for another boot and was not performed, the
+    /// reply carries the nonce of the current boot
+    StaleNonce = 3,
+}
+
+/// Get the key to authenticate commands with, as provided by the bootloader.
+/// Returns `None` if there is no valid key.
+fn key() -> Option<[u8; KEY_SIZE]> {
+    let addr = core!().boot_args.command_key_addr.load(Ordering::SeqCst);
+    let size = core!().boot_args.command_key_size.load(Ordering::SeqCst);
+    if addr == 0 {
+        return None;
+    }
+
+    if size != KEY_SIZE as u64 {
+        print!("Command key is {} bytes instead of {}, ignoring\n",
+               size, KEY_SIZE);
+        return None;
+    }
+
+    // Make sure the key is in our physical window
+    assert!(addr.checked_add(size - 1)
+            .map(|end| end < KERNEL_PHYS_WINDOW_SIZE) == Some(true),
+        "Command key outside of physical window");
+
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(unsafe {
+        core::slice::from_raw_parts(
+            (KERNEL_PHYS_WINDOW_BASE + addr) as *const u8, KEY_SIZE)
+    });
+    Some(key)
+}
+
+/// Pick the nonce of this boot. This uses `rdrand` if it is available,
+/// otherwise the TSC mixed with the node ID, which at least differs between
+/// boots.
+fn boot_nonce(node_id: u64) -> u64 {
+    if cpu_features::get().rdrand {
+        // Retry a few times if the hardware RNG is not ready
+        let rand32 = || (0..16).find_map(|_| unsafe { cpu::rdrand32() });
+        if let (Some(high), Some(low)) = (rand32(), rand32()) {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+
+    let mut seed = [0u8; 16];
+    seed[..8].copy_from_slice(&cpu::rdtsc().to_le_bytes());
+    seed[8..].copy_from_slice(&node_id.to_le_bytes());
+    u64::from_le_bytes(hashes::blake3(&seed)[..8].try_into().unwrap())
+}
+
+/// Reset the machine through the reset control register of the chipset
+fn reboot() -> ! {
+    unsafe { cpu::out8(0xcf9, 0x06); }
+
+    // The reset may take a moment to happen
+    cpu::halt();
+}
+
+/// Compare two MACs without leaking where they differ through timing
+fn mac_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() &&
+        a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Perform `command` with `args`, returning the status and text of the reply
+fn handle(device: &NetDevice, command: Command, args: &[u8])
+        -> (Status, String) {
+    let mut text = String::new();
+
+    let status = match command {
+        Command::Reboot | Command::SoftReboot => {
+            // These never return, they're performed once the reply was sent
+            Status::Ok
+        }
+        Command::SetTunable => {
+            match core::str::from_utf8(args) {
+                Ok(args) => {
+                    tunables::apply(args);
+                    Status::Ok
+                }
+                Err(_) => Status::InvalidArguments,
+            }
+        }
+        Command::DumpStats => {
+            let _ = write!(text, "uptime {:.6}\ncores {}\nrtt (us) {}\n",
+                           time::uptime(), acpi::num_cores(), device.rtt());
+
+            #[cfg(feature = "lock_hold_check")]
+            {
+                let (waited, held) = crate::locks::times();
+                let _ = write!(text, "lock wait (cycles) {}\n\
+                                      lock hold (cycles) {}\n", waited, held);
+            }
+
+            // Report the state of every present core, such that parked cores
+            // show up
+            for apic_id in 0..acpi::MAX_CORES as u32 {
+                let state = acpi::core_state(apic_id);
+                if state != ApicState::None {
+                    let _ = write!(text, "apic {} {:?}\n", apic_id, state);
+                }
+            }
+
+            // Ask every core for its stats over the bulk band of the bus
+            let stats = bus::gather(Priority::Bulk, STATS_TIMEOUT,
+                                    || core!().tasks.lock().len());
+            for (core_id, tasks) in stats {
+                let _ = write!(text, "core {} tasks {}\n", core_id, tasks);
+            }
+            Status::Ok
+        }
+        Command::DumpLocks => {
+            crate::locks::for_each_held(|reg, holder| {
+                let _ = match holder {
+                    Some(core) => write!(text, "{} held by core {}\n",
+                                         reg.name, core),
+                    None       => write!(text, "{} held\n", reg.name),
+                };
+            });
+            Status::Ok
+        }
+        Command::Park | Command::Unpark => {
+            // We can't park the core handling commands, as nothing could
+            // unpark it
+            let core_id = args.try_into().ok().map(u32::from_le_bytes);
+            match core_id {
+                Some(core_id) if core_id < acpi::num_cores() &&
+                        core_id != core!().id => {
+                    if command == Command::Park {
+                        executor::park(core_id);
+                    } else {
+                        executor::unpark(core_id);
+                    }
+                    Status::Ok
+                }
+                _ => Status::InvalidArguments,
+            }
+        }
+        Command::SwitchTarget | Command::EnterGdb => {
+            // There are no fuzz targets or debugger stub to switch to yet
+            Status::Unsupported
+        }
+    };
+
+    (status, text)
+}
+
+/// Parse and authenticate the command in `udp`. Returns `None` if the packet
+/// isn't an authentic command for us. The nonce and sequence number are
+/// left for the caller to check.
+fn parse(udp: &Udp, key: &[u8; KEY_SIZE], node_id: u64) -> Option<Request> {
+    let payload = udp.payload;
+    if payload.len() < HEADER_SIZE + MAC_SIZE ||
+            &payload[..8] != COMMAND_MAGIC {
+        return None;
+    }
+
+    // Make sure the command is authentic
+    let (body, mac) = payload.split_at(payload.len() - MAC_SIZE);
+    if !mac_equal(&hashes::hmac_sha256(key, body), mac) {
+        print!("Dropping unauthenticated command from {:?}\n",
+               udp.ip.src_ip);
+        return None;
+    }
+
+    // Make sure the command is meant for us
+    let target = u64::from_le_bytes(body[8..16].try_into().unwrap());
+    if target != node_id {
+        return None;
+    }
+
+    Some(Request {
+        src_mac:  udp.ip.eth.src_mac,
+        src_ip:   udp.ip.src_ip,
+        src_port: udp.src_port,
+        nonce:    u64::from_le_bytes(body[16..24].try_into().unwrap()),
+        sequence: u64::from_le_bytes(body[24..32].try_into().unwrap()),
+        command:  body[32],
+        args:     body[HEADER_SIZE..].to_vec(),
+    })
+}
+
+/// Start listening for commands on `device`. Only the first device to call
+/// this listens for commands.
+pub fn listen(device: &'static NetDevice) {
+    if LISTENING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let key = match key() {
+        Some(key) => key,
+        None => {
+            print!("No command key, not listening for commands\n");
+            return;
+        }
+    };
+
+    let socket = match UdpSocket::bind(device, COMMAND_PORT) {
+        Some(socket) => socket,
+        None => {
+            print!("Command port {} already in use, not listening for \
+                    commands\n", COMMAND_PORT);
+            return;
+        }
+    };
+
+    // Only commands carrying the nonce of this boot are performed
+    let node_id = crate::node::id().unwrap_or(0);
+    let nonce   = boot_nonce(node_id);
+
+    // Sequence number of the last command we accepted
+    let mut last_sequence = 0u64;
+
+    executor::spawn("command", POLL_INTERVAL, move || {
+        // Handle all commands which have arrived
+        while let Some(request) = socket.recv(|_, udp| {
+            Some(parse(&udp, &key, node_id))
+        }) {
+            // Skip anything which isn't a valid command
+            let request = match request {
+                Some(request) => request,
+                None          => continue,
+            };
+
+            // Let the server know the nonce of this boot if the command was
+            // meant for another one
+            if request.nonce != nonce {
+                reply(&socket, &request, node_id, nonce, Status::StaleNonce,
+                      &[]);
+                continue;
+            }
+
+            // Skip replays
+            if request.sequence <= last_sequence {
+                continue;
+            }
+
+            // Don't accept this command, or anything before it, again
+            last_sequence = request.sequence;
+
+            let command = Command::from_u8(request.command);
+            print!("Command {:?} from {:?}\n", command, request.src_ip);
+
+            let (status, text) = match command {
+                Some(command) => handle(device, command, &request.args),
+                None          => (Status::Unsupported, String::new()),
+            };
+
+            reply(&socket, &request, node_id, nonce, status,
+                  text.as_bytes());
+
+            // Reboot only once the server knows we got the command, giving
+            // the NIC a moment to get the reply out
+            match command {
+                Some(Command::Reboot) => {
+                    time::sleep(REBOOT_DELAY);
+                    reboot();
+                }
+                Some(Command::SoftReboot) => {
+                    time::sleep(REBOOT_DELAY);
+                    crate::panic::request_soft_reboot("command channel");
+                }
+                _ => {}
+            }
+        }
+
+        TaskStatus::Continue
+    });
+
+    print!("Listening for commands on port {}\n", COMMAND_PORT);
+}
+
+/// Stop listening for commands, as the device we listen on is about to be
+/// torn down by a soft reboot. The statics survive the soft reboot, thus
+/// this lets the first device of the next boot listen again.
+pub fn disconnect() {
+    LISTENING.store(false, Ordering::SeqCst);
+}
+
+/// Send a reply to `request` with `status` and `text`, carrying the `nonce`
+/// of this boot
+fn reply(socket: &UdpSocket, request: &Request, node_id: u64, nonce: u64,
+         status: Status, text: &[u8]) {
+    let text = &text[..core::cmp::min(text.len(), MAX_REPLY_TEXT)];
+
+    socket.send_to(request.src_mac, request.src_ip, request.src_port,
+                   |packet| {
+        let payload = packet.put(HEADER_SIZE + text.len());
+        payload[..8].copy_from_slice(REPLY_MAGIC);
+        payload[8..16].copy_from_slice(&node_id.to_le_bytes());
+        payload[16..24].copy_from_slice(&nonce.to_le_bytes());
+        payload[24..32].copy_from_slice(&request.sequence.to_le_bytes());
+        payload[32] = status as u8;
+        payload[HEADER_SIZE..].copy_from_slice(text);
+    });
+}
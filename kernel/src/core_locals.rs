@@ -1,10 +1,17 @@
 //! This file is used to hold and access all of the core locals
 
 use core::sync::atomic::{AtomicUsize, AtomicU32, Ordering};
+#[cfg(feature = "lock_hold_check")]
+use core::sync::atomic::AtomicBool;
+use alloc::vec::Vec;
 
 use crate::apic::Apic;
+use crate::timer::Deadline;
+use crate::executor::ScheduledTask;
 use crate::mm::PageFreeList;
 use crate::interrupts::Interrupts;
+#[cfg(feature = "profile")]
+use crate::profile::Samples;
 
 use lockcell::LockCell;
 use page_table::PhysAddr;
@@ -58,6 +65,12 @@ impl<'a> Drop for AutoAtomicRefGuard<'a> {
     }
 }
 
+/// Maximum number of microseconds a lock which disables interrupts may be
+/// held for before we panic, `0` for unlimited
+#[cfg(feature = "lock_hold_check")]
+pub static LOCK_HOLD_BUDGET: crate::tunables::Tunable =
+    crate::tunables::Tunable::new("lock_hold_budget", 1000);
+
 /// A empty structure to implement interrupt disablement for pre-emptable locks
 pub struct LockInterrupts;
 
@@ -70,6 +83,10 @@ impl lockcell::InterruptState for LockInterrupts {
         core!().in_exception()
     }
 
+    fn interrupt_depth() -> u32 {
+        core!().interrupt_depth()
+    }
+
     fn core_id() -> u32 {
         core!().id
     }
@@ -81,6 +98,24 @@ impl lockcell::InterruptState for LockInterrupts {
     fn exit_lock() {
         unsafe { core!().enable_interrupts(); }
     }
+
+    #[cfg(feature = "lock_hold_check")]
+    fn hold_budget() -> Option<u64> {
+        match LOCK_HOLD_BUDGET.get() {
+            0  => None,
+            us => Some(us * crate::time::tsc_mhz()),
+        }
+    }
+
+    #[cfg(feature = "lock_hold_check")]
+    fn timestamp() -> u64 {
+        cpu::rdtsc()
+    }
+
+    #[cfg(feature = "lock_hold_check")]
+    fn lock_released(waited: u64, held: u64) {
+        crate::locks::record_times(waited, held);
+    }
 }
 
 /// A core-exclusive data structure which can be accessed via the `core!()`
@@ -111,6 +146,18 @@ pub struct CoreLocals {
     /// A core local free list of pages
     pub free_list: LockCell<PageFreeList, LockInterrupts>,
 
+    /// Pending deadline timers for this core, sorted by expiry. This is read
+    /// by the APIC timer interrupt when it programs the next tick.
+    pub deadlines: LockCell<Vec<Deadline>, LockInterrupts>,
+
+    /// Tasks run by the cooperative executor on this core
+    pub tasks: LockCell<Vec<ScheduledTask>, LockInterrupts>,
+
+    /// Profiler samples for this core. These are recorded from the timer
+    /// interrupt, thus the lock disables interrupts while held.
+    #[cfg(feature = "profile")]
+    pub profile: LockCell<Samples, LockInterrupts>,
+
     /// Current level of interrupt nesting. Incremented on every interrupt
     /// entry, and decremented on every interrupt return.
     interrupt_depth: AutoAtomicRef,
@@ -130,6 +177,11 @@ pub struct CoreLocals {
 
     /// Get the core's APIC ID
     apic_id: AtomicU32,
+
+    /// Set while this core records lock times, such that the lock the times
+    /// are recorded under isn't recorded itself
+    #[cfg(feature = "lock_hold_check")]
+    pub recording_lock_times: AtomicBool,
 }
 
 /// Empty marker trait that requires `Sync`, such that we can compile-time
@@ -173,6 +225,11 @@ impl CoreLocals {
         self.interrupt_depth.count() > 0
     }
 
+    /// Get the number of interrupt handlers currently nested on this core
+    pub fn interrupt_depth(&self) -> u32 {
+        self.interrupt_depth.count() as u32
+    }
+
     /// Disable interrupts and increase the interrupt disable reference count
     ///
     /// Interrupts will always be disabled when this code executes. This will
@@ -245,6 +302,7 @@ pub fn init(boot_args: PhysAddr, core_id: u32) {
     impl lockcell::InterruptState for DummyLockInterrupts {
         fn in_interrupt() -> bool { false }
         fn in_exception() -> bool { false }
+        fn interrupt_depth() -> u32 { 0 }
         fn core_id() -> u32 { unsafe { cpu::gs_base() as u32 } }
         fn enter_lock() {}
         fn exit_lock() {}
@@ -279,10 +337,18 @@ pub fn init(boot_args: PhysAddr, core_id: u32) {
         free_list:  LockCell::new(PageFreeList::new()),
         apic:       LockCell::new_no_preempt(None),
         interrupts: LockCell::new_no_preempt(None),
+        deadlines:  LockCell::new_no_preempt(Vec::new()),
+        tasks:      LockCell::new(Vec::new()),
+
+        #[cfg(feature = "profile")]
+        profile: LockCell::new_no_preempt(Samples::new()),
 
         interrupt_depth:               AutoAtomicRef::new(0),
         exception_depth:               AutoAtomicRef::new(0),
         interrupt_disable_outstanding: AtomicUsize::new(1),
+
+        #[cfg(feature = "lock_hold_check")]
+        recording_lock_times: AtomicBool::new(false),
     };
 
     unsafe {
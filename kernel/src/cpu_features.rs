@@ -0,0 +1,55 @@
+//! System-wide CPU feature detection
+//!
+//! Features are enumerated once by the BSP during boot and are then
+//! accessible from anywhere in the kernel via `get()`. Every other core
+//! re-enumerates its own features when it comes online and verifies that they
+//! match what the BSP found, as the rest of the kernel assumes a homogeneous
+//! system.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+use alloc::boxed::Box;
+
+use cpu::CPUFeatures;
+
+/// Features detected on the BSP. Until the BSP has initialized this, it is
+/// null.
+static CPU_FEATURES: AtomicPtr<CPUFeatures> =
+    AtomicPtr::new(core::ptr::null_mut());
+
+/// Get the CPU features of the system
+pub fn get() -> &'static CPUFeatures {
+    // Get the features detected during boot
+    let features = CPU_FEATURES.load(Ordering::SeqCst);
+    assert!(!features.is_null(), "CPU features have not been initialized");
+
+    unsafe { &*features }
+}
+
+/// Enumerate the CPU features for the current core. On the BSP this
+/// establishes the system-wide features, on all other cores this verifies
+/// that this core supports exactly what the BSP supports.
+pub fn init() {
+    // Detect the features of the core we're running on
+    let features = cpu::get_cpu_features();
+
+    if core!().id == 0 {
+        // Store the features as the system-wide features
+        let old = CPU_FEATURES.swap(Box::into_raw(Box::new(features)),
+                                    Ordering::SeqCst);
+
+        // Kernel statics survive a soft reboot, thus the features may still
+        // be set from the previous boot. All other cores were halted for the
+        // soft reboot and nothing from the previous boot uses them anymore,
+        // thus free them.
+        if !old.is_null() {
+            unsafe { drop(Box::from_raw(old)); }
+        }
+    } else {
+        // Make sure this core matches the features we found on the BSP
+        let bsp_features = get();
+        assert!(&features == bsp_features,
+            "CPU features on core {} do not match the BSP\n\
+             Core {}: {:#x?}\nBSP: {:#x?}",
+            core!().id, core!().id, features, bsp_features);
+    }
+}
@@ -0,0 +1,394 @@
+//! Crash artifact ring which persists across soft reboots
+//!
+//! The bootloader places `CRASH_RING_SIZE` bytes of memory at the same
+//! physical address every boot. Crash artifacts are staged here such that
+//! they are not lost if they cannot be shipped off immediately, eg. because
+//! the network is down or we're panicking. Once they have been shipped they
+//! are removed from the ring.
+//!
+//! The first page of the ring holds a `RingHeader`, the rest holds records.
+//! Each record is a `RecordHeader` followed by its data, padded to 16 bytes.
+//! Records never straddle the end of the ring, instead a wrap record pads
+//! out the end. When the ring is full the oldest records are dropped. Every
+//! record has a CRC such that anything damaged during a reboot is thrown
+//! away rather than shipped.
+
+use core::mem::size_of;
+use core::sync::atomic::Ordering;
+
+use lockcell::LockCell;
+use page_table::PhysAddr;
+use boot_args::{CRASH_RING_SIZE, KERNEL_PHYS_WINDOW_BASE};
+
+use crate::core_locals::LockInterrupts;
+use crate::physmap::{self, RegionType};
+
+/// Magic value identifying an initialized ring header
+const RING_MAGIC: u64 = u64::from_le_bytes(*b"CMcrash1");
+
+/// Magic value identifying a record
+const RECORD_MAGIC: u32 = u32::from_le_bytes(*b"CMrc");
+
+/// Kind used for records which pad out the end of the ring
+const KIND_WRAP: u32 = !0;
+
+/// Alignment of records in the ring
+const RECORD_ALIGN: u64 = 16;
+
+/// Offset of the record storage from the start of the ring
+const DATA_OFFSET: u64 = 4096;
+
+/// Number of bytes available for records
+const CAPACITY: u64 = CRASH_RING_SIZE - DATA_OFFSET;
+
+/// The crash ring, `None` until it has been initialized
+static RING: LockCell<Option<CrashRing>, LockInterrupts> =
+    LockCell::new(None);
+register_lock!(RING);
+
+/// Kinds of crash artifacts stored in the ring
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum RecordKind {
+    /// Panic message of the kernel
+    Panic = 1,
+
+    /// Input which caused a crash
+    CrashInput = 2,
+
+    /// Report describing a crash
+    CrashReport = 3,
+}
+
+impl RecordKind {
+    /// Convert a raw record kind into a `RecordKind`
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(RecordKind::Panic),
+            2 => Some(RecordKind::CrashInput),
+            3 => Some(RecordKind::CrashReport),
+            _ => None,
+        }
+    }
+}
+
+/// Header at the start of the ring
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RingHeader {
+    /// Set to `RING_MAGIC` once the ring has been initialized
+    magic: u64,
+
+    /// Offset of the oldest record
+    head: u64,
+
+    /// Offset at which the next record will be written
+    tail: u64,
+
+    /// Number of bytes used by records, including padding
+    used: u64,
+
+    /// Number of records which were dropped due to lack of space
+    dropped: u64,
+}
+
+/// Header of a record in the ring
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RecordHeader {
+    /// Set to `RECORD_MAGIC`
+    magic: u32,
+
+    /// Raw `RecordKind`, or `KIND_WRAP`
+    kind: u32,
+
+    /// Length of the data following the header in bytes
+    len: u32,
+
+    /// CRC32 of the kind, length, and data
+    crc: u32,
+}
+
+/// Compute the CRC32 of `bytes`, continuing from `crc`
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// Compute the CRC of a record
+fn record_crc(kind: u32, data: &[u8]) -> u32 {
+    let crc = crc32(0, &kind.to_le_bytes());
+    let crc = crc32(crc, &(data.len() as u32).to_le_bytes());
+    crc32(crc, data)
+}
+
+/// Get the number of bytes a record with `len` bytes of data uses in the
+/// ring
+fn record_size(len: u64) -> u64 {
+    (size_of::<RecordHeader>() as u64 + len + RECORD_ALIGN - 1) &
+        !(RECORD_ALIGN - 1)
+}
+
+/// The crash ring
+struct CrashRing {
+    /// Virtual address of the ring in the physical window
+    base: *mut u8,
+
+    /// Copy of the header, written back to the ring on every update
+    header: RingHeader,
+}
+
+unsafe impl Send for CrashRing {}
+
+impl CrashRing {
+    /// Get a pointer to the record storage at `offset`
+    unsafe fn data(&self, offset: u64) -> *mut u8 {
+        self.base.offset((DATA_OFFSET + offset) as isize)
+    }
+
+    /// Write back the header to the ring
+    unsafe fn flush_header(&mut self) {
+        core::ptr::write_volatile(self.base as *mut RingHeader, self.header);
+    }
+
+    /// Reset the ring to be empty
+    unsafe fn reset(&mut self) {
+        self.header = RingHeader {
+            magic: RING_MAGIC, head: 0, tail: 0, used: 0, dropped: 0,
+        };
+        self.flush_header();
+    }
+
+    /// Read the record at the head of the ring, returning the header, the
+    /// number of bytes it uses in the ring, and its data. Returns `None` if
+    /// the ring is empty or the record is damaged.
+    unsafe fn peek(&self) -> Option<(RecordHeader, u64, &[u8])> {
+        let head = self.header.head;
+        if self.header.used == 0 ||
+                head + size_of::<RecordHeader>() as u64 > CAPACITY {
+            return None;
+        }
+
+        let record = core::ptr::read_volatile(
+            self.data(head) as *const RecordHeader);
+        if record.magic != RECORD_MAGIC {
+            return None;
+        }
+
+        // Wrap records use the rest of the ring
+        if record.kind == KIND_WRAP {
+            return Some((record, CAPACITY - head, &[]));
+        }
+
+        // Make sure the record fits in the ring
+        let size = record_size(record.len as u64);
+        if head + size > CAPACITY || size > self.header.used {
+            return None;
+        }
+
+        // Get the data and make sure it's intact
+        let data = core::slice::from_raw_parts(
+            self.data(head + size_of::<RecordHeader>() as u64),
+            record.len as usize);
+        if record_crc(record.kind, data) != record.crc {
+            return None;
+        }
+
+        Some((record, size, data))
+    }
+
+    /// Remove the `size` byte record at the head of the ring
+    unsafe fn pop(&mut self, size: u64) {
+        self.header.head = (self.header.head + size) % CAPACITY;
+        self.header.used -= size;
+        if self.header.used == 0 {
+            self.header.head = 0;
+            self.header.tail = 0;
+        }
+        self.flush_header();
+    }
+
+    /// Make sure the ring header and all records in the ring are intact,
+    /// invoking `func` on each record, oldest first
+    unsafe fn validate<F>(&self, mut func: F) -> bool
+            where F: FnMut(&RecordHeader, &[u8]) {
+        let header = self.header;
+        if header.magic != RING_MAGIC || header.head >= CAPACITY ||
+                header.tail >= CAPACITY || header.used > CAPACITY ||
+                (header.head % RECORD_ALIGN) != 0 ||
+                (header.tail % RECORD_ALIGN) != 0 {
+            return false;
+        }
+
+        // Walk all of the records on a copy of the ring
+        let mut walk = CrashRing { base: self.base, header };
+        while walk.header.used > 0 {
+            match walk.peek() {
+                Some((record, size, data)) => {
+                    func(&record, data);
+                    walk.header.head = (walk.header.head + size) % CAPACITY;
+                    walk.header.used -= size;
+                }
+                None => return false,
+            }
+        }
+
+        // All records should end exactly at the tail
+        walk.header.head == header.tail || header.used == 0
+    }
+
+    /// Append a record, dropping the oldest records if needed to make room
+    unsafe fn push(&mut self, kind: RecordKind, data: &[u8]) {
+        let size = record_size(data.len() as u64);
+
+        // If this record can never fit, drop it
+        if size > CAPACITY {
+            self.header.dropped += 1;
+            self.flush_header();
+            return;
+        }
+
+        // If the record does not fit before the end of the ring, pad out the
+        // end of the ring and wrap around to the start
+        if self.header.tail + size > CAPACITY {
+            let pad = CAPACITY - self.header.tail;
+            self.make_room(pad);
+
+            // If the ring was emptied the tail was rewound, and there is
+            // nothing to pad out
+            if self.header.tail != 0 {
+                core::ptr::write_volatile(
+                    self.data(self.header.tail) as *mut RecordHeader,
+                    RecordHeader {
+                        magic: RECORD_MAGIC, kind: KIND_WRAP, len: 0, crc: 0,
+                    });
+                self.header.tail  = 0;
+                self.header.used += pad;
+            }
+        }
+
+        // Make room for the record and write it
+        self.make_room(size);
+        let tail = self.header.tail;
+        core::ptr::write_volatile(self.data(tail) as *mut RecordHeader,
+            RecordHeader {
+                magic: RECORD_MAGIC,
+                kind:  kind as u32,
+                len:   data.len() as u32,
+                crc:   record_crc(kind as u32, data),
+            });
+        core::ptr::copy_nonoverlapping(data.as_ptr(),
+            self.data(tail + size_of::<RecordHeader>() as u64), data.len());
+
+        self.header.tail  = (tail + size) % CAPACITY;
+        self.header.used += size;
+        self.flush_header();
+    }
+
+    /// Drop the oldest records until there are `size` free bytes after the
+    /// tail
+    unsafe fn make_room(&mut self, size: u64) {
+        while CAPACITY - self.header.used < size {
+            let (record, used, _) = self.peek()
+                .expect("Crash ring corrupted while in use");
+            self.pop(used);
+
+            // Wrap records are just padding, they don't count as drops
+            if record.kind != KIND_WRAP {
+                self.header.dropped += 1;
+            }
+        }
+
+        // An empty ring always starts at the beginning, rewind the tail in
+        // case we emptied it
+        if self.header.used == 0 {
+            self.header.head = 0;
+            self.header.tail = 0;
+        }
+    }
+}
+
+/// Stage a crash artifact of `kind` in the ring. If the ring is full the
+/// oldest artifacts are dropped to make room.
+#[allow(dead_code)]
+pub fn stage(kind: RecordKind, data: &[u8]) {
+    if let Some(ring) = RING.lock().as_mut() {
+        unsafe { ring.push(kind, data); }
+    }
+}
+
+/// Stage a crash artifact without taking the ring lock. This is only to be
+/// used while panicking, when all other cores have been halted.
+pub unsafe fn stage_panic(data: &[u8]) {
+    if let Some(ring) = (&mut *RING.shatter()).as_mut() {
+        ring.push(RecordKind::Panic, data);
+    }
+}
+
+/// Ship staged crash artifacts, oldest first, by passing them to `ship`. If
+/// `ship` returns `false` the artifact could not be shipped and is kept in
+/// the ring, and shipping stops.
+pub fn drain<F>(mut ship: F) where F: FnMut(RecordKind, &[u8]) -> bool {
+    let mut ring = RING.lock();
+    let ring = if let Some(ring) = ring.as_mut() { ring } else { return };
+
+    unsafe {
+        while let Some((record, size, data)) = ring.peek() {
+            // Skip over wrap records and records of kinds we don't know
+            let shipped = match RecordKind::from_raw(record.kind) {
+                Some(kind) => ship(kind, data),
+                None       => true,
+            };
+
+            if !shipped { break; }
+            ring.pop(size);
+        }
+    }
+}
+
+/// Get access to the crash ring, keeping any artifacts from previous boots
+/// if they are intact
+pub unsafe fn init() {
+    let paddr = PhysAddr(core!().boot_args.crash_ring_addr
+        .load(Ordering::SeqCst));
+    assert!(paddr.0 != 0, "Bootloader did not provide a crash ring");
+
+    // Reserve the ring in the physical address space
+    physmap::reserve("crash ring", RegionType::Memory, paddr,
+                     CRASH_RING_SIZE);
+
+    // Get access to the ring in the physical window
+    let base = (KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut u8;
+    let mut ring = CrashRing {
+        base,
+        header: core::ptr::read_volatile(base as *const RingHeader),
+    };
+
+    // Report any panics from previous boots, if the ring is intact
+    let intact = ring.validate(|record, data| {
+        if record.kind == RecordKind::Panic as u32 {
+            // The message may have been truncated mid-character
+            let msg = core::str::from_utf8(data).unwrap_or_else(|err| {
+                core::str::from_utf8(&data[..err.valid_up_to()]).unwrap()
+            });
+            print!("Panic from a previous boot:\n{}\n", msg);
+        }
+    });
+
+    if !intact {
+        // Nothing usable in the ring, start over
+        ring.reset();
+    } else if ring.header.used > 0 || ring.header.dropped > 0 {
+        print!("Crash ring holds {} bytes of artifacts from previous \
+                boots, {} dropped\n", ring.header.used, ring.header.dropped);
+    }
+
+    *RING.lock() = Some(ring);
+}
@@ -2,18 +2,29 @@
 
 use core::mem::size_of;
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
 use alloc::vec::Vec;
-use crate::net::{NetDevice, Packet, Udp, Ipv4Addr};
+use alloc::sync::Arc;
+use crate::net::{NetDevice, UdpSocket, Packet, Udp, Ipv4Addr};
+use crate::timer;
+use crate::tunables::Tunable;
 
 /// The magic DHCP cookie
 const DHCP_COOKIE: u32 = 0x63825363;
 
+/// Time to wait for a reply from the DHCP server before retransmitting, in
+/// microseconds
+pub static RETRANSMIT_TIMEOUT: Tunable =
+    Tunable::new("dhcp_retransmit_timeout", 1_000_000);
+
 #[derive(Debug)]
 pub struct Lease {
     pub client_ip:    Ipv4Addr,
     pub server_ip:    Ipv4Addr,
     pub broadcast_ip: Option<Ipv4Addr>,
     pub subnet_mask:  Option<Ipv4Addr>,
+    pub router:       Option<Ipv4Addr>,
+    pub mtu:          Option<u16>,
 }
 
 /// DHCP protocol header
@@ -81,6 +92,8 @@ impl From<u8> for MessageType {
 #[derive(Debug, PartialEq, Eq)]
 enum DhcpOption<'a> {
     SubnetMask(u32),
+    Router(u32),
+    InterfaceMtu(u16),
     BroadcastIp(u32),
     RequestedIp(u32),
     LeaseTime(u32),
@@ -96,6 +109,8 @@ enum DhcpOption<'a> {
 #[repr(u8)]
 enum DhcpOptionId {
     SubnetMask           =   1,
+    Router               =   3,
+    InterfaceMtu         =  26,
     BroadcastIp          =  28,
     RequestedIp          =  50,
     LeaseTime            =  51,
@@ -121,6 +136,16 @@ impl<'a> DhcpOption<'a> {
                 DhcpOption::SubnetMask(
                     u32::from_be_bytes(payload.try_into().ok()?))
             }
+            3 => {
+                // The routers are listed in order of preference, we only
+                // care about the first one
+                DhcpOption::Router(
+                    u32::from_be_bytes(payload.get(..4)?.try_into().ok()?))
+            }
+            26 => {
+                DhcpOption::InterfaceMtu(
+                    u16::from_be_bytes(payload.try_into().ok()?))
+            }
             28 => {
                 DhcpOption::BroadcastIp(
                     u32::from_be_bytes(payload.try_into().ok()?))
@@ -170,6 +195,16 @@ impl<'a> DhcpOption<'a> {
                 buffer.push(4);
                 buffer.extend_from_slice(&mask.to_be_bytes())
             }
+            DhcpOption::Router(addr) => {
+                buffer.push(DhcpOptionId::Router as u8);
+                buffer.push(4);
+                buffer.extend_from_slice(&addr.to_be_bytes())
+            }
+            DhcpOption::InterfaceMtu(mtu) => {
+                buffer.push(DhcpOptionId::InterfaceMtu as u8);
+                buffer.push(2);
+                buffer.extend_from_slice(&mtu.to_be_bytes())
+            }
             DhcpOption::BroadcastIp(addr) => {
                 buffer.push(DhcpOptionId::BroadcastIp as u8);
                 buffer.push(4);
@@ -300,6 +335,41 @@ fn create_dhcp_packet(packet: &mut Packet, xid: u32,
     }
 }
 
+/// Send a DHCP packet with `options` and wait for a reply which `func`
+/// accepts. If no accepted reply shows up within `RETRANSMIT_TIMEOUT`, the
+/// packet is sent again.
+fn exchange<F>(device: &NetDevice, bind: &UdpSocket, xid: u32, mac: [u8; 6],
+               options: &[u8], mut func: F)
+        where F: FnMut(&Packet, Udp) -> Option<()> {
+    // Build the DHCP packet once, every retransmit sends the same buffer
+    let mut packet = device.allocate_packet();
+    create_dhcp_packet(&mut packet, xid, mac, options);
+
+    loop {
+        // Send the DHCP packet
+        device.send(packet.clone());
+
+        // Set a deadline after which we retransmit
+        let timed_out = Arc::new(AtomicBool::new(false));
+        {
+            let timed_out = timed_out.clone();
+            timer::set_deadline(RETRANSMIT_TIMEOUT.get(), move || {
+                timed_out.store(true, Ordering::SeqCst);
+            });
+        }
+
+        // Wait for a reply. We're typically running with interrupts disabled
+        // during device probing, thus dispatch expired deadlines ourselves.
+        while !timed_out.load(Ordering::SeqCst) {
+            if bind.recv(|pkt, udp| func(pkt, udp)).is_some() {
+                return;
+            }
+
+            timer::run_expired();
+        }
+    }
+}
+
 pub fn get_lease(device: &NetDevice) -> Option<Lease> {
     // Get a "unique" transaction ID
     let xid = cpu::rdtsc() as u32;
@@ -308,7 +378,7 @@ pub fn get_lease(device: &NetDevice) -> Option<Lease> {
     let mac = device.mac();
 
     // Bind to UDP port 68
-    let bind = device.bind_udp(68)
+    let bind = UdpSocket::bind(device, 68)
         .expect("Could not bind to port 68 for dhcp");
 
     // Construct the DHCP options for the discover
@@ -320,17 +390,12 @@ pub fn get_lease(device: &NetDevice) -> Option<Lease> {
     ]).serialize(&mut options);
     DhcpOption::End.serialize(&mut options);
     
-    // Send the DHCP discover
-    let mut packet = device.allocate_packet();
-    create_dhcp_packet(&mut packet, xid, mac, &options);
-    device.send(packet);
-
     // Things we hope to maybe find in a DHCP offer
     let mut offer_ip:  Option<Ipv4Addr> = None;
     let mut server_ip: Option<Ipv4Addr> = None;
 
-    // Wait for the DHCP offer
-    while bind.recv(|_pkt, udp| {
+    // Send the DHCP discover and wait for the DHCP offer
+    exchange(device, &bind, xid, mac, &options, |_pkt, udp| {
         // Check that the destination is us
         if udp.ip.eth.dst_mac != mac { return None; }
 
@@ -352,7 +417,7 @@ pub fn get_lease(device: &NetDevice) -> Option<Lease> {
         });
 
         Some(())
-    }).is_none() {}
+    });
 
     // Attempt to get the offer IP and server IP
     let offer_ip  = offer_ip?;
@@ -364,23 +429,22 @@ pub fn get_lease(device: &NetDevice) -> Option<Lease> {
     DhcpOption::RequestedIp(offer_ip.into()).serialize(&mut options);
     DhcpOption::ServerIp(server_ip.into()).serialize(&mut options);
     DhcpOption::ParameterRequestList(&[
-        DhcpOptionId::MessageType as u8,
-        DhcpOptionId::BroadcastIp as u8,
-        DhcpOptionId::SubnetMask  as u8,
+        DhcpOptionId::MessageType  as u8,
+        DhcpOptionId::BroadcastIp  as u8,
+        DhcpOptionId::SubnetMask   as u8,
+        DhcpOptionId::Router       as u8,
+        DhcpOptionId::InterfaceMtu as u8,
     ]).serialize(&mut options);
     DhcpOption::End.serialize(&mut options);
     
-    // Send the DHCP request
-    let mut packet = device.allocate_packet();
-    create_dhcp_packet(&mut packet, xid, mac, &options);
-    device.send(packet);
-
     // Things we hope to get from the DHCP ACK
     let mut broadcast_ip = None;
     let mut subnet_mask  = None;
+    let mut router       = None;
+    let mut mtu          = None;
     
-    // Wait for the DHCP ACK
-    while bind.recv(|_pkt, udp| {
+    // Send the DHCP request and wait for the DHCP ACK
+    exchange(device, &bind, xid, mac, &options, |_pkt, udp| {
         // Check that the destination is us
         if udp.ip.eth.dst_mac != mac { return None; }
 
@@ -404,15 +468,31 @@ pub fn get_lease(device: &NetDevice) -> Option<Lease> {
                 Some((*ip).into())
             } else { None }
         });
+        
+        // Save the default gateway if it was present
+        router = options.iter().find_map(|x| {
+            if let DhcpOption::Router(ip) = x {
+                Some((*ip).into())
+            } else { None }
+        });
+        
+        // Save the MTU if it was present
+        mtu = options.iter().find_map(|x| {
+            if let DhcpOption::InterfaceMtu(mtu) = x {
+                Some(*mtu)
+            } else { None }
+        });
 
         Some(())
-    }).is_none() {}
+    });
 
     Some(Lease {
         client_ip: offer_ip,
         server_ip,
         broadcast_ip,
-        subnet_mask
+        subnet_mask,
+        router,
+        mtu,
     })
 }
 
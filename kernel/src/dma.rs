@@ -0,0 +1,74 @@
+//! Memory which devices can DMA to
+//!
+//! `DmaBuffer`s are physically contiguous. If the IOMMU translates DMA they
+//! are mapped into its DMA domain, which holds the only memory devices can
+//! access. Without the `iommu` feature, or without usable remapping
+//! hardware, devices use the physical address of the buffer.
+
+use core::ops::{Deref, DerefMut};
+
+use crate::mm::PhysContig;
+
+/// Physically contiguous memory which is accessible by devices
+pub struct DmaBuffer<T> {
+    /// Backing memory
+    buf: PhysContig<T>,
+
+    /// Address of the buffer for devices to use
+    dma_addr: u64,
+
+    /// Size of the mapping in the DMA domain, or zero if not mapped
+    mapped: u64,
+}
+
+impl<T> DmaBuffer<T> {
+    /// Allocate a new DMA buffer and move `val` into it
+    pub fn new(val: T) -> DmaBuffer<T> {
+        let buf = PhysContig::new(val);
+
+        // Map the buffer into the DMA domain if DMA is translated
+        #[cfg(feature = "iommu")]
+        {
+            // Compute the size of the allocation, in pages
+            let size = (core::cmp::max(4096, core::mem::size_of::<T>())
+                as u64 + 0xfff) & !0xfff;
+
+            if let Some(dma_addr) = crate::iommu::map(buf.phys_addr(), size) {
+                return DmaBuffer { buf, dma_addr, mapped: size };
+            }
+        }
+
+        // DMA is not translated, devices use physical addresses
+        let dma_addr = buf.phys_addr().0;
+        DmaBuffer { buf, dma_addr, mapped: 0 }
+    }
+
+    /// Get the address devices should use to access this buffer
+    pub fn dma_addr(&self) -> u64 {
+        self.dma_addr
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        if self.mapped == 0 { return; }
+
+        // Revoke device access to the buffer prior to freeing it
+        #[cfg(feature = "iommu")]
+        crate::iommu::unmap(self.dma_addr, self.mapped);
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
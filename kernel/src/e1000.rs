@@ -1,16 +1,16 @@
 //! Intel 1gbit network card driver
 
-use core::mem::size_of;
 use core::ptr::{read_volatile, write_volatile};
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 
-use page_table::{PAGE_NX, PAGE_CACHE_DISABLE};
-use page_table::{PhysAddr, VirtAddr, PageType, PAGE_PRESENT, PAGE_WRITE};
+use page_table::PhysAddr;
 
-use crate::mm::{alloc_virt_addr_4k, PhysContig};
-use crate::net::{NetDriver, NetDevice, Packet, PacketLease};
-use crate::pci::{Device, PciDevice, BarType};
+use crate::dma::DmaBuffer;
+use crate::mmio::{Mmio, Register};
+use crate::net::{NetDriver, NetDevice, Packet, PacketLease, LinkState};
+use crate::net::{Capabilities, ETHERNET_MTU, MAX_MTU, PACKET_BUFFER_SIZE};
+use crate::pci::{Device, Driver, PciDevice, BarType};
 
 /// Number of receive descriptors to allocate per device (max is 256)
 const NUM_RX_DESCS: usize = 8;
@@ -29,130 +29,125 @@ struct NicRegisters {
     queue_enable: bool,
 
     /// Device control register
-    ctrl: usize,
+    ctrl: Register<u32>,
+
+    /// Device status register
+    status: Register<u32>,
 
     /// Interrupt mask clear
-    imc: usize,
+    imc: Register<u32>,
 
     /// Receive descriptor base low
-    rdbal: usize,
+    rdbal: Register<u32>,
     
     /// Receive descriptor base high
-    rdbah: usize,
+    rdbah: Register<u32>,
     
     /// Receive descriptor length
-    rdlen: usize,
+    rdlen: Register<u32>,
     
     /// Receive descriptor head
-    rdh: usize,
+    rdh: Register<u32>,
     
     /// Receive descriptor tail
-    rdt: usize,
+    rdt: Register<u32>,
     
     /// Transmit descriptor base low
-    tdbal: usize,
+    tdbal: Register<u32>,
     
     /// Transmit descriptor base high
-    tdbah: usize,
+    tdbah: Register<u32>,
     
     /// Transmit descriptor length
-    tdlen: usize,
+    tdlen: Register<u32>,
     
     /// Transmit descriptor head
-    tdh: usize,
+    tdh: Register<u32>,
     
     /// Transmit descriptor tail
-    tdt: usize,
+    tdt: Register<u32>,
 
     /// Receive address low for the 0th entry in the table
-    ral0: usize,
+    ral0: Register<u32>,
     
     /// Receive address high for the 0th entry in the table
-    rah0: usize,
+    rah0: Register<u32>,
 
     /// Receive control
-    rctl: usize,
+    rctl: Register<u32>,
 
     /// Transmit control
-    tctl: usize,
+    tctl: Register<u32>,
 
     /// Receive descriptor control
-    rxdctl: usize,
+    rxdctl: Register<u32>,
 
     /// Transmit descriptor control
-    txdctl: usize,
+    txdctl: Register<u32>,
 }
 
+/// I210 Gigabit Network Connection, which needs its RX and TX queues enabled
+/// explicitly
+const I210: (u16, u16) = (0x8086, 0x1533);
+
+/// Driver registration for the PCI subsystem
+pub const DRIVER: Driver = Driver {
+    name: "e1000",
+    ids: &[
+        // 82540EM Gigabit Ethernet Controller "e1000"
+        (0x8086, 0x100e),
+
+        // 82574L Gigabit Network Connection "e1000e"
+        (0x8086, 0x10d3),
+
+        I210,
+    ],
+    probe,
+};
+
 /// Checks to see if the PCI device being probed is a device that we can handle
 /// with our driver
-pub fn probe(device: &PciDevice) -> Option<Box<dyn Device>> {
-    const E1000_REGS: NicRegisters = NicRegisters {
+fn probe(device: &PciDevice) -> Option<Box<dyn Device>> {
+    /// Registers of the devices we support, which all share the same layout
+    const REGS: NicRegisters = NicRegisters {
         queue_enable: false,
-        ctrl:   0x0000,
-        imc:    0x00d8,
-        rdbal:  0x2800,
-        rdbah:  0x2804,
-        rdlen:  0x2808,
-        rdh:    0x2810,
-        rdt:    0x2818,
-        tdbal:  0x3800,
-        tdbah:  0x3804,
-        tdlen:  0x3808,
-        tdh:    0x3810,
-        tdt:    0x3818,
-        ral0:   0x5400,
-        rah0:   0x5404,
-        rctl:   0x0100,
-        tctl:   0x0400,
-        rxdctl: 0x2828,
-        txdctl: 0x3828,
+        ctrl:   Register::new(0x0000),
+        status: Register::new(0x0008),
+        imc:    Register::new(0x00d8),
+        rdbal:  Register::new(0x2800),
+        rdbah:  Register::new(0x2804),
+        rdlen:  Register::new(0x2808),
+        rdh:    Register::new(0x2810),
+        rdt:    Register::new(0x2818),
+        tdbal:  Register::new(0x3800),
+        tdbah:  Register::new(0x3804),
+        tdlen:  Register::new(0x3808),
+        tdh:    Register::new(0x3810),
+        tdt:    Register::new(0x3818),
+        ral0:   Register::new(0x5400),
+        rah0:   Register::new(0x5404),
+        rctl:   Register::new(0x0100),
+        tctl:   Register::new(0x0400),
+        rxdctl: Register::new(0x2828),
+        txdctl: Register::new(0x3828),
     };
-    
-    /// The different (vendor, device IDs) we support
-    const HANDLED_DEVICES: &[(u16, u16, NicRegisters)] = &[
-        // 82540EM Gigabit Ethernet Controller "e1000"
-        (0x8086, 0x100e, E1000_REGS),
-
-        // 82574L Gigabit Network Connection "e1000e"
-        (0x8086, 0x10d3, E1000_REGS),
-
-        // I210 Gigabit Network Connection
-        (0x8086, 0x1533, NicRegisters {
-            queue_enable: true,
-            ctrl:   0x0000,
-            imc:    0x00d8,
-            rdbal:  0x2800,
-            rdbah:  0x2804,
-            rdlen:  0x2808,
-            rdh:    0x2810,
-            rdt:    0x2818,
-            tdbal:  0x3800,
-            tdbah:  0x3804,
-            tdlen:  0x3808,
-            tdh:    0x3810,
-            tdt:    0x3818,
-            ral0:   0x5400,
-            rah0:   0x5404,
-            rctl:   0x0100,
-            tctl:   0x0400,
-            rxdctl: 0x2828,
-            txdctl: 0x3828,
-        }),
-    ];
 
     // Check if we can handle this device
-    for &(vid, did, regs) in HANDLED_DEVICES {
-        // Check if the VID:DID match what we support
-        if device.header.vendor_id == vid && device.header.device_id == did {
-            // Create the new device
-            return Some(Box::new(
-                NetDevice::new(Box::new(IntelGbit::new(*device, regs)))
-            ));
-        }
+    let id = (device.header.vendor_id, device.header.device_id);
+    if !DRIVER.ids.contains(&id) {
+        return None;
     }
 
-    // Device is not handled
-    None
+    let regs = NicRegisters {
+        queue_enable: id == I210,
+        ..REGS
+    };
+
+    // Create the new device, it's put on the network once registered
+    let device = Box::new(
+        NetDevice::new(Box::new(IntelGbit::new(*device, regs))));
+
+    Some(device)
 }
 
 /// Legacy receive descriptor
@@ -187,10 +182,10 @@ struct IntelGbit {
 
     /// Memory mapped I/O for this device
     /// These devices map 128 KiB of memory
-    mmio: &'static mut [u32; 32 * 1024],
+    mmio: Mmio,
 
     /// Virtually mapped RX descriptors
-    rx_descriptors: PhysContig<[LegacyRxDesc; NUM_RX_DESCS]>,
+    rx_descriptors: DmaBuffer<[LegacyRxDesc; NUM_RX_DESCS]>,
 
     /// Receive buffers corresponding to their descriptors
     rx_buffers: Vec<Packet>,
@@ -200,16 +195,17 @@ struct IntelGbit {
     rx_head: usize,
     
     /// Virtually mapped TX descriptors
-    tx_descriptors: PhysContig<[LegacyTxDesc; NUM_TX_DESCS]>,
+    tx_descriptors: DmaBuffer<[LegacyTxDesc; NUM_TX_DESCS]>,
 
     /// Current index of the next free transmit buffer slot
     tx_head: usize,
 
-    /// Free list of packets
-    packets: Vec<Packet>,
-
     /// Mac address of this device
     mac: [u8; 6],
+
+    /// MTU of the device, frames larger than a standard ethernet frame are
+    /// only received if this is larger than `ETHERNET_MTU`
+    mtu: usize,
 }
 
 impl<'a> IntelGbit {
@@ -238,40 +234,8 @@ impl<'a> IntelGbit {
         // is 4 KiB aligned
         assert!((bar.0 & 0xfff) == 0, "Non-4 KiB aligned Intel gbit nic?!");
 
-        // Map in the physical MMIO space into uncacheable virtual memory
-        let mmio = {
-            // Get a virtual address capable of holding a 128 KiB mapping
-            let vaddr = alloc_virt_addr_4k(128 * 1024);
-            
-            // Get access to physical memory allocations
-            let mut pmem = crate::mm::PhysicalMemory;
-
-            // Get access to the current page table
-            let mut page_table = core!().boot_args.page_table.lock();
-            let page_table = page_table.as_mut().unwrap();
-
-            // Map in the 128 KiB of MMIO space into virtual memory based on
-            // the `vaddr` we allocated above
-            for paddr in (bar.0..bar.0.checked_add(128 * 1024).unwrap())
-                    .step_by(4096) {
-                // Compute the offset into MMIO space
-                let offset = paddr - bar.0;
-
-                unsafe {
-                    page_table.map_raw(&mut pmem, VirtAddr(vaddr.0 + offset),
-                                       PageType::Page4K,
-                                       paddr | PAGE_NX | PAGE_WRITE | 
-                                       PAGE_CACHE_DISABLE | PAGE_PRESENT)
-                        .expect("Failed to map in Intel gbit MMIO to \
-                                 virtual memory");
-                }
-            }
-
-            // Box up the MMIO space
-            unsafe {
-                &mut *(vaddr.0 as *mut [u32; 32 * 1024])
-            }
-        };
+        // Map in the 128 KiB of MMIO space into uncacheable virtual memory
+        let mmio = Mmio::map("e1000", bar, 128 * 1024);
 
         // Make sure that the descriptor tables fit on a single page. They're
         // 16-byte entries thus we make sure that we never use more than 256
@@ -285,9 +249,13 @@ impl<'a> IntelGbit {
                 NUM_TX_DESCS > 0,
             "Invalid Intel gbit constant configuration");
 
+        // The NIC is configured to DMA up to 16 KiB into each RX buffer
+        assert!(PACKET_BUFFER_SIZE == 16 * 1024,
+            "Intel gbit RX buffer size does not match packet buffers");
+
         // Create the RX descriptors
         let mut rx_descriptors =
-            PhysContig::new([LegacyRxDesc::default(); NUM_RX_DESCS]);
+            DmaBuffer::new([LegacyRxDesc::default(); NUM_RX_DESCS]);
 
         // Create the RX buffers
         let mut rx_buffers = Vec::new();
@@ -296,7 +264,7 @@ impl<'a> IntelGbit {
             let rx_buf = Packet::new();
 
             // Store the packet buffer in the descriptor table
-            rx_descriptors[ii].buffer = rx_buf.phys_addr().0;
+            rx_descriptors[ii].buffer = rx_buf.dma_addr();
 
             // Save the reference to the buffer
             rx_buffers.push(rx_buf);
@@ -304,7 +272,7 @@ impl<'a> IntelGbit {
         
         // Create the TX descriptors
         let tx_descriptors =
-            PhysContig::new([LegacyTxDesc::default(); NUM_TX_DESCS]);
+            DmaBuffer::new([LegacyTxDesc::default(); NUM_TX_DESCS]);
         
         // Create the NIC
         let mut nic = IntelGbit {
@@ -315,110 +283,177 @@ impl<'a> IntelGbit {
             rx_head: 0,
             tx_descriptors,
             tx_head: 0,
-            packets: Vec::with_capacity(128),
             mac: [0u8; 6],
+            mtu: ETHERNET_MTU,
         };
 
         unsafe {
-            // Reset the NIC
-            nic.write(nic.regs.ctrl, nic.read(nic.regs.ctrl) | (1 << 26));
+            // Bring up the datapath
+            nic.reset();
+
+            // Read the receive address high and low for the first entry
+            // in the RX MAC filter. We assume this holds the MAC address of
+            // this NIC
+            let ral = nic.read(nic.regs.ral0);
+            let rah = nic.read(nic.regs.rah0);
+            let mut mac = [0u8; 6];
+            assert!((rah & (1 << 31)) != 0, "Whoa, couldn't get MAC");
+            mac[0..4].copy_from_slice(&ral.to_le_bytes());
+            mac[4..6].copy_from_slice(&(rah as u16).to_le_bytes());
+            nic.mac = mac;
+        }
+
+        nic
+    }
+
+    /// Get the receive control value for the current configuration
+    fn rctl(&self) -> u32 {
+        // Strip ethernet CRC, 16 KiB RX buffers (matching our packet
+        // buffers), accept broadcast packets, and enable RX
+        let mut rctl = (1 << 26) | (1 << 25) | (1 << 16) | (1 << 15) | (1 << 1);
+
+        // Accept long packets if we're using jumbo frames
+        if self.mtu > ETHERNET_MTU {
+            rctl |= 1 << 5;
+        }
+
+        rctl
+    }
+
+    /// Read from the MMIO Intel register `reg`
+    unsafe fn read(&self, reg: Register<u32>) -> u32 {
+        self.mmio.read(reg)
+    }
+
+    /// Write `val` to the MMIO Intel register `reg`
+    unsafe fn write(&mut self, reg: Register<u32>, val: u32) {
+        self.mmio.write(reg, val);
+    }
+}
+
+impl NetDriver for IntelGbit {
+    fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn link_state(&self) -> LinkState {
+        let status = unsafe { self.read(self.regs.status) };
+
+        // Check the link up bit
+        if (status & (1 << 1)) == 0 {
+            return LinkState::Down;
+        }
 
-            // Wait for the reset to clear
-            while (nic.read(nic.regs.ctrl) & (1 << 26)) != 0 {}
+        // Decode the speed bits
+        match (status >> 6) & 3 {
+            0 => LinkState::Up(10),
+            1 => LinkState::Up(100),
+            _ => LinkState::Up(1000),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            queues:  1,
+            max_mtu: MAX_MTU,
+            ..Default::default()
+        }
+    }
+
+    fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+        unsafe { self.write(self.regs.rctl, self.rctl()); }
+    }
 
-            // Write all `f`s to the IMC to disable all interrupts
-            nic.write(nic.regs.imc, !0);
+    fn reset(&mut self) {
+        unsafe {
+            // Reset the NIC, stopping all DMA and interrupts
+            self.purge();
             
-            if nic.regs.queue_enable {
+            // Now that the NIC is no longer using them, put all RX buffers back
+            // up for use and clear out any transmits which were in flight
+            for ii in 0..self.rx_descriptors.len() {
+                self.rx_descriptors[ii] = LegacyRxDesc {
+                    buffer: self.rx_buffers[ii].dma_addr(),
+                    ..Default::default()
+                };
+            }
+
+            for desc in self.tx_descriptors.iter_mut() {
+                *desc = LegacyTxDesc::default();
+            }
+
+            // Start the queues over at the beginning
+            self.rx_head = 0;
+            self.tx_head = 0;
+
+            if self.regs.queue_enable {
                 // Enable RX and TX queues if the NIC requires this enablement
-                nic.write(nic.regs.rxdctl,
-                          (1 << 25) | nic.read(nic.regs.rxdctl));
-                nic.write(nic.regs.txdctl,
-                          (1 << 25) | nic.read(nic.regs.txdctl));
+                self.write(self.regs.rxdctl,
+                           (1 << 25) | self.read(self.regs.rxdctl));
+                self.write(self.regs.txdctl,
+                           (1 << 25) | self.read(self.regs.txdctl));
             }
 
             // Initialize the NIC for receive
             {
                 // Program the receive descriptor base
-                nic.write(nic.regs.rdbah,
-                    (nic.rx_descriptors.phys_addr().0 >> 32) as u32); // high
-                nic.write(nic.regs.rdbal,
-                    (nic.rx_descriptors.phys_addr().0 >>  0) as u32); // low
+                self.write(self.regs.rdbah,
+                    (self.rx_descriptors.dma_addr() >> 32) as u32); // high
+                self.write(self.regs.rdbal,
+                    (self.rx_descriptors.dma_addr() >>  0) as u32); // low
 
                 // Write in the size of the RX descriptor queue
                 let queue_size = core::mem::size_of_val(
-                    &nic.rx_descriptors[..]);
-                nic.write(nic.regs.rdlen, queue_size as u32);
+                    &self.rx_descriptors[..]);
+                self.write(self.regs.rdlen, queue_size as u32);
 
                 // Set the RX head
-                nic.write(nic.regs.rdh, 0);
+                self.write(self.regs.rdh, 0);
 
                 // Set the RX tail
-                nic.write(nic.regs.rdt, nic.rx_descriptors.len() as u32 - 1);
+                self.write(self.regs.rdt, self.rx_descriptors.len() as u32 - 1);
             }
 
             // Initialize the NIC for transmit
             {
                 // Program the transmit descriptor base
-                nic.write(nic.regs.tdbah,
-                    (nic.tx_descriptors.phys_addr().0 >> 32) as u32); // high
-                nic.write(nic.regs.tdbal,
-                    (nic.tx_descriptors.phys_addr().0 >>  0) as u32); // low
+                self.write(self.regs.tdbah,
+                    (self.tx_descriptors.dma_addr() >> 32) as u32); // high
+                self.write(self.regs.tdbal,
+                    (self.tx_descriptors.dma_addr() >>  0) as u32); // low
 
                 // Write in the size of the TX descriptor queue
                 let queue_size = core::mem::size_of_val(
-                    &nic.tx_descriptors[..]);
-                nic.write(nic.regs.tdlen, queue_size as u32);
-            
+                    &self.tx_descriptors[..]);
+                self.write(self.regs.tdlen, queue_size as u32);
+        
                 // Set the TX head
-                nic.write(nic.regs.tdh, 0);
+                self.write(self.regs.tdh, 0);
 
                 // Set the TX tail
-                nic.write(nic.regs.tdt, 0);
+                self.write(self.regs.tdt, 0);
             }
 
-            // Read the receive address high and low for the first entry
-            // in the RX MAC filter. We assume this holds the MAC address of
-            // this NIC
-            let ral = nic.read(nic.regs.ral0);
-            let rah = nic.read(nic.regs.rah0);
-            let mut mac = [0u8; 6];
-            assert!((rah & (1 << 31)) != 0, "Whoa, couldn't get MAC");
-            mac[0..4].copy_from_slice(&ral.to_le_bytes());
-            mac[4..6].copy_from_slice(&(rah as u16).to_le_bytes());
-            nic.mac = mac;
-
-            // Strip ethernet CRC, 2 KiB RX buffers,
-            // and accept broadcast packets, and enable RX
-            nic.write(nic.regs.rctl, (1 << 26) | (1 << 15) | (1 << 1));
-            
+            // Configure and enable RX
+            self.write(self.regs.rctl, self.rctl());
+        
             // Enable TX
-            nic.write(nic.regs.tctl, 1 << 1);
+            self.write(self.regs.tctl, 1 << 1);
         }
-
-        nic
     }
+    
+    unsafe fn purge(&mut self) {
+        // Reset the NIC
+        self.write(self.regs.ctrl, self.read(self.regs.ctrl) | (1 << 26));
 
-    /// Read from the MMIO Intel register at `reg_offset`. This is the offset
-    /// into MMIO space in bytes, not the register ID
-    unsafe fn read(&self, reg_offset: usize) -> u32 {
-        let reg = reg_offset / size_of::<u32>();
-        core::ptr::read_volatile(&self.mmio[reg])
-    }
+        // Wait for the reset to clear
+        while (self.read(self.regs.ctrl) & (1 << 26)) != 0 {}
 
-    /// Write `val` to the MMIO Intel register at `reg_offset`. This is the
-    /// offset into MMIO space in bytes, not the register ID
-    unsafe fn write(&mut self, reg_offset: usize, val: u32) {
-        let reg = reg_offset / size_of::<u32>();
-        core::ptr::write_volatile(&mut self.mmio[reg], val);
+        // Write all `f`s to the IMC to disable all interrupts
+        self.write(self.regs.imc, !0);
     }
-}
 
-impl NetDriver for IntelGbit {
-    fn mac(&self) -> [u8; 6] {
-        self.mac
-    }
-    
     fn recv<'a, 'b: 'a>(&'b mut self) -> Option<PacketLease<'a>> {
         unsafe {
             // Check if there is a packet that is ready to read
@@ -437,8 +472,8 @@ impl NetDriver for IntelGbit {
             // Allocate a new packet for this descriptor
             let mut packet = self.allocate_packet();
 
-            // Get the physical address of the new packet
-            let new_packet_phys = packet.phys_addr();
+            // Get the DMA address of the new packet
+            let new_packet_dma = packet.dma_addr();
 
             // Swap in the new packet in place of the old packet in the
             // buffer list
@@ -448,7 +483,7 @@ impl NetDriver for IntelGbit {
             // Clear the status to put this descriptor back up for use
             write_volatile(&mut self.rx_descriptors[self.rx_head],
                LegacyRxDesc {
-                   buffer: new_packet_phys.0,
+                   buffer: new_packet_dma,
                    ..Default::default()
                });
             
@@ -474,7 +509,7 @@ impl NetDriver for IntelGbit {
             // Fill in the TX descriptor
             write_volatile(&mut self.tx_descriptors[self.tx_head],
                 LegacyTxDesc {
-                    buffer: packet.phys_addr().0,
+                    buffer: packet.dma_addr(),
                     cmd:    (1 << 3) | (1 << 1) | (1 << 0),
                     len:    packet.raw().len() as u16,
                     ..Default::default()
@@ -490,23 +525,45 @@ impl NetDriver for IntelGbit {
             // Bump the TX head as we've used this slot
             self.tx_head = tail;
 
-            // Put the packet onto our free list
+            // Give the packet back
             self.release_packet(packet);
         }
     }
 
-    fn allocate_packet(&mut self) -> Packet {
-        self.packets.pop().unwrap_or(Packet::new())
-    }
+    fn send_batch(&mut self, packets: &mut Vec<Packet>) {
+        while !packets.is_empty() {
+            // Fill as many descriptors as we can. One is left unused as a
+            // tail equal to the head means the ring is empty.
+            let count = core::cmp::min(packets.len(),
+                                       self.tx_descriptors.len() - 1);
+            for packet in &packets[..count] {
+                unsafe {
+                    write_volatile(&mut self.tx_descriptors[self.tx_head],
+                        LegacyTxDesc {
+                            buffer: packet.dma_addr(),
+                            cmd:    (1 << 3) | (1 << 1) | (1 << 0),
+                            len:    packet.raw().len() as u16,
+                            ..Default::default()
+                        });
+                }
 
-    fn release_packet(&mut self, packet: Packet) {
-        // If we have room in our free list, push the packet into it.
-        // Otherwise, we'll just free the packet entirely, putting it back up
-        // for use for the whole system
-        if self.packets.len() < self.packets.capacity() {
-            // Put the packet back into the free list
-            self.packets.push(packet);
+                self.tx_head = (self.tx_head + 1) % self.tx_descriptors.len();
+            }
+
+            unsafe {
+                // Bump the tail pointer on the NIC once for the whole batch
+                self.write(self.regs.tdt, self.tx_head as u32);
+
+                // Descriptors complete in order, thus wait for the NIC to
+                // transmit the last packet of the batch
+                let last = (self.tx_head + self.tx_descriptors.len() - 1) %
+                    self.tx_descriptors.len();
+                while (read_volatile(
+                    &self.tx_descriptors[last].status) & 1) == 0 {}
+            }
+
+            // Give the packets back to the pool
+            packets.drain(..count);
         }
     }
 }
-
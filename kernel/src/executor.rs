@@ -0,0 +1,195 @@
+//! Per-core cooperative task executor
+//!
+//! Background chores are registered as tasks on a core along with how often
+//! they should run. Tasks are run from the core's idle loop, outside of
+//! interrupt context, and the APIC timer tick wakes the core to check which
+//! tasks are due. Tasks are never preempted by each other, thus every run of
+//! a task must do a bounded amount of work and return.
+//!
+//! Messages sent to the core over the message bus are handled between runs of
+//! tasks.
+//!
+//! Cores can be parked at runtime, which stops them from running tasks and
+//! leaves them halted until they are unparked. Any registered lock a core
+//! still holds when it parks was leaked, and is released through the lock
+//! registry such that other cores don't wait on the parked core.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::boxed::Box;
+
+use crate::bus;
+use crate::{time, timer};
+use crate::acpi::{self, ApicState, MAX_CORES};
+
+/// Requests for cores to be parked, indexed by core ID
+static PARK_REQUESTS: [AtomicBool; MAX_CORES] =
+    [AtomicBool::new(false); MAX_CORES];
+
+/// Result of running a task once
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskStatus {
+    /// The task wants to be run again after its interval
+    Continue,
+
+    /// The task is finished and should be removed from the executor
+    Done,
+}
+
+/// A unit of background work which is run periodically by the executor
+pub trait Task: Send {
+    /// Run the task once
+    fn run(&mut self) -> TaskStatus;
+}
+
+/// Any closure can be used as a task
+impl<F: FnMut() -> TaskStatus + Send> Task for F {
+    fn run(&mut self) -> TaskStatus {
+        self()
+    }
+}
+
+/// A task registered with the executor of a core
+pub struct ScheduledTask {
+    /// Name of the task, for debugging
+    name: &'static str,
+
+    /// Interval between runs of the task in microseconds
+    interval: u64,
+
+    /// TSC value at which the task is next due to run
+    next_run: u64,
+
+    /// The task itself
+    task: Box<dyn Task>,
+}
+
+/// Register `task` named `name` on the current core. The task is first run
+/// on the next tick, and then once every `interval` microseconds until it
+/// returns `TaskStatus::Done`.
+pub fn spawn<T>(name: &'static str, interval: u64, task: T)
+        where T: Task + 'static {
+    core!().tasks.lock().push(ScheduledTask {
+        name,
+        interval,
+        next_run: 0,
+        task: Box::new(task),
+    });
+}
+
+/// Register a closure `func` named `name` which is invoked on the current core
+/// every `interval` microseconds, forever
+#[allow(dead_code)]
+pub fn spawn_periodic<F>(name: &'static str, interval: u64, mut func: F)
+        where F: FnMut() + Send + 'static {
+    spawn(name, interval, move || {
+        func();
+        TaskStatus::Continue
+    });
+}
+
+/// Run all tasks on the current core which are due, returning the number of
+/// tasks which were run
+pub fn run_ready() -> usize {
+    // Only run tasks which were due when we started, such that a task which
+    // is due again by the time it finishes can't keep us here forever
+    let now = cpu::rdtsc();
+    let mut ran = 0;
+
+    loop {
+        // Take out the first task which is due. The lock must be released
+        // while the task runs such that the task can spawn new tasks.
+        let mut scheduled = {
+            let mut tasks = core!().tasks.lock();
+            match tasks.iter().position(|x| x.next_run <= now) {
+                Some(idx) => tasks.swap_remove(idx),
+                None      => break,
+            }
+        };
+
+        // Run the task and put it back if it wants to run again
+        match scheduled.task.run() {
+            TaskStatus::Continue => {
+                scheduled.next_run = time::future(scheduled.interval);
+                core!().tasks.lock().push(scheduled);
+            }
+            TaskStatus::Done => {
+                print!("[{:16.8}] Task {} finished on core {}\n",
+                       time::uptime(), scheduled.name, core!().id);
+            }
+        }
+
+        ran += 1;
+    }
+
+    ran
+}
+
+/// Prepare the executor of the current core. Kernel statics survive a soft
+/// reboot, thus this drops any park request for this core which was left over
+/// from the previous boot. This must be called before the core checks in, as
+/// other cores may park it from then on.
+pub fn init() {
+    PARK_REQUESTS[core!().id as usize].store(false, Ordering::SeqCst);
+}
+
+/// Run the executor on the current core forever. Expired deadlines are
+/// invoked before the tasks which are due. When no tasks are due the core
+/// halts until the next interrupt, which at the latest is the next APIC timer
+/// tick.
+pub fn run() -> ! {
+    loop {
+        if PARK_REQUESTS[core!().id as usize].load(Ordering::SeqCst) {
+            park_current();
+        }
+
+        timer::run_expired();
+        bus::dispatch();
+        run_ready();
+        cpu::wait_for_interrupt();
+    }
+}
+
+/// Request that the core with ID `core_id` parks. The core finishes the task
+/// it is running, if any, and then halts without running any more tasks until
+/// it is unparked.
+pub fn park(core_id: u32) {
+    assert!(core_id < acpi::num_cores(), "Attempted to park invalid core");
+    PARK_REQUESTS[core_id as usize].store(true, Ordering::SeqCst);
+}
+
+/// Unpark the core with ID `core_id`. The core resumes running tasks on its
+/// next APIC timer tick.
+pub fn unpark(core_id: u32) {
+    assert!(core_id < acpi::num_cores(), "Attempted to unpark invalid core");
+    PARK_REQUESTS[core_id as usize].store(false, Ordering::SeqCst);
+}
+
+/// Park the current core until it is unparked
+fn park_current() {
+    let apic_id = core!().apic_id().unwrap();
+
+    // We're between tasks, thus any lock we still hold was leaked. Release
+    // them such that the rest of the system doesn't wait on us while parked.
+    unsafe {
+        crate::locks::release_held_by(core!().id, |reg| {
+            print!("Core {} released leaked lock {} before parking\n",
+                   core!().id, reg.name);
+        });
+    }
+
+    unsafe { acpi::set_core_state(apic_id, ApicState::Parked); }
+    print!("[{:16.8}] Core {} parked\n", time::uptime(), core!().id);
+
+    // Halt until we're unparked. We're woken up by every APIC timer tick to
+    // check, which keeps the timer and deadlines working as usual. Urgent
+    // messages are still handled while we're parked.
+    while PARK_REQUESTS[core!().id as usize].load(Ordering::SeqCst) {
+        timer::run_expired();
+        bus::dispatch_urgent();
+        cpu::wait_for_interrupt();
+    }
+
+    unsafe { acpi::set_core_state(apic_id, ApicState::Online); }
+    print!("[{:16.8}] Core {} unparked\n", time::uptime(), core!().id);
+}
@@ -0,0 +1,168 @@
+//! Memory protection hardening of the kernel
+//!
+//! After boot the kernel image is re-checked to make sure no page is both
+//! writable and executable, the physical window is made non-executable, and
+//! the CPU protection features (`CR0.WP`, SMEP, and SMAP) are enabled. Unless
+//! the `fault_diagnostics` tunable is zero, a page fault handler is installed
+//! which reports writes to protected memory and execution of non-executable
+//! memory in a readable form, rather than just dumping registers.
+
+use core::sync::atomic::Ordering;
+
+use crate::mm::{self, PhysicalMemory};
+use crate::tunables::Tunable;
+use crate::interrupts::{InterruptFrame, AllRegs};
+use page_table::{VirtAddr, PAGE_WRITE, PAGE_NX};
+use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+
+/// Write protect bit in `cr0`, prevents supervisor writes to read-only pages
+const CR0_WP: u64 = 1 << 16;
+
+/// Supervisor mode execution prevention enable bit in `cr4`
+const CR4_SMEP: u64 = 1 << 20;
+
+/// Supervisor mode access prevention enable bit in `cr4`
+const CR4_SMAP: u64 = 1 << 21;
+
+/// Page fault error code bit indicating the page was present
+const PF_PRESENT: usize = 1 << 0;
+
+/// Page fault error code bit indicating the access was a write
+const PF_WRITE: usize = 1 << 1;
+
+/// Page fault error code bit indicating the access was an instruction fetch
+const PF_FETCH: usize = 1 << 4;
+
+/// Page fault exception vector
+const PAGE_FAULT: u8 = 14;
+
+/// If non-zero, protection violations are diagnosed by a page fault handler
+pub static FAULT_DIAGNOSTICS: Tunable = Tunable::new("fault_diagnostics", 1);
+
+/// Physical memory which must stay executable in the physical window. The
+/// bootloader lives here, and both AP startup and soft reboots execute it
+/// through the physical window.
+const BOOTLOADER_PHYS_END: u64 = 1024 * 1024;
+
+/// Page fault handler which diagnoses protection violations. This never
+/// handles the fault, it just turns known violations into a readable panic.
+unsafe fn page_fault(_number: u8, frame: &mut InterruptFrame, error: usize,
+                     _regs: &mut AllRegs) -> bool {
+    // Get the faulting address
+    let addr = cpu::read_cr2();
+
+    // Only diagnose faults on present pages, these are permission violations
+    if (error & PF_PRESENT) == 0 {
+        return false;
+    }
+
+    // Get the bounds of the kernel image
+    let image_base = core!().boot_args.kernel_image_base
+        .load(Ordering::SeqCst);
+    let image_size = core!().boot_args.kernel_image_size
+        .load(Ordering::SeqCst);
+
+    if (error & PF_WRITE) != 0 &&
+            addr >= image_base && addr - image_base < image_size {
+        panic!("Write to protected kernel memory at {:#x} \
+                (image offset {:#x}) from rip {:#x}",
+               addr, addr - image_base, frame.rip);
+    } else if (error & PF_WRITE) != 0 {
+        panic!("Write to read-only memory at {:#x} from rip {:#x}",
+               addr, frame.rip);
+    } else if (error & PF_FETCH) != 0 {
+        panic!("Execution of non-executable memory at {:#x}", addr);
+    }
+
+    false
+}
+
+/// Enforce W^X on every page of the kernel image, dropping execute
+/// permissions from any page that is writable
+unsafe fn protect_image(pmem: &mut PhysicalMemory,
+                        table: &mut page_table::PageTable) {
+    // Get the bounds of the kernel image
+    let image_base = core!().boot_args.kernel_image_base
+        .load(Ordering::SeqCst);
+    let image_size = core!().boot_args.kernel_image_size
+        .load(Ordering::SeqCst);
+
+    for vaddr in (image_base..image_base + image_size).step_by(4096) {
+        // Get the page table entry mapping this page. The image has holes
+        // between sections, skip those.
+        let entry = match table.translate(pmem, VirtAddr(vaddr))
+                .and_then(|x| x.entry()) {
+            Some(entry) => entry,
+            None        => continue,
+        };
+
+        // Read the entry
+        let ent: u64 = mm::read_phys(entry);
+
+        // If the page is writable and executable, make it non-executable
+        if (ent & PAGE_WRITE) != 0 && (ent & PAGE_NX) == 0 {
+            table.protect(pmem, VirtAddr(vaddr), 4096, true, false)
+                .expect("Failed to protect kernel image page");
+        }
+    }
+}
+
+/// Mark the physical window as non-executable, other than the region which
+/// contains the bootloader
+unsafe fn protect_phys_window(pmem: &mut PhysicalMemory,
+                              table: &mut page_table::PageTable) {
+    // Current address in the physical window
+    let mut vaddr = KERNEL_PHYS_WINDOW_BASE;
+
+    while vaddr < KERNEL_PHYS_WINDOW_BASE + KERNEL_PHYS_WINDOW_SIZE {
+        // Get the page backing this part of the physical window
+        let mapping = table.translate(pmem, VirtAddr(vaddr))
+            .expect("Failed to translate physical window");
+        let page_size = mapping.size()
+            .expect("Physical window not mapped") as u64;
+
+        // Leave any page overlapping the bootloader executable
+        if vaddr - KERNEL_PHYS_WINDOW_BASE >= BOOTLOADER_PHYS_END {
+            table.protect(pmem, VirtAddr(vaddr), page_size, true, false)
+                .expect("Failed to protect physical window");
+        }
+
+        vaddr += page_size;
+    }
+}
+
+/// Enable memory protection hardening on the current core. On the BSP this
+/// also remaps the kernel image and physical window with the strictest
+/// permissions we can use.
+pub unsafe fn init() {
+    // Get the features of the system
+    let features = crate::cpu_features::get();
+
+    if core!().id == 0 {
+        // Get access to physical memory
+        let mut pmem = PhysicalMemory;
+
+        // Get access to the kernel page table
+        let mut table = core!().boot_args.page_table.lock();
+        let table = table.as_mut().unwrap();
+
+        protect_image(&mut pmem, table);
+        protect_phys_window(&mut pmem, table);
+    }
+
+    // The bootloader should have enabled write protection, but make sure
+    cpu::write_cr0(cpu::read_cr0() | CR0_WP);
+
+    // Enable SMEP and SMAP if they are supported. The kernel never maps
+    // anything as user accessible, so this only catches bugs.
+    let mut cr4 = cpu::read_cr4();
+    if features.smep { cr4 |= CR4_SMEP; }
+    if features.smap { cr4 |= CR4_SMAP; }
+    cpu::write_cr4(cr4);
+
+    // Install the protection violation diagnostics if requested
+    if FAULT_DIAGNOSTICS.get() != 0 {
+        core!().interrupts.lock().as_mut().unwrap().add_handler(
+            PAGE_FAULT, page_fault, false);
+    }
+}
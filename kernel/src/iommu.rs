@@ -0,0 +1,504 @@
+//! Intel VT-d IOMMU support for isolating device DMA
+//!
+//! All devices which perform DMA are attached to a single DMA domain. Memory
+//! is only accessible to devices if it was allocated as a `DmaBuffer`, which
+//! maps the memory into the domain at an address in a dedicated DMA window.
+//! Devices which were never attached have no context entry, thus all of their
+//! DMA is blocked once translation is enabled.
+//!
+//! If there is no DMAR ACPI table, or the remapping hardware does not support
+//! what we need, DMA is left untranslated and `DmaBuffer`s just hand out
+//! physical addresses. This module is only compiled in with the `iommu`
+//! feature.
+
+use core::mem::size_of;
+use core::alloc::Layout;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+
+use lockcell::LockCell;
+use page_table::{PhysMem, PhysAddr, VirtAddr, PageTable, PageType};
+use page_table::{PAGE_PRESENT, PAGE_WRITE};
+
+use crate::mm::{self, PhysicalMemory};
+use crate::pci::PciAddress;
+use crate::mmio::{Mmio, Register};
+use crate::core_locals::LockInterrupts;
+
+/// Version register
+const REG_VER: usize = 0x00;
+
+/// Capability register
+const REG_CAP: usize = 0x08;
+
+/// Extended capability register
+const REG_ECAP: usize = 0x10;
+
+/// Global command register
+const REG_GCMD: usize = 0x18;
+
+/// Global status register
+const REG_GSTS: usize = 0x1c;
+
+/// Root table address register
+const REG_RTADDR: usize = 0x20;
+
+/// Context command register
+const REG_CCMD: usize = 0x28;
+
+/// Global command/status bit for translation enable
+const GCMD_TE: u32 = 1 << 31;
+
+/// Global command/status bit for setting the root table pointer
+const GCMD_SRTP: u32 = 1 << 30;
+
+/// Bits of the global status which are one-shot commands and must not be
+/// written back to the global command register
+const GCMD_ONE_SHOT_MASK: u32 = 0x96ff_ffff;
+
+/// Context command bit to invalidate the context cache, cleared by hardware
+/// once the invalidation has completed
+const CCMD_ICC: u64 = 1 << 63;
+
+/// Context command global invalidation granularity
+const CCMD_GLOBAL: u64 = 1 << 61;
+
+/// IOTLB invalidate bit, cleared by hardware once the invalidation has
+/// completed
+const IOTLB_IVT: u64 = 1 << 63;
+
+/// IOTLB global invalidation granularity
+const IOTLB_GLOBAL: u64 = 1 << 60;
+
+/// IOTLB domain-selective invalidation granularity
+const IOTLB_DOMAIN: u64 = 2 << 60;
+
+/// IOTLB page-selective invalidation granularity, the pages are given in the
+/// invalidate address register
+const IOTLB_PAGE: u64 = 3 << 60;
+
+/// Capability bit for caching mode, in which hardware may cache not-present
+/// entries and thus needs invalidations when mappings are created
+const CAP_CM: u64 = 1 << 7;
+
+/// Capability bit for page-selective IOTLB invalidation support
+const CAP_PSI: u64 = 1 << 39;
+
+/// Supported adjusted guest address width bit for 4-level paging
+const SAGAW_4LEVEL: u64 = 1 << 2;
+
+/// Context entry address width encoding for 4-level paging
+const CONTEXT_AW_4LEVEL: u64 = 2;
+
+/// Domain identifier used for all attached devices
+const DOMAIN_ID: u64 = 1;
+
+/// Start of the window of DMA addresses handed out to devices
+const DMA_WINDOW_BASE: u64 = 0x4000_0000;
+
+/// End of the window of DMA addresses handed out to devices. This fits in
+/// the smallest guest address width that hardware with 4-level support has.
+const DMA_WINDOW_END: u64 = 1 << 39;
+
+/// Remapping hardware state, `None` if DMA is not being translated
+static IOMMU: LockCell<Option<Iommu>, LockInterrupts> = LockCell::new(None);
+register_lock!(IOMMU);
+
+/// A DMA remapping hardware unit
+struct RemappingUnit {
+    /// Register set of this unit
+    regs: Mmio,
+
+    /// Offset of the IOTLB registers
+    iotlb_offset: usize,
+
+    /// Set if the unit may cache not-present entries, requiring IOTLB
+    /// invalidations when mappings are created
+    caching_mode: bool,
+
+    /// Largest address mask usable for page-selective invalidations, `None`
+    /// if the unit only supports domain-selective and global invalidations
+    max_address_mask: Option<u32>,
+}
+
+impl RemappingUnit {
+    /// Read the 32-bit register at `offset`
+    unsafe fn read32(&self, offset: usize) -> u32 {
+        self.regs.read(Register::new(offset))
+    }
+
+    /// Write the 32-bit register at `offset`
+    unsafe fn write32(&self, offset: usize, val: u32) {
+        self.regs.write(Register::new(offset), val);
+    }
+
+    /// Read the 64-bit register at `offset`
+    unsafe fn read64(&self, offset: usize) -> u64 {
+        self.regs.read(Register::new(offset))
+    }
+
+    /// Write the 64-bit register at `offset`
+    unsafe fn write64(&self, offset: usize, val: u64) {
+        self.regs.write(Register::new(offset), val);
+    }
+
+    /// Set or clear the global command bits in `cmd` and wait for the global
+    /// status to reflect the change
+    unsafe fn global_command(&self, cmd: u32, set: bool) {
+        let status = self.read32(REG_GSTS) & GCMD_ONE_SHOT_MASK;
+        let new = if set { status | cmd } else { status & !cmd };
+        self.write32(REG_GCMD, new);
+
+        while ((self.read32(REG_GSTS) & cmd) != 0) != set {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Globally invalidate the context cache and the IOTLB
+    unsafe fn invalidate(&self) {
+        self.write64(REG_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        while (self.read64(REG_CCMD) & CCMD_ICC) != 0 {
+            core::hint::spin_loop();
+        }
+
+        self.write64(self.iotlb_offset + 8, IOTLB_IVT | IOTLB_GLOBAL);
+        while (self.read64(self.iotlb_offset + 8) & IOTLB_IVT) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Invalidate the IOTLB entries of the DMA domain for the `size` bytes at
+    /// `dma_addr`. Only the pages of the range are invalidated if the unit
+    /// supports it, otherwise the whole domain is invalidated.
+    unsafe fn invalidate_range(&self, dma_addr: u64, size: u64) {
+        // Find the smallest naturally aligned block of pages which covers the
+        // range, page-selective invalidations work on such blocks
+        let end = dma_addr + size - 1;
+        let mut mask = 0;
+        while (dma_addr >> (12 + mask)) != (end >> (12 + mask)) {
+            mask += 1;
+        }
+
+        let granularity = match self.max_address_mask {
+            Some(max) if mask <= max => {
+                let block = dma_addr & !((0x1000u64 << mask) - 1);
+                self.write64(self.iotlb_offset, block | mask as u64);
+                IOTLB_PAGE
+            }
+            _ => IOTLB_DOMAIN,
+        };
+
+        self.write64(self.iotlb_offset + 8,
+                     IOTLB_IVT | granularity | DOMAIN_ID << 32);
+        while (self.read64(self.iotlb_offset + 8) & IOTLB_IVT) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// State of the DMA remapping hardware
+struct Iommu {
+    /// All remapping units which we have enabled
+    units: Vec<RemappingUnit>,
+
+    /// Physical address of the root table shared by all units
+    root_table: PhysAddr,
+
+    /// Context tables for each bus which has an attached device
+    context_tables: BTreeMap<u8, PhysAddr>,
+
+    /// Second-level page table for the DMA domain. The permission bits of
+    /// second-level entries line up with the present and write bits of
+    /// regular page tables, thus we can manage it with `PageTable`.
+    domain: PageTable,
+
+    /// Next address in the DMA window which was never handed out
+    next_dma_addr: u64,
+
+    /// DMA addresses of freed buffers, keyed by the size of their mapping,
+    /// such that the DMA window is reused rather than exhausted
+    free_dma_addrs: BTreeMap<u64, Vec<u64>>,
+
+    /// Set if hardware page walks snoop the CPU caches. If not, we have to
+    /// write back caches after updating any tables.
+    coherent: bool,
+}
+
+impl Iommu {
+    /// Make table updates visible to the hardware and invalidate all
+    /// remapping caches
+    unsafe fn flush(&self) {
+        if !self.coherent {
+            cpu::wbinvd();
+        }
+
+        for unit in &self.units {
+            unit.invalidate();
+        }
+    }
+
+    /// Get a DMA address for a mapping of `size` bytes. The address of a
+    /// freed mapping of the same size is reused if there is one.
+    fn alloc_dma_addr(&mut self, size: u64) -> u64 {
+        if let Some(dma_addr) = self.free_dma_addrs.get_mut(&size)
+                .and_then(|x| x.pop()) {
+            return dma_addr;
+        }
+
+        let dma_addr = self.next_dma_addr;
+        self.next_dma_addr = dma_addr.checked_add(size)
+            .filter(|&x| x <= DMA_WINDOW_END)
+            .expect("Out of DMA window space");
+        dma_addr
+    }
+
+    /// Write back the cache lines of the table entries which translate the
+    /// `size` bytes at `dma_addr`, such that hardware which doesn't snoop the
+    /// CPU caches sees our updates to them
+    unsafe fn write_back_entries(&self, dma_addr: u64, size: u64) {
+        if self.coherent {
+            return;
+        }
+
+        let mut pmem = PhysicalMemory;
+        for offset in (0..size).step_by(4096) {
+            let mapping = match self.domain.translate(&mut pmem,
+                    VirtAddr(dma_addr + offset)) {
+                Some(mapping) => mapping,
+                None          => continue,
+            };
+
+            for &entry in [mapping.pml4e, mapping.pdpe, mapping.pde,
+                           mapping.pte].iter().flatten() {
+                cpu::clflush(pmem.translate(entry, size_of::<u64>()) as usize);
+            }
+        }
+    }
+
+    /// Check if mapping the `size` bytes at `dma_addr` requires new page
+    /// tables in the domain
+    fn needs_tables(&self, dma_addr: u64, size: u64) -> bool {
+        let mut pmem = PhysicalMemory;
+        (0..size).step_by(4096).any(|offset| {
+            self.domain.translate(&mut pmem, VirtAddr(dma_addr + offset))
+                .and_then(|x| x.pte).is_none()
+        })
+    }
+}
+
+/// Parse the DMAR ACPI table and enable DMA translation for all remapping
+/// units on PCI segment 0. Until devices are attached, this blocks all DMA.
+pub unsafe fn init() {
+    // Get the DMAR table, if there isn't one there is no remapping hardware
+    let (payload, payload_len) =
+        if let Some(dmar) = crate::acpi::find_table(b"DMAR") {
+            dmar
+        } else {
+            return;
+        };
+
+    // Parse each remapping structure, which start after the host address
+    // width, flags, and 10 reserved bytes
+    let mut units  = Vec::new();
+    let mut offset = 12;
+    while offset + 4 <= payload_len {
+        let entry  = payload.0 + offset as u64;
+        let typ    = mm::read_phys::<u16>(PhysAddr(entry));
+        let length = mm::read_phys::<u16>(PhysAddr(entry + 2)) as usize;
+        if length < 4 { break; }
+        offset += length;
+
+        // Only handle DMA remapping hardware unit definitions (DRHDs) for
+        // segment 0
+        if typ != 0 || length < 16 { continue; }
+        let segment = mm::read_phys::<u16>(PhysAddr(entry + 6));
+        let base    = mm::read_phys::<u64>(PhysAddr(entry + 8));
+        if segment != 0 { continue; }
+
+        // Map in the registers
+        let regs = Mmio::map("VT-d remapping unit", PhysAddr(base), 4096);
+        let mut unit = RemappingUnit {
+            regs,
+            iotlb_offset:     0,
+            caching_mode:     false,
+            max_address_mask: None,
+        };
+
+        // Make sure the unit supports 4-level second-level paging and an
+        // address width large enough for our DMA window
+        let cap  = unit.read64(REG_CAP);
+        let ecap = unit.read64(REG_ECAP);
+        let mgaw = ((cap >> 16) & 0x3f) + 1;
+        if (cap & SAGAW_4LEVEL) == 0 || (1u64 << mgaw) < DMA_WINDOW_END {
+            print!("IOMMU at {:#x} unsupported, leaving DMA untranslated\n",
+                   base);
+            return;
+        }
+
+        // Get the offset of the IOTLB registers and how they can be used
+        unit.iotlb_offset = (((ecap >> 8) & 0x3ff) * 16) as usize;
+        unit.caching_mode = (cap & CAP_CM) != 0;
+        if (cap & CAP_PSI) != 0 {
+            unit.max_address_mask = Some(((cap >> 48) & 0x3f) as u32);
+        }
+
+        print!("IOMMU at {:#x} | version {:#x} | cap {:#x} | ecap {:#x}\n",
+               base, unit.read32(REG_VER), cap, ecap);
+
+        units.push((unit, (ecap & 1) != 0));
+    }
+
+    if units.is_empty() {
+        return;
+    }
+
+    // We can only skip cache write backs if all units are coherent
+    let coherent = units.iter().all(|x| x.1);
+    let units: Vec<RemappingUnit> = units.into_iter().map(|x| x.0).collect();
+
+    // Allocate the root table. All entries are not present, thus all DMA is
+    // blocked once translation is enabled.
+    let mut pmem = PhysicalMemory;
+    let root_table = pmem.alloc_phys_zeroed(
+        Layout::from_size_align(4096, 4096).unwrap());
+
+    // Create the domain page table
+    let domain = PageTable::new(&mut pmem);
+
+    if !coherent {
+        cpu::wbinvd();
+    }
+
+    // Program the root table and enable translation on every unit
+    for unit in &units {
+        unit.write64(REG_RTADDR, root_table.0);
+        unit.global_command(GCMD_SRTP, true);
+        unit.invalidate();
+        unit.global_command(GCMD_TE, true);
+    }
+
+    *IOMMU.lock() = Some(Iommu {
+        units,
+        root_table,
+        context_tables: BTreeMap::new(),
+        domain,
+        next_dma_addr:  DMA_WINDOW_BASE,
+        free_dma_addrs: BTreeMap::new(),
+        coherent,
+    });
+}
+
+/// Attach the PCI function at `addr` to the DMA domain, allowing it to access
+/// `DmaBuffer`s. This does nothing if DMA is not being translated.
+pub unsafe fn attach(addr: PciAddress) {
+    let mut iommu = IOMMU.lock();
+    let iommu = if let Some(iommu) = iommu.as_mut() {
+        iommu
+    } else {
+        return;
+    };
+
+    assert!(addr.segment == 0, "IOMMU attach outside of segment 0");
+
+    let mut pmem = PhysicalMemory;
+
+    // Get the context table for this bus, creating it if needed
+    let context_table = if let Some(&table) =
+            iommu.context_tables.get(&addr.bus) {
+        table
+    } else {
+        let table = pmem.alloc_phys_zeroed(
+            Layout::from_size_align(4096, 4096).unwrap());
+
+        // Link the context table into the root table
+        mm::write_phys(PhysAddr(iommu.root_table.0 +
+            addr.bus as u64 * 2 * size_of::<u64>() as u64), table.0 | 1);
+
+        iommu.context_tables.insert(addr.bus, table);
+        table
+    };
+
+    // Create the context entry for this function, pointing to the domain
+    // page table
+    let devfn = (addr.device as u64) << 3 | addr.function as u64;
+    let entry = context_table.0 + devfn * 2 * size_of::<u64>() as u64;
+    mm::write_phys(PhysAddr(entry + 8), CONTEXT_AW_4LEVEL | DOMAIN_ID << 8);
+    mm::write_phys(PhysAddr(entry), iommu.domain.table().0 | 1);
+
+    iommu.flush();
+}
+
+/// Disable DMA translation. This is used during soft reboots such that the
+/// firmware drivers used by the bootloader can DMA again.
+pub unsafe fn disable() {
+    if let Some(iommu) = &*IOMMU.shatter() {
+        for unit in &iommu.units {
+            unit.global_command(GCMD_TE, false);
+        }
+    }
+}
+
+/// Map the `size` bytes of physical memory at `paddr` into the DMA domain as
+/// readable and writable, returning the address devices should use to access
+/// it. Returns `None` if DMA is not being translated, in which case devices
+/// use physical addresses.
+pub fn map(paddr: PhysAddr, size: u64) -> Option<u64> {
+    let mut iommu = IOMMU.lock();
+    let iommu = iommu.as_mut()?;
+
+    // Allocate a DMA address
+    let dma_addr = iommu.alloc_dma_addr(size);
+
+    // New page tables are zeroed through the CPU caches, and hardware which
+    // doesn't snoop them may walk through any of their entries
+    let needs_tables = iommu.needs_tables(dma_addr, size);
+
+    // Map the memory into the DMA domain
+    let mut pmem = PhysicalMemory;
+    for offset in (0..size).step_by(4096) {
+        unsafe {
+            iommu.domain.map_raw(&mut pmem, VirtAddr(dma_addr + offset),
+                PageType::Page4K,
+                (paddr.0 + offset) | PAGE_WRITE | PAGE_PRESENT)
+                .expect("Failed to map DMA buffer");
+        }
+    }
+
+    unsafe {
+        // Make the new mapping visible to the hardware. Only entries we
+        // wrote need to be written back, unless there are new tables.
+        if !iommu.coherent && needs_tables {
+            cpu::wbinvd();
+        } else {
+            iommu.write_back_entries(dma_addr, size);
+        }
+
+        // Not-present entries are only cached in caching mode
+        for unit in iommu.units.iter().filter(|x| x.caching_mode) {
+            unit.invalidate_range(dma_addr, size);
+        }
+    }
+
+    Some(dma_addr)
+}
+
+/// Revoke device access to the `size` bytes mapped at `dma_addr` by `map()`
+pub fn unmap(dma_addr: u64, size: u64) {
+    let mut iommu = IOMMU.lock();
+    let iommu = iommu.as_mut().unwrap();
+    unsafe {
+        iommu.domain.unmap(&mut PhysicalMemory, VirtAddr(dma_addr), size);
+
+        // Write back the cleared entries, which includes any entries
+        // unlinking tables which were freed, and drop the translations from
+        // the IOTLBs
+        iommu.write_back_entries(dma_addr, size);
+        for unit in &iommu.units {
+            unit.invalidate_range(dma_addr, size);
+        }
+    }
+
+    // The DMA address can now be used for another mapping
+    iommu.free_dma_addrs.entry(size).or_insert_with(Vec::new)
+        .push(dma_addr);
+}
@@ -0,0 +1,205 @@
+//! IPv4 fragmentation and reassembly
+//!
+//! IP packets larger than the MTU are split into fragments on send, and
+//! fragments we receive are collected until the whole packet can be handed to
+//! the rest of the stack. Reassembly is bounded in the number of packets in
+//! progress, their size, and how long we wait for their fragments, such that
+//! a flood of bogus fragments cannot consume unbounded memory.
+//!
+//! Packets are reassembled into buffers which hold the largest IPv4 packet,
+//! thus reassembled packets may be larger than a frame. They're handed to
+//! sockets like any other packet, but must be fragmented again to be sent.
+
+use alloc::vec::Vec;
+
+use crate::net::Packet;
+
+/// Maximum number of IP packets which can be in the process of being
+/// reassembled. When a fragment for a new packet arrives and this many are
+/// in progress, the oldest one is dropped.
+const MAX_REASSEMBLIES: usize = 8;
+
+/// Time to wait for all fragments of an IP packet to arrive, in microseconds
+const REASSEMBLY_TIMEOUT: u64 = 1_000_000;
+
+/// Largest IP payload which we reassemble, this is the largest payload an
+/// IPv4 packet can carry
+const MAX_PAYLOAD: usize = 65535 - 20;
+
+/// Offset of the IP payload in a packet
+const PAYLOAD_OFFSET: usize = 14 + 20;
+
+/// Number of 8-byte blocks tracked by a reassembly bitmap
+const BITMAP_BLOCKS: usize = (MAX_PAYLOAD + 7) / 8;
+
+/// An IP packet being reassembled
+struct Reassembly {
+    /// Source IP, destination IP, identification, and protocol of the packet
+    key: (u32, u32, u16, u8),
+
+    /// Packet the fragments are reassembled into
+    packet: Packet,
+
+    /// Bitmap of 8-byte blocks of the payload which have been received
+    received: [u64; (BITMAP_BLOCKS + 63) / 64],
+
+    /// Size of the payload, known once the last fragment has arrived
+    length: Option<usize>,
+
+    /// TSC value after which the reassembly is abandoned
+    deadline: u64,
+}
+
+/// Reassembly state for fragments received on a network device
+pub struct Reassembler {
+    /// IP packets currently being reassembled
+    entries: Vec<Reassembly>,
+}
+
+impl Reassembler {
+    /// Create a new reassembler with nothing in progress
+    pub fn new() -> Self {
+        Reassembler { entries: Vec::new() }
+    }
+
+    /// Add the IP fragment `packet` to its reassembly. Returns the
+    /// reassembled packet once all of its fragments have arrived.
+    pub fn insert(&mut self, packet: &Packet) -> Option<Packet> {
+        let frag = packet.ip_fragment()?;
+        let key  = (frag.ip.src_ip.into(), frag.ip.dst_ip.into(), frag.id,
+                    frag.ip.protocol);
+        let data = frag.ip.payload;
+
+        // Abandon reassemblies which took too long
+        let now = cpu::rdtsc();
+        self.entries.retain(|x| now < x.deadline);
+
+        // Find the reassembly this fragment belongs to, or start a new one
+        let idx = match self.entries.iter().position(|x| x.key == key) {
+            Some(idx) => idx,
+            None => {
+                // Make room by dropping the oldest reassembly
+                if self.entries.len() >= MAX_REASSEMBLIES {
+                    let oldest = (0..self.entries.len())
+                        .min_by_key(|&ii| self.entries[ii].deadline)
+                        .unwrap();
+                    self.entries.swap_remove(oldest);
+                }
+
+                let mut buffer = Packet::new_large();
+                buffer.put(PAYLOAD_OFFSET + MAX_PAYLOAD);
+
+                self.entries.push(Reassembly {
+                    key,
+                    packet:   buffer,
+                    received: [0; (BITMAP_BLOCKS + 63) / 64],
+                    length:   None,
+                    deadline: crate::time::future(REASSEMBLY_TIMEOUT),
+                });
+                self.entries.len() - 1
+            }
+        };
+        let entry = &mut self.entries[idx];
+
+        // Validate the fragment. It must fit in our reassembly buffer, all but
+        // the last fragment must be a multiple of 8 bytes, and it must agree
+        // with the size of the packet if we know it. If anything is off, the
+        // whole packet is dropped.
+        let end = frag.offset + data.len();
+        if end > MAX_PAYLOAD || (frag.more && (data.len() % 8) != 0) ||
+                entry.length.map(|x| end > x || (!frag.more && end != x)) ==
+                    Some(true) {
+            self.entries.swap_remove(idx);
+            return None;
+        }
+
+        // Copy in the fragment
+        let raw = entry.packet.raw_mut();
+        raw[PAYLOAD_OFFSET + frag.offset..PAYLOAD_OFFSET + end]
+            .copy_from_slice(data);
+        if frag.offset == 0 {
+            // The first fragment provides the headers for the packet
+            raw[..PAYLOAD_OFFSET]
+                .copy_from_slice(&packet.raw()[..PAYLOAD_OFFSET]);
+        }
+
+        // Mark the blocks of the fragment as received
+        for block in frag.offset / 8..(end + 7) / 8 {
+            entry.received[block / 64] |= 1 << (block % 64);
+        }
+
+        // The last fragment tells us the size of the packet
+        if !frag.more {
+            entry.length = Some(end);
+        }
+
+        // Check if we have the whole packet
+        let length = entry.length?;
+        let complete = (0..(length + 7) / 8).all(|block| {
+            (entry.received[block / 64] & (1 << (block % 64))) != 0
+        });
+        if !complete {
+            return None;
+        }
+
+        // Turn the reassembled packet into an unfragmented packet
+        let mut packet = self.entries.swap_remove(idx).packet;
+        packet.set_len(PAYLOAD_OFFSET + length);
+        write_ip_header(&mut packet, 0, false);
+        Some(packet)
+    }
+}
+
+/// Update the length, fragment offset, and more fragments flag of the IP
+/// header of `packet`, and recompute its checksum
+fn write_ip_header(packet: &mut Packet, offset: usize, more: bool) {
+    let length = packet.raw().len() - 14;
+    let ip     = &mut packet.raw_mut()[14..PAYLOAD_OFFSET];
+
+    // Set the total length
+    ip[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+
+    // Set the flags and fragment offset (in 8-byte units)
+    let flags = ((more as u16) << 13) | (offset / 8) as u16;
+    ip[6..8].copy_from_slice(&flags.to_be_bytes());
+
+    // Compute the checksum and fill in the checksum field
+    ip[10..12].copy_from_slice(&[0; 2]);
+    let checksum = Packet::checksum(0, ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Split the IP packet `packet` into fragments which fit in `mtu`, using `id`
+/// as the identification of the fragments
+pub fn fragment(packet: &Packet, mtu: usize, id: u16) -> Vec<Packet> {
+    let ip = packet.ip().expect("Attempted to fragment a non-IP packet");
+    let headers = &packet.raw()[..PAYLOAD_OFFSET];
+
+    // Make sure we're allowed to fragment the packet
+    assert!((headers[14 + 6] & 0x40) == 0,
+        "Attempted to fragment a don't fragment IP packet");
+
+    // Get the amount of payload in each fragment. All but the last fragment
+    // must be a multiple of 8 bytes.
+    let chunk_size = (mtu - 20) & !7;
+
+    let mut fragments = Vec::new();
+    for (ii, chunk) in ip.payload.chunks(chunk_size).enumerate() {
+        let offset = ii * chunk_size;
+        let more   = offset + chunk.len() < ip.payload.len();
+
+        // Copy in the headers and this part of the payload
+        let mut fragment = Packet::new();
+        let raw = fragment.put(PAYLOAD_OFFSET + chunk.len());
+        raw[..PAYLOAD_OFFSET].copy_from_slice(headers);
+        raw[PAYLOAD_OFFSET..].copy_from_slice(chunk);
+
+        // Set the identification and the fragment information
+        raw[14 + 4..14 + 6].copy_from_slice(&id.to_be_bytes());
+        write_ip_header(&mut fragment, offset, more);
+
+        fragments.push(fragment);
+    }
+
+    fragments
+}
@@ -0,0 +1,150 @@
+//! Registry of the named `LockCell`s in the kernel
+//!
+//! Static locks are registered with `register_lock!` next to their
+//! declaration, which places a `RegisteredLock` in the `.lockreg` section of
+//! the kernel image. The linker sorts grouped sections by the name after the
+//! `$`, thus all registrations end up between the `START` and `END` markers
+//! and can be enumerated at runtime without any central list of locks.
+
+use lockcell::LockState;
+#[cfg(feature = "lock_hold_check")]
+use lockcell::LockCell;
+#[cfg(feature = "lock_hold_check")]
+use histogram::{Histogram, Summary};
+#[cfg(feature = "lock_hold_check")]
+use crate::core_locals::LockInterrupts;
+#[cfg(feature = "lock_hold_check")]
+use core::sync::atomic::Ordering;
+
+/// A `LockCell` which was registered with `register_lock!`
+#[repr(C)]
+pub struct RegisteredLock {
+    /// Path of the static holding the lock
+    pub name: &'static str,
+
+    /// The lock itself
+    pub lock: &'static dyn LockState,
+}
+
+/// Register the static `LockCell` `$lock`, making it show up in
+/// `locks::registered()`
+macro_rules! register_lock {
+    ($lock:ident) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".lockreg$m"]
+            static REGISTRATION: $crate::locks::RegisteredLock =
+                $crate::locks::RegisteredLock {
+                    name: concat!(module_path!(), "::", stringify!($lock)),
+                    lock: &$lock,
+                };
+        };
+    }
+}
+
+/// Marker placed before all registered locks
+#[used]
+#[link_section = ".lockreg$a"]
+static START: [RegisteredLock; 0] = [];
+
+/// Marker placed after all registered locks
+#[used]
+#[link_section = ".lockreg$z"]
+static END: [RegisteredLock; 0] = [];
+
+/// Get all registered locks
+pub fn registered() -> &'static [RegisteredLock] {
+    let start = START.as_ptr();
+    let len   = (END.as_ptr() as usize - start as usize) /
+        core::mem::size_of::<RegisteredLock>();
+
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+/// Invoke `func` with every registered lock which is currently held and the
+/// ID of the core holding it, if known
+pub fn for_each_held<F: FnMut(&RegisteredLock, Option<u32>)>(mut func: F) {
+    for reg in registered() {
+        if reg.lock.is_locked() {
+            func(reg, reg.lock.holder());
+        }
+    }
+}
+
+/// Forcibly release all registered locks. This must only be used once all
+/// other cores are halted and we never return to anything holding a lock,
+/// such that teardown during a soft reboot can't deadlock on a lock which was
+/// held when a core was stopped.
+pub unsafe fn release_all() {
+    for reg in registered() {
+        reg.lock.force_release();
+    }
+}
+
+/// Release all registered locks held by the core with ID `core_id`, invoking
+/// `func` with each lock released. This must only be used once the core is
+/// parked or otherwise will never release the locks itself.
+pub unsafe fn release_held_by<F: FnMut(&RegisteredLock)>(core_id: u32,
+                                                         mut func: F) {
+    for reg in registered() {
+        if reg.lock.release_holder(core_id) {
+            func(reg);
+        }
+    }
+}
+
+/// Histograms of how long locks were waited on and held, in TSC cycles
+#[cfg(feature = "lock_hold_check")]
+struct LockTimes {
+    /// Time from requesting a lock until getting it
+    waited: Histogram,
+
+    /// Time from getting a lock until releasing it
+    held: Histogram,
+}
+
+/// Times of all locks released on any core. This is taken when locks are
+/// released in interrupts, thus it disables interrupts while held.
+#[cfg(feature = "lock_hold_check")]
+static LOCK_TIMES: LockCell<LockTimes, LockInterrupts> =
+    LockCell::new_no_preempt(LockTimes {
+        waited: Histogram::new(),
+        held:   Histogram::new(),
+    });
+#[cfg(feature = "lock_hold_check")]
+register_lock!(LOCK_TIMES);
+
+/// Record that a lock was waited on for `waited` and held for `held` TSC
+/// cycles. Releasing `LOCK_TIMES` itself is not recorded, and neither are
+/// locks released in exceptions, as we can't block on `LOCK_TIMES` there.
+#[cfg(feature = "lock_hold_check")]
+pub fn record_times(waited: u64, held: u64) {
+    let core = core!();
+    if core.in_exception() ||
+            core.recording_lock_times.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    {
+        let mut times = LOCK_TIMES.lock();
+        times.waited.record(waited);
+        times.held.record(held);
+    }
+
+    core.recording_lock_times.store(false, Ordering::SeqCst);
+}
+
+/// Get summaries of how long locks were waited on and held, in TSC cycles
+#[cfg(feature = "lock_hold_check")]
+pub fn times() -> (Summary, Summary) {
+    let times = LOCK_TIMES.lock();
+    (times.waited.summary(), times.held.summary())
+}
+
+/// Forget all recorded lock times, as the statics survive a soft reboot
+#[cfg(feature = "lock_hold_check")]
+pub fn reset_times() {
+    let mut times = LOCK_TIMES.lock();
+    times.waited.clear();
+    times.held.clear();
+}
@@ -0,0 +1,88 @@
+//! Software loopback network driver
+//!
+//! Every frame sent on a loopback device is received back on it, which lets
+//! us exercise the network stack without any hardware or a network around it.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::net::{NetDriver, NetDevice, Packet, PacketLease, LinkState};
+use crate::net::{Capabilities, UdpSocket, MAX_MTU};
+
+/// MAC address of loopback devices (locally administered)
+const LOOPBACK_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+/// Port used for the loopback self test
+const SELF_TEST_PORT: u16 = 1337;
+
+/// Loopback network driver
+pub struct Loopback {
+    /// Frames which were sent and are waiting to be received
+    queue: VecDeque<Packet>,
+}
+
+impl Loopback {
+    /// Create a new loopback driver
+    pub fn new() -> Self {
+        Loopback {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl NetDriver for Loopback {
+    fn mac(&self) -> [u8; 6] {
+        LOOPBACK_MAC
+    }
+
+    fn link_state(&self) -> LinkState {
+        // There's no physical link, thus it's always up. Report the speed of
+        // the fastest NIC we have a driver for.
+        LinkState::Up(1000)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // Frames never leave memory, thus checksums never need to be computed
+        // and there is nothing to segment
+        Capabilities {
+            checksum: true,
+            tso:      true,
+            queues:   1,
+            max_mtu:  MAX_MTU,
+        }
+    }
+
+    fn recv<'a, 'b: 'a>(&'b mut self) -> Option<PacketLease<'a>> {
+        let packet = self.queue.pop_front()?;
+        Some(PacketLease::new(self, packet))
+    }
+
+    fn send(&mut self, packet: Packet) {
+        self.queue.push_back(packet);
+    }
+}
+
+/// Send a UDP packet over a loopback device and make sure the stack delivers
+/// it intact to the bound port. Returns `true` if it was.
+pub fn self_test() -> bool {
+    const MESSAGE: &[u8] = b"chocolate milk";
+
+    let device = NetDevice::new(Box::new(Loopback::new()));
+    assert!(device.link_state() != LinkState::Down,
+        "Loopback link is down");
+
+    // Bind to the test port
+    let bind = UdpSocket::bind(&device, SELF_TEST_PORT)
+        .expect("Could not bind to loopback self test port");
+
+    // Send a packet to ourselves
+    bind.send_to(device.mac(), 0x7f00_0001.into(), SELF_TEST_PORT, |packet| {
+        packet.put(MESSAGE.len()).copy_from_slice(MESSAGE);
+    });
+
+    // Make sure we got the exact same thing back
+    let received = bind.recv(|_, udp| {
+        Some(udp.src_port == SELF_TEST_PORT && udp.payload == MESSAGE)
+    });
+    received == Some(true)
+}
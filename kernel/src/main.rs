@@ -1,7 +1,7 @@
 //! A kernel written all in Rust
 
 #![feature(panic_info_message, alloc_error_handler, asm, global_asm)]
-#![feature(const_in_array_repeat_expressions)]
+#![feature(const_in_array_repeat_expressions, core_intrinsics)]
 
 #![no_std]
 #![no_main]
@@ -13,6 +13,7 @@ extern crate core_reqs;
 
 #[macro_use] mod core_locals;
 #[macro_use] mod print;
+#[macro_use] mod locks;
 mod panic;
 mod mm;
 mod interrupts;
@@ -20,10 +21,32 @@ mod apic;
 mod acpi;
 mod intrinsics;
 mod pci;
-mod e1000;
-mod net;
-mod dhcp;
+#[cfg(feature = "network")] mod e1000;
+#[cfg(feature = "network")] mod net;
+#[cfg(feature = "network")] mod dhcp;
+#[cfg(feature = "network")] mod loopback;
+#[cfg(feature = "network")] mod ipfrag;
+#[cfg(feature = "network")] mod beacon;
+#[cfg(feature = "network")] mod node;
+#[cfg(feature = "network")] mod command;
 mod time;
+mod cpu_features;
+#[cfg(feature = "microcode")] mod microcode;
+mod hardening;
+mod timer;
+mod executor;
+mod bus;
+#[cfg(feature = "iommu")] mod iommu;
+mod dma;
+mod mmio;
+#[cfg(feature = "roles")] mod role;
+mod tunables;
+mod physmap;
+mod memtest;
+mod selftest;
+mod crash_ring;
+mod report;
+#[cfg(feature = "profile")] mod profile;
 
 use page_table::PhysAddr;
 
@@ -44,16 +67,73 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
 
     // Initialize the core locals, this must happen first.
     core_locals::init(boot_args, core_id);
+
+    if core_id == 0 {
+        // Apply tunable overrides before anything uses them
+        unsafe { tunables::init(); }
+
+        // Reserve the physical memory which was in use before we booted
+        physmap::init();
+
+        // Pick up crash artifacts from previous boots
+        unsafe { crash_ring::init(); }
+    }
+
+    // Apply any microcode update we were given. This must happen before
+    // detecting CPU features as an update may change them.
+    #[cfg(feature = "microcode")]
+    {
+        if role::subsystems().microcode {
+            unsafe { microcode::init(); }
+        }
+    }
+
+    // Detect the CPU features, making sure all cores match the BSP
+    cpu_features::init();
     
     // Initialize interrupts
     interrupts::init();
 
+    // Enforce W^X and enable CPU memory protection features
+    unsafe { hardening::init(); }
+
     // Initialize the APIC
     unsafe { apic::init(); }
     
     if core!().id == 0 {
         // One-time initialization for the whole kernel
 
+        // Throw away what the boot-time tests left behind on a previous
+        // boot, before any other core can run them
+        memtest::reset();
+        selftest::reset();
+
+        // Forget the lock times recorded during a previous boot
+        #[cfg(feature = "lock_hold_check")]
+        locks::reset_times();
+
+        // Report what this node is going to be used for
+        #[cfg(feature = "roles")]
+        print!("[{:16.8}] Booting as {:?} with {:?}\n", time::uptime(),
+               role::get(), role::subsystems());
+
+        // Set up DMA isolation, this must happen before any drivers start
+        // using DMA
+        #[cfg(feature = "iommu")]
+        {
+            if role::subsystems().iommu {
+                unsafe { iommu::init() }
+            }
+        }
+
+        // Make sure the network stack works before handing it real devices
+        #[cfg(feature = "network")]
+        {
+            if role::subsystems().network {
+                assert!(loopback::self_test(), "Loopback self test failed");
+            }
+        }
+
         // Initialize PCI devices
         unsafe { pci::init() }
 
@@ -61,6 +141,11 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
         // information with the memory manager through the use of the ACPI
         // information.
         unsafe { acpi::init() }
+
+        // Dump the physical address space if requested
+        if physmap::DUMP_PHYS_REGIONS.get() != 0 {
+            physmap::dump();
+        }
     }
 
     // Enable the APIC timer
@@ -69,6 +154,17 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
     // Now we're ready for interrupts!
     unsafe { core!().enable_interrupts(); }
     
+    // Get the executor ready before other cores can ask it to park
+    executor::init();
+
+    // Set up our mailbox such that other cores can message us once they're
+    // released
+    bus::init();
+
+    // Report the profile of this core from the executor
+    #[cfg(feature = "profile")]
+    profile::init();
+
     // Let ACPI know that we've booted, it'll be happy to know we're here!
     // This will also serialize until all cores have come up. Once all cores
     // are online this will release all of the cores. This ensures that no
@@ -78,11 +174,22 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
     // NMIs and soft reboots work.
     acpi::core_checkin();
 
+    // Test all free memory if requested, before anything else can use it
+    if memtest::MEMTEST.get() != 0 {
+        memtest::run();
+    }
+
+    // Make sure the kernel works on this node before running anything
+    if selftest::SELFTEST.get() != 0 {
+        selftest::run();
+    }
+
     if core!().id == acpi::num_cores() - 1 {
         print!("[{:16.8}] We made it! All cores online! {}\n",
                time::uptime(), core!().id + 1);
     }
 
-    cpu::halt();
+    // Run background tasks for the rest of time
+    executor::run();
 }
 
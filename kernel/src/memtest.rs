@@ -0,0 +1,254 @@
+//! Boot-time test of free physical memory
+//!
+//! When enabled, every core takes chunks of free physical memory out of the
+//! allocator, writes and reads back a set of patterns, and hands the chunk
+//! to the BSP once it's done. Once all free memory has been tested, the BSP
+//! puts the good memory back into the allocator and reserves the bad memory
+//! such that nothing ever uses it.
+//!
+//! All free memory is held by the test while it runs, thus nothing may
+//! allocate physical memory until it completes. This is why it runs right
+//! after all cores check in, before any tasks are started, and why every core
+//! runs it with interrupts disabled such that no interrupt handler can
+//! allocate either. Memory which was already allocated by then is not
+//! tested.
+//!
+//! Once the test is done the bad ranges are reported to the server, if there
+//! is one.
+//!
+//! The number of separate bad ranges is bounded, such that the good memory
+//! around them still fits in the allocator. Once the bound is hit, further
+//! bad pages are merged into the closest bad range, giving up the memory in
+//! between, and the report is flagged as truncated. Thus even badly broken
+//! RAM still boots and reports, with all of the bad pages excluded.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicBool, Ordering};
+use alloc::string::String;
+
+use lockcell::LockCell;
+use rangeset::{Range, RangeSet};
+use page_table::PhysAddr;
+use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+
+use crate::acpi;
+use crate::time;
+use crate::report;
+use crate::core_locals::LockInterrupts;
+use crate::physmap::{self, RegionType};
+use crate::tunables::Tunable;
+
+/// If non-zero, all free physical memory is tested at boot
+pub static MEMTEST: Tunable = Tunable::new("memtest", 0);
+
+/// Largest chunk of memory a core tests at once
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Fixed patterns written to memory. After these, every 64-bit word is also
+/// tested with its own physical address to catch aliased address lines.
+const PATTERNS: &[u64] = &[
+    0x0000_0000_0000_0000,
+    0xffff_ffff_ffff_ffff,
+    0xaaaa_aaaa_aaaa_aaaa,
+    0x5555_5555_5555_5555,
+];
+
+/// Memory which passed the test, held until all cores are done
+static TESTED: LockCell<RangeSet, LockInterrupts> =
+    LockCell::new(RangeSet::new());
+register_lock!(TESTED);
+
+/// 4 KiB pages which failed the test
+static BAD: LockCell<RangeSet, LockInterrupts> =
+    LockCell::new(RangeSet::new());
+register_lock!(BAD);
+
+/// Maximum number of separate ranges in `BAD`. Every bad range may split a
+/// range of free memory in two, thus this leaves plenty of room in the
+/// 256-entry `RangeSet` of the allocator.
+const MAX_BAD_RANGES: usize = 64;
+
+/// Set if bad pages were merged into other bad ranges as there were more
+/// than `MAX_BAD_RANGES` of them
+static TRUNCATED: AtomicBool = AtomicBool::new(false);
+
+/// Number of cores which have run out of memory to test
+static CORES_DONE: AtomicU32 = AtomicU32::new(0);
+
+/// Set by the BSP once the tested memory has been returned to the allocator
+static COMPLETE: AtomicBool = AtomicBool::new(false);
+
+/// Reset the state of the test left behind by a previous boot, as statics
+/// survive soft reboots. This must be called on the BSP before the other
+/// cores are launched.
+pub fn reset() {
+    *TESTED.lock() = RangeSet::new();
+    *BAD.lock()    = RangeSet::new();
+    TRUNCATED.store(false, Ordering::SeqCst);
+    CORES_DONE.store(0, Ordering::SeqCst);
+    COMPLETE.store(false, Ordering::SeqCst);
+}
+
+/// Take the largest chunk of free memory we can, up to `CHUNK_SIZE`, out of
+/// the allocator. Returns `None` once there is no free memory left.
+fn take_chunk() -> Option<Range> {
+    let mut phys_mem = core!().boot_args.free_memory.lock();
+    let phys_mem     = phys_mem.as_mut().unwrap();
+
+    let mut size = CHUNK_SIZE;
+    while size >= 4096 {
+        if let Some(start) = phys_mem.allocate(size, 4096) {
+            return Some(Range {
+                start: start as u64,
+                end:   start as u64 + size - 1,
+            });
+        }
+        size /= 2;
+    }
+
+    None
+}
+
+/// Record that the 4 KiB page at `page` failed the test in `bad`. If `bad`
+/// already holds `MAX_BAD_RANGES` ranges, the closest one is grown to cover
+/// the page instead of adding another range.
+fn mark_bad(bad: &mut RangeSet, page: u64) {
+    let mut range = Range { start: page, end: page + 4095 };
+
+    // Get the distance from the page to the closest bad range
+    let closest = bad.entries().iter().copied().min_by_key(|x| {
+        if range.end < x.start {
+            x.start - range.end
+        } else {
+            range.start.saturating_sub(x.end)
+        }
+    });
+
+    // Merge into the closest range if there's no room for another one and
+    // the page doesn't overlap or touch the closest range already
+    if let Some(closest) = closest {
+        if bad.entries().len() >= MAX_BAD_RANGES &&
+                (range.end + 1 < closest.start ||
+                 range.start > closest.end + 1) {
+            range.start = core::cmp::min(range.start, closest.start);
+            range.end   = core::cmp::max(range.end,   closest.end);
+            TRUNCATED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    bad.insert(range);
+}
+
+/// Test the memory in `chunk`, recording every 4 KiB page which fails in
+/// `BAD`
+unsafe fn test_chunk(chunk: Range) {
+    assert!(chunk.end < KERNEL_PHYS_WINDOW_SIZE,
+            "Physical memory outside of window");
+
+    let words = ((chunk.end - chunk.start + 1) / 8) as usize;
+    let ptr   = (KERNEL_PHYS_WINDOW_BASE + chunk.start) as *mut u64;
+
+    // Get the value to write to word `ii` for the pattern `pattern`
+    let value = |pattern: usize, ii: usize| {
+        PATTERNS.get(pattern).copied()
+            .unwrap_or(chunk.start + ii as u64 * 8)
+    };
+
+    for pattern in 0..=PATTERNS.len() {
+        // Write the whole chunk before reading any of it back, such that
+        // writes which land somewhere else are noticed
+        for ii in 0..words {
+            core::ptr::write_volatile(ptr.add(ii), value(pattern, ii));
+        }
+
+        // Read it back, marking the pages with mismatches
+        for ii in 0..words {
+            if core::ptr::read_volatile(ptr.add(ii)) != value(pattern, ii) {
+                let page = (chunk.start + ii as u64 * 8) & !0xfff;
+
+                mark_bad(&mut BAD.lock(), page);
+            }
+        }
+    }
+}
+
+/// Test all free physical memory, this must be called on every core once
+/// all cores have checked in
+pub fn run() {
+    // Nothing may allocate while the test holds all free memory, thus make
+    // sure no interrupt handler runs on this core until it completes
+    unsafe { core!().disable_interrupts(); }
+    let summary = test();
+    unsafe { core!().enable_interrupts(); }
+
+    // Let the server know how the memory of this node held up
+    if let Some(summary) = summary {
+        report::send(report::Kind::MemTest, summary.as_bytes());
+    }
+}
+
+/// Test all free physical memory on this core, with interrupts disabled.
+/// Returns a summary of the results on the BSP.
+fn test() -> Option<String> {
+    if core!().id == 0 {
+        print!("[{:16.8}] Testing free physical memory on all cores\n",
+               time::uptime());
+    }
+
+    // Test chunks until there's no free memory left
+    while let Some(chunk) = take_chunk() {
+        unsafe { test_chunk(chunk); }
+        TESTED.lock().insert(chunk);
+    }
+
+    CORES_DONE.fetch_add(1, Ordering::SeqCst);
+
+    if core!().id != 0 {
+        // Wait for the BSP to give the memory back
+        while !COMPLETE.load(Ordering::SeqCst) {}
+        return None;
+    }
+
+    // Wait for all cores to finish
+    while CORES_DONE.load(Ordering::SeqCst) != acpi::num_cores() {}
+
+    let tested = *TESTED.lock();
+    let mut good = tested;
+    good.subtract(&*BAD.lock());
+
+    // Bad ranges which were merged may span memory which was allocated
+    // before the test, only the tested part of them is ours to reserve
+    let mut bad = tested;
+    bad.subtract(&good);
+
+    // Give back all of the memory which passed
+    {
+        let mut phys_mem = core!().boot_args.free_memory.lock();
+        let phys_mem     = phys_mem.as_mut().unwrap();
+        for &range in good.entries() {
+            phys_mem.insert(range);
+        }
+    }
+
+    // Reserve the memory which failed, making sure nothing ever uses it
+    let mut summary = String::new();
+    for range in bad.entries() {
+        let _ = write!(summary, "Bad memory at {:#018x}-{:#018x}\n",
+                       range.start, range.end);
+        physmap::reserve("bad memory", RegionType::Memory,
+                         PhysAddr(range.start), range.end - range.start + 1);
+    }
+
+    if TRUNCATED.load(Ordering::SeqCst) {
+        let _ = write!(summary, "Truncated, too many bad ranges, nearby bad \
+                                 ranges were merged\n");
+    }
+    let _ = write!(summary, "Memory test done, {} MiB good, {} KiB bad\n",
+                   good.sum().unwrap_or(0) / (1024 * 1024),
+                   bad.sum().unwrap_or(0) / 1024);
+
+    COMPLETE.store(true, Ordering::SeqCst);
+    print!("[{:16.8}] {}", time::uptime(), summary);
+
+    Some(summary)
+}
@@ -0,0 +1,238 @@
+//! Early boot Intel microcode update loading
+//!
+//! The bootloader optionally downloads `chocolate_milk.ucode` which may
+//! contain one or more concatenated Intel microcode updates (the same format
+//! as the files in Intel's `intel-ucode` directory). Every core looks for an
+//! update matching its processor signature and platform and applies it if it
+//! is newer than the currently loaded revision.
+
+use core::mem::size_of;
+use core::convert::TryInto;
+use core::sync::atomic::Ordering;
+use alloc::vec::Vec;
+
+use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+
+/// MSR used to trigger a microcode update, written with the linear address of
+/// the update data
+const IA32_BIOS_UPDT_TRIG: u32 = 0x79;
+
+/// MSR holding the currently loaded microcode revision in the high 32-bits
+const IA32_BIOS_SIGN_ID: u32 = 0x8b;
+
+/// MSR holding the platform ID in bits 52:50
+const IA32_PLATFORM_ID: u32 = 0x17;
+
+/// Size of a microcode update header, in bytes
+const HEADER_SIZE: usize = 48;
+
+/// Size of the update data if the header reports a data size of zero
+const DEFAULT_DATA_SIZE: usize = 2000;
+
+/// Size of the entire update if the header reports a total size of zero
+const DEFAULT_TOTAL_SIZE: usize = 2048;
+
+/// Size of the extended signature table header, in bytes
+const EXT_TABLE_HEADER_SIZE: usize = 20;
+
+/// Size of an extended signature table entry, in bytes
+const EXT_SIGNATURE_SIZE: usize = 12;
+
+/// A parsed and validated microcode update
+struct Update<'a> {
+    /// Revision of the microcode contained in this update
+    revision: u32,
+
+    /// The entire raw update, including the header
+    raw: &'a [u8],
+}
+
+/// Read a little endian `u32` from `bytes` at `offset`
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset.checked_add(size_of::<u32>())?)?
+        .try_into().ok()?))
+}
+
+/// Compute the 32-bit sum of the `u32`s in `bytes`, a valid region sums to 0
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.chunks_exact(size_of::<u32>()).fold(0u32, |acc, x| {
+        acc.wrapping_add(u32::from_le_bytes(x.try_into().unwrap()))
+    })
+}
+
+/// Parse the update at the start of `blob`. Returns the size of the update
+/// and the update itself if it applies to a processor with the `signature`
+/// and `platform`. Returns `None` if the update is malformed, in which case
+/// nothing after it can be parsed either.
+fn parse_update(blob: &[u8], signature: u32, platform: u32)
+        -> Option<(usize, Option<Update>)> {
+    let header = blob.get(..HEADER_SIZE)?;
+
+    // Parse the header fields we care about
+    let header_version = read_u32(header,  0)?;
+    let revision       = read_u32(header,  4)?;
+    let update_sig     = read_u32(header, 12)?;
+    let update_flags   = read_u32(header, 24)?;
+    let data_size      = read_u32(header, 28)? as usize;
+    let total_size     = read_u32(header, 32)? as usize;
+
+    // We only support version 1 headers
+    if header_version != 1 { return None; }
+
+    // Zero sizes indicate the legacy fixed-size update
+    let (data_size, total_size) = if data_size == 0 {
+        (DEFAULT_DATA_SIZE, DEFAULT_TOTAL_SIZE)
+    } else {
+        (data_size, total_size)
+    };
+
+    // Make sure the sizes are sane
+    if total_size < data_size.checked_add(HEADER_SIZE)? ||
+            total_size % size_of::<u32>() != 0 {
+        return None;
+    }
+
+    // Get the entire update
+    let raw = blob.get(..total_size)?;
+
+    // Validate the checksum of the header and data
+    if checksum(&raw[..HEADER_SIZE + data_size]) != 0 {
+        return Some((total_size, None));
+    }
+
+    // Check if the primary signature matches this processor
+    let mut matches = update_sig == signature &&
+        (update_flags & platform) != 0;
+
+    // Check the extended signature table, if present
+    let ext = &raw[HEADER_SIZE + data_size..];
+    if !matches && ext.len() >= EXT_TABLE_HEADER_SIZE &&
+            checksum(ext) == 0 {
+        let count = read_u32(ext, 0)? as usize;
+        for ii in 0..count {
+            let entry = EXT_TABLE_HEADER_SIZE + ii * EXT_SIGNATURE_SIZE;
+            let ext_sig   = read_u32(ext, entry + 0)?;
+            let ext_flags = read_u32(ext, entry + 4)?;
+
+            if ext_sig == signature && (ext_flags & platform) != 0 {
+                matches = true;
+                break;
+            }
+        }
+    }
+
+    if !matches {
+        return Some((total_size, None));
+    }
+
+    Some((total_size, Some(Update { revision, raw })))
+}
+
+/// Find the newest update in `blob` which applies to a processor with the
+/// `signature` (CPUID leaf 1 EAX) and `platform` (platform ID bit). Parsing
+/// stops at the first malformed update, keeping what was found before it.
+fn find_update(blob: &[u8], signature: u32, platform: u32)
+        -> Option<Update> {
+    // Tracks the newest matching update
+    let mut best: Option<Update> = None;
+
+    // Current offset into the blob
+    let mut offset = 0usize;
+
+    while offset < blob.len() {
+        let (size, update) =
+                match parse_update(&blob[offset..], signature, platform) {
+            Some(x) => x,
+            None    => break,
+        };
+
+        // Go to the next update
+        offset += size;
+
+        // Save this update if it applies and is the newest we've seen
+        if let Some(update) = update {
+            if best.as_ref().map(|x| update.revision > x.revision)
+                    .unwrap_or(true) {
+                best = Some(update);
+            }
+        }
+    }
+
+    best
+}
+
+/// Get the revision of the currently loaded microcode
+fn current_revision() -> u32 {
+    unsafe {
+        // Clear the signature MSR, execute a CPUID leaf 1 to populate it, and
+        // read the loaded revision
+        cpu::wrmsr(IA32_BIOS_SIGN_ID, 0);
+        cpu::cpuid(1, 0);
+        (cpu::rdmsr(IA32_BIOS_SIGN_ID) >> 32) as u32
+    }
+}
+
+/// Apply the microcode update provided by the bootloader to the current core,
+/// if there is one which is newer than what is already loaded.
+///
+/// This must happen prior to CPU feature detection as a microcode update can
+/// change the features reported by CPUID.
+pub unsafe fn init() {
+    // Get the blob downloaded by the bootloader
+    let addr = core!().boot_args.microcode_addr.load(Ordering::SeqCst);
+    let size = core!().boot_args.microcode_size.load(Ordering::SeqCst);
+    if addr == 0 || size == 0 {
+        return;
+    }
+
+    // Only Intel microcode updates are supported
+    let (_, ebx, ecx, edx) = cpu::cpuid(0, 0);
+    if (ebx, edx, ecx) != (u32::from_le_bytes(*b"Genu"),
+                           u32::from_le_bytes(*b"ineI"),
+                           u32::from_le_bytes(*b"ntel")) {
+        return;
+    }
+
+    // Make sure the blob is in our physical window
+    let end = size.checked_sub(1).and_then(|x| x.checked_add(addr))
+        .expect("Integer overflow on microcode range");
+    assert!(end < KERNEL_PHYS_WINDOW_SIZE,
+        "Microcode outside of physical window");
+
+    // Get access to the blob
+    let blob = core::slice::from_raw_parts(
+        (KERNEL_PHYS_WINDOW_BASE + addr) as *const u8, size as usize);
+
+    // Get the signature and platform of this processor
+    let signature = cpu::cpuid(1, 0).0;
+    let platform  = 1 << ((cpu::rdmsr(IA32_PLATFORM_ID) >> 50) & 7);
+
+    // Find an update for this processor
+    let update = if let Some(update) = find_update(blob, signature, platform) {
+        update
+    } else {
+        return;
+    };
+
+    // Don't apply the update if we already have this or a newer revision
+    let before = current_revision();
+    if update.revision as i32 <= before as i32 {
+        return;
+    }
+
+    // The update data must be 16-byte aligned, thus copy it into a fresh
+    // allocation. Our allocator always gives out page aligned allocations.
+    let aligned: Vec<u8> = update.raw.to_vec();
+    let data = aligned.as_ptr() as u64 + HEADER_SIZE as u64;
+    assert!((data & 0xf) == 0, "Microcode update data not 16-byte aligned");
+
+    // Apply the update!
+    cpu::wrmsr(IA32_BIOS_UPDT_TRIG, data);
+
+    // Log the revision change
+    let after = current_revision();
+    print!("Core {:4} microcode revision {:#x} -> {:#x}\n",
+           core!().id, before, after);
+    assert!(after == update.revision, "Microcode update failed to apply");
+}
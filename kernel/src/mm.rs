@@ -9,12 +9,12 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 
 use crate::acpi::MAX_CORES;
+use crate::physmap::RegionType;
 
 use rangeset::Range;
 use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
-use boot_args::KERNEL_VMEM_BASE;
 use page_table::{PhysMem, PhysAddr, PageType, VirtAddr};
-use page_table::{PAGE_PRESENT, PAGE_WRITE, PAGE_NX};
+use page_table::{PAGE_PRESENT, PAGE_WRITE, PAGE_NX, PAGE_CACHE_DISABLE};
 
 /// Table which is indexed by an APIC identifier to map to a physical range
 /// which is local to it its NUMA node
@@ -65,8 +65,9 @@ pub unsafe fn register_numa_nodes(apic_to_domain: BTreeMap<u32, u32>,
 ///
 /// This is only valid for virtual requests for 4 KiB mappings
 pub fn alloc_virt_addr_4k(size: u64) -> VirtAddr {
-    /// Base address for virtual allocations
-    static NEXT_FREE_VADDR: AtomicU64 = AtomicU64::new(KERNEL_VMEM_BASE);
+    /// Base address for virtual allocations. Zero until the first allocation
+    /// picks up the randomized base from the bootloader.
+    static NEXT_FREE_VADDR: AtomicU64 = AtomicU64::new(0);
 
     /// Gap between virtual allocations
     const GUARD_PAGE_SIZE: u64 = 32 * 1024;
@@ -79,6 +80,12 @@ pub fn alloc_virt_addr_4k(size: u64) -> VirtAddr {
     let reserve_size = GUARD_PAGE_SIZE.checked_add(size as u64)
         .expect("Integer overflow on virtual region size");
     
+    // Initialize the base of virtual allocations if this is the first
+    // allocation. If we lose the race, someone else already initialized it.
+    let _ = NEXT_FREE_VADDR.compare_exchange(0,
+        core!().boot_args.vmem_base.load(Ordering::SeqCst),
+        Ordering::SeqCst, Ordering::SeqCst);
+
     // Get a new virtual region that is free
     let ret = VirtAddr(
         NEXT_FREE_VADDR.fetch_add(reserve_size, Ordering::SeqCst)
@@ -92,6 +99,41 @@ pub fn alloc_virt_addr_4k(size: u64) -> VirtAddr {
     ret
 }
 
+/// Map `size` bytes of MMIO space at physical address `paddr` into virtual
+/// memory as uncacheable, readable, and writable memory. The region is
+/// reserved for `name` in the physical address space. Returns the virtual
+/// address of the mapping.
+pub fn map_mmio(name: &'static str, paddr: PhysAddr, size: u64) -> VirtAddr {
+    assert!((paddr.0 & 0xfff) == 0 && size > 0 && (size & 0xfff) == 0,
+        "Invalid MMIO region for mapping");
+
+    // Make sure nobody else is using this region
+    crate::physmap::reserve(name, RegionType::Mmio, paddr, size);
+
+    // Get a virtual address capable of holding the mapping
+    let vaddr = alloc_virt_addr_4k(size);
+
+    // Get access to physical memory allocations
+    let mut pmem = PhysicalMemory;
+
+    // Get access to the current page table
+    let mut page_table = core!().boot_args.page_table.lock();
+    let page_table = page_table.as_mut().unwrap();
+
+    // Map in each page of the MMIO space
+    for offset in (0..size).step_by(4096) {
+        unsafe {
+            page_table.map_raw(&mut pmem, VirtAddr(vaddr.0 + offset),
+                               PageType::Page4K,
+                               (paddr.0 + offset) | PAGE_NX | PAGE_WRITE |
+                               PAGE_CACHE_DISABLE | PAGE_PRESENT)
+                .expect("Failed to map in MMIO to virtual memory");
+        }
+    }
+
+    vaddr
+}
+
 /// Read a physical address containing a type `T`. This just handles the
 /// windowing and performs a `core::ptr::read_volatile`.
 #[allow(dead_code)]
@@ -118,6 +160,35 @@ pub unsafe fn write_phys<T>(paddr: PhysAddr, val: T) {
         (KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut T, val);
 }
 
+/// Pattern freed physical memory is filled with when the `poison_free`
+/// feature is enabled. This is a non-canonical address, thus dereferencing a
+/// stale pointer read from freed memory faults.
+const FREE_POISON: u64 = 0xdead_f4ee_dead_f4ee;
+
+/// Number of freed 4 KiB pages held in each core's quarantine before they
+/// can be reused when the `poison_free` feature is enabled
+#[cfg(feature = "poison_free")]
+const QUARANTINE_PAGES: usize = 1024;
+
+/// Fill `size` bytes of physical memory at `paddr` with `FREE_POISON`
+unsafe fn poison(paddr: PhysAddr, size: u64) {
+    for offset in (0..size & !7).step_by(size_of::<u64>()) {
+        write_phys(PhysAddr(paddr.0 + offset), FREE_POISON);
+    }
+}
+
+/// Make sure the 4 KiB page at `paddr` still contains the poison it was
+/// filled with when it was freed, panicking if it was written to
+#[cfg(feature = "poison_free")]
+unsafe fn check_poison(paddr: PhysAddr) {
+    for offset in (0..4096).step_by(size_of::<u64>()) {
+        let val: u64 = read_phys(PhysAddr(paddr.0 + offset));
+        assert!(val == FREE_POISON,
+            "Use after free: freed page {:#x} written at offset {:#x} \
+             with {:#x}", paddr.0, offset, val);
+    }
+}
+
 /// The metadata on a freed page present in the free list. We don't just
 /// directly link the pages together, instead we use the entire 4 KiB of the
 /// freed page to hold a list of pages. This _significantly_ reduces the
@@ -157,13 +228,49 @@ impl FreeListNode {
 pub struct PageFreeList {
     /// Physical address of the first entry in the free list
     head: PhysAddr,
+
+    /// Ring of recently freed pages which are not yet available for reuse
+    #[cfg(feature = "poison_free")]
+    quarantine: [PhysAddr; QUARANTINE_PAGES],
+
+    /// Index of the oldest page in the `quarantine`
+    #[cfg(feature = "poison_free")]
+    quarantine_idx: usize,
 }
 
 impl PageFreeList {
     /// Create a new, empty free list
     pub fn new() -> Self {
         assert!(size_of::<FreeListNode>() == 4096);
-        PageFreeList { head: PhysAddr(0) }
+        PageFreeList {
+            head: PhysAddr(0),
+
+            #[cfg(feature = "poison_free")]
+            quarantine: [PhysAddr(0); QUARANTINE_PAGES],
+
+            #[cfg(feature = "poison_free")]
+            quarantine_idx: 0,
+        }
+    }
+
+    /// Put a poisoned page into the quarantine, evicting the oldest page in
+    /// the quarantine. If the evicted page is still intact it is returned
+    /// such that it can be put back on the free list.
+    #[cfg(feature = "poison_free")]
+    unsafe fn quarantine(&mut self, page: PhysAddr) -> Option<PhysAddr> {
+        // Swap the page in place of the oldest page
+        let old = core::mem::replace(
+            &mut self.quarantine[self.quarantine_idx], page);
+        self.quarantine_idx = (self.quarantine_idx + 1) % QUARANTINE_PAGES;
+
+        // The quarantine was not full yet
+        if old == PhysAddr(0) {
+            return None;
+        }
+
+        // Make sure nobody used the page while it was freed
+        check_poison(old);
+        Some(old)
     }
 
     /// Get a page from the free list
@@ -287,9 +394,24 @@ impl PhysMem for PhysicalMemory {
     }
 
     fn free_phys(&mut self, phys: PhysAddr, size: u64) {
+        // Poison the freed memory such that uses after free are noticed
+        if cfg!(feature = "poison_free") {
+            unsafe { poison(phys, size); }
+        }
+
         if (phys.0 & 0xfff) == 0 && size == 4096 {
             // Get access to the free list
-            unsafe { core!().free_list.lock().push(phys); }
+            let mut free_list = core!().free_list.lock();
+
+            // Hold the page in quarantine for a while before it can be
+            // reused, freeing the oldest quarantined page instead
+            #[cfg(feature = "poison_free")]
+            let phys = match unsafe { free_list.quarantine(phys) } {
+                Some(old) => old,
+                None      => return,
+            };
+
+            unsafe { free_list.push(phys); }
         } else {
             // Compute the end address
             let end = size.checked_sub(1).and_then(|x| {
@@ -304,6 +426,128 @@ impl PhysMem for PhysicalMemory {
     }
 }
 
+/// Byte pattern the unused space between the end of a heap allocation and its
+/// `AllocTrailer` is filled with when the `heap_canaries` feature is enabled
+#[cfg(feature = "heap_canaries")]
+const CANARY: u8 = 0xcb;
+
+/// Minimum number of canary bytes after every heap allocation when the
+/// `heap_canaries` feature is enabled
+#[cfg(feature = "heap_canaries")]
+const CANARY_MIN: usize = 16;
+
+/// Magic value identifying an `AllocTrailer`
+#[cfg(feature = "heap_canaries")]
+const TRAILER_MAGIC: u64 = 0x4865_6170_5472_6c72;
+
+/// Number of heap allocations made, used to identify allocations in reports
+#[cfg(feature = "heap_canaries")]
+static ALLOC_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Information about a heap allocation, stored at the end of the last page of
+/// the allocation when the `heap_canaries` feature is enabled
+#[cfg(feature = "heap_canaries")]
+#[repr(C)]
+struct AllocTrailer {
+    /// Set to `TRAILER_MAGIC`
+    magic: u64,
+
+    /// Address of the allocation
+    base: u64,
+
+    /// Size of the allocation in bytes, as requested
+    size: u64,
+
+    /// Core which made the allocation
+    core_id: u64,
+
+    /// Return address of the call to the allocator which made the allocation,
+    /// including the KASLR slide
+    caller: u64,
+
+    /// Value of `ALLOC_SEQUENCE` when the allocation was made
+    sequence: u64,
+}
+
+/// Get the number of bytes of virtual memory backing a heap allocation of
+/// `layout`, which is always a multiple of 4 KiB
+fn heap_size(layout: Layout) -> Option<u64> {
+    let size = layout.size();
+
+    // Make room for the canaries and the trailer
+    #[cfg(feature = "heap_canaries")]
+    let size = size.checked_add(CANARY_MIN + size_of::<AllocTrailer>())?;
+
+    Some((size.checked_add(0xfff)? & !0xfff) as u64)
+}
+
+/// Fill the end of the new heap allocation at `ptr` with canaries and its
+/// trailer, noting that the allocation was made by `caller`
+#[cfg(feature = "heap_canaries")]
+unsafe fn write_canaries(ptr: *mut u8, layout: Layout, alignsize: u64,
+                         caller: u64) {
+    let trailer = alignsize as usize - size_of::<AllocTrailer>();
+    core::ptr::write_bytes(ptr.add(layout.size()), CANARY,
+                           trailer - layout.size());
+
+    core::ptr::write(ptr.add(trailer) as *mut AllocTrailer, AllocTrailer {
+        magic:    TRAILER_MAGIC,
+        base:     ptr as u64,
+        size:     layout.size() as u64,
+        core_id:  core!().id as u64,
+        caller,
+        sequence: ALLOC_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+    });
+}
+
+/// Make sure `ptr` is a live heap allocation of `layout`, and that nothing
+/// was written past its end, panicking otherwise
+#[cfg(feature = "heap_canaries")]
+unsafe fn check_canaries(ptr: *mut u8, layout: Layout, alignsize: u64) {
+    let base = ptr as u64;
+    assert!(base & 0xfff == 0,
+            "Invalid free of {:#x}, not a heap allocation", base);
+
+    // Virtual addresses are never reused, thus once an allocation is freed
+    // it stays unmapped. The lock must be released before we can panic.
+    let mapped = {
+        let mut pmem   = PhysicalMemory;
+        let page_table = core!().boot_args.page_table.lock();
+        let page_table = page_table.as_ref().unwrap();
+        [base, base + alignsize - 4096].iter().all(|&vaddr| {
+            page_table.translate(&mut pmem, VirtAddr(vaddr))
+                .and_then(|x| x.page).is_some()
+        })
+    };
+    assert!(mapped, "Double free or invalid free of {:#x} ({} bytes)",
+            base, layout.size());
+
+    // Make sure the trailer belongs to this allocation
+    let trailer = alignsize as usize - size_of::<AllocTrailer>();
+    let trailer = &*(ptr.add(trailer) as *const AllocTrailer);
+    assert!(trailer.magic == TRAILER_MAGIC && trailer.base == base,
+            "Invalid free of {:#x}, not the start of a heap allocation", base);
+
+    // Get the address the allocation was made from without the KASLR slide,
+    // such that it can be looked up in the kernel image
+    let caller = trailer.caller.wrapping_sub(
+        core!().boot_args.kernel_slide.load(Ordering::SeqCst));
+
+    assert!(trailer.size == layout.size() as u64,
+            "Free of {:#x} as {} bytes, but it was allocated as {} bytes \
+             from {:#x}", base, layout.size(), trailer.size, caller);
+
+    // Check the canaries
+    let canaries = core::slice::from_raw_parts(ptr.add(layout.size()),
+        alignsize as usize - size_of::<AllocTrailer>() - layout.size());
+    if let Some(offset) = canaries.iter().position(|&x| x != CANARY) {
+        panic!("Heap overflow: allocation #{} of {} bytes at {:#x} from \
+                core {} at {:#x} overwritten at offset {:#x} with {:#x}",
+               trailer.sequence, trailer.size, base, trailer.core_id, caller,
+               layout.size() + offset, canaries[offset]);
+    }
+}
+
 /// The global allocator for the bootloader, this just uses physical memory as
 /// a backing and does not handle any fancy things like fragmentation. Use this
 /// carefully.
@@ -321,10 +565,12 @@ impl GlobalAllocator {
     /// constructed with new pages.
     ///
     /// Returns `None` if the allocation failed, otherwise it returns a pointer
-    /// to the base of the allocation.
-    unsafe fn opt_alloc(&self, layout: Layout) -> Option<*mut u8> {
-        // 4-KiB align up the allocation size
-        let alignsize = (layout.size().checked_add(0xfff)? & !0xfff) as u64;
+    /// to the base of the allocation. `caller` is the return address of the
+    /// allocation, recorded in the trailer with the `heap_canaries` feature.
+    unsafe fn opt_alloc(&self, layout: Layout, caller: u64)
+            -> Option<*mut u8> {
+        // Get the size of virtual memory needed for the allocation
+        let alignsize = heap_size(layout)?;
 
         // Get a unique virtual address for this allocation
         let vaddr = alloc_virt_addr_4k(alignsize);
@@ -340,6 +586,12 @@ impl GlobalAllocator {
         page_table.map(&mut pmem, vaddr, PageType::Page4K,
             alignsize, true, true, false)?;
 
+        #[cfg(feature = "heap_canaries")]
+        write_canaries(vaddr.0 as *mut u8, layout, alignsize, caller);
+
+        #[cfg(not(feature = "heap_canaries"))]
+        let _ = caller;
+
         // Allocation success, `vaddr` now is valid as read-write for
         // `alignsize` bytes!
         Some(vaddr.0 as *mut u8)
@@ -348,16 +600,23 @@ impl GlobalAllocator {
 
 unsafe impl GlobalAlloc for GlobalAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.opt_alloc(layout).unwrap_or(core::ptr::null_mut())
+        // Note where the allocation was made from, `#[track_caller]` can't
+        // be used on `GlobalAlloc`
+        let caller = core::intrinsics::return_address() as u64;
+
+        self.opt_alloc(layout, caller).unwrap_or(core::ptr::null_mut())
     }
     
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         // Get access to physical memory
         let mut pmem = PhysicalMemory;
 
-        // 4-KiB align up the allocation size
-        let alignsize =
-            (layout.size().checked_add(0xfff).unwrap() & !0xfff) as u64;
+        // Get the size of virtual memory backing the allocation
+        let alignsize = heap_size(layout).unwrap();
+
+        // Make sure this is a valid free of an allocation which is intact
+        #[cfg(feature = "heap_canaries")]
+        check_canaries(ptr, layout, alignsize);
 
         // Get access to virtual memory
         let mut page_table = core!().boot_args.page_table.lock();
@@ -0,0 +1,97 @@
+//! Typed volatile access to memory mapped I/O
+//!
+//! Devices describe their register blocks as `Register` constants, which hold
+//! the byte offset of a register into MMIO space along with the type of the
+//! register. All accesses through an `Mmio` mapping are volatile, bounds and
+//! alignment checked, and ordered against normal memory accesses such that
+//! memory written before a register write (eg. a DMA descriptor before
+//! bumping a tail pointer) is visible to the device first.
+
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+use page_table::{PhysAddr, VirtAddr};
+
+/// Marker for types which can be used as the value of an MMIO register. Only
+/// types which the hardware can access in a single naturally aligned access
+/// may implement this.
+pub unsafe trait RegisterType: Copy {}
+unsafe impl RegisterType for u8  {}
+unsafe impl RegisterType for u16 {}
+unsafe impl RegisterType for u32 {}
+unsafe impl RegisterType for u64 {}
+
+/// A register holding a `T`, located at a fixed byte offset into an MMIO
+/// region
+#[derive(Clone, Copy)]
+pub struct Register<T> {
+    /// Offset of the register in bytes from the start of MMIO space
+    offset: usize,
+
+    /// The type of the register
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Register<T> {
+    /// Create a new register definition at byte `offset` into MMIO space
+    pub const fn new(offset: usize) -> Self {
+        Register { offset, _phantom: PhantomData }
+    }
+
+    /// Get the offset of the register in bytes
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// A mapping of MMIO space into virtual memory
+pub struct Mmio {
+    /// Virtual address of the start of the mapping
+    base: VirtAddr,
+
+    /// Size of the mapping in bytes
+    size: usize,
+}
+
+impl Mmio {
+    /// Map `size` bytes of MMIO space at physical address `paddr` into
+    /// uncacheable virtual memory, reserving it for `name`
+    pub fn map(name: &'static str, paddr: PhysAddr, size: u64) -> Self {
+        Mmio {
+            base: crate::mm::map_mmio(name, paddr, size),
+            size: size as usize,
+        }
+    }
+
+    /// Get the virtual address of `register`, making sure it is in bounds of
+    /// the mapping and naturally aligned
+    fn address<T: RegisterType>(&self, register: Register<T>) -> usize {
+        assert!(register.offset % size_of::<T>() == 0 &&
+                register.offset.checked_add(size_of::<T>())
+                    .map(|end| end <= self.size) == Some(true),
+            "Invalid MMIO register access at offset {:#x}", register.offset);
+
+        self.base.0 as usize + register.offset
+    }
+
+    /// Read the value of `register`
+    ///
+    /// Memory accesses after the read will not be performed before it, thus
+    /// a status read can be used to gate reads of DMA memory.
+    pub unsafe fn read<T: RegisterType>(&self, register: Register<T>) -> T {
+        let val = core::ptr::read_volatile(self.address(register) as *const T);
+        fence(Ordering::Acquire);
+        val
+    }
+
+    /// Write `val` to `register`
+    ///
+    /// Memory accesses before the write will be visible before the device
+    /// observes it, thus this can be used to hand DMA memory to a device.
+    pub unsafe fn write<T: RegisterType>(&self, register: Register<T>,
+                                         val: T) {
+        fence(Ordering::Release);
+        core::ptr::write_volatile(self.address(register) as *mut T, val);
+    }
+}
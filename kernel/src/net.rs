@@ -2,21 +2,107 @@
 
 use core::fmt::{self, Formatter, Debug};
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use core::ops::{Deref, DerefMut};
+use core::mem::ManuallyDrop;
+use alloc::vec::Vec;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::collections::{BTreeMap, VecDeque};
 use crate::pci::Device;
-use crate::mm::PhysContig;
+use crate::dma::DmaBuffer;
+use crate::acpi::MAX_CORES;
 use crate::core_locals::LockInterrupts;
+use crate::tunables::Tunable;
+use crate::ipfrag::{self, Reassembler};
 use lockcell::LockCell;
-use page_table::PhysAddr;
+use histogram::{Histogram, Summary};
 
 /// IPv4 ethernet frame type
 const ETHTYPE_IPV4: u16 = 0x0800;
 
+/// ARP ethernet frame type
+const ETHTYPE_ARP: u16 = 0x0806;
+
+/// ICMP protocol for the IP header
+const IPPROTO_ICMP: u8 = 0x01;
+
 /// UDP protocol for the IP header
 const IPPROTO_UDP: u8 = 0x11;
 
+/// ARP opcode for a request
+const ARP_REQUEST: u16 = 1;
+
+/// ARP opcode for a reply
+const ARP_REPLY: u16 = 2;
+
+/// ICMP type for an echo reply
+const ICMP_ECHO_REPLY: u8 = 0;
+
+/// ICMP type for an echo request
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Minimum time between checks of the link state of a device, in
+/// microseconds
+pub static LINK_POLL_INTERVAL: Tunable =
+    Tunable::new("link_poll_interval", 100_000);
+
+/// Number of packets which can be queued for transmit on a core before they
+/// are handed to the driver
+pub static TX_BATCH_SIZE: Tunable = Tunable::new("net_tx_batch_size", 16);
+
+/// Default MTU of network devices, used if DHCP does not provide one
+pub static MTU: Tunable = Tunable::new("mtu", 1500);
+
+/// Largest MTU supported by the stack (jumbo frames)
+pub const MAX_MTU: usize = 9000;
+
+/// Smallest MTU which every IPv4 host must support
+const MIN_MTU: usize = 68;
+
+/// MTU of standard, non-jumbo, ethernet frames
+pub const ETHERNET_MTU: usize = 1500;
+
+/// Size of the buffer backing each packet. This is large enough to hold a
+/// maximum sized jumbo frame, and is a size that NICs can be configured to
+/// receive into.
+pub const PACKET_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Maximum number of freed packet buffers to keep around for reuse. Buffers
+/// beyond this are freed back to the system.
+const PACKET_POOL_SIZE: usize = 256;
+
+/// Size of the ethernet, IP, and UDP headers which are prepended to a UDP
+/// payload
+pub const UDP_HEADROOM: usize = 14 + 20 + 8;
+
+/// Maximum size of an ethernet frame, not including the FCS
+const MAX_FRAME_SIZE: usize = 14 + MAX_MTU;
+
+/// Size of the buffer backing a reassembled IP packet, which holds the
+/// ethernet header and the largest possible IP packet
+const LARGE_PACKET_BUFFER_SIZE: usize = 14 + 65535;
+
+/// Packet buffers which are free for reuse. Packets are recycled rather than
+/// freed such that they don't have to be allocated, and mapped for DMA, again.
+///
+/// Kernel statics survive a soft reboot while the DMA mappings of the buffers
+/// do not, thus the pool must be drained with `drain_pool()` during teardown.
+static PACKET_POOL:
+    LockCell<Vec<DmaBuffer<[u8; PACKET_BUFFER_SIZE]>>, LockInterrupts> =
+    LockCell::new(Vec::new());
+
+/// Ethernet broadcast address
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+/// Number of microseconds to wait for the gateway to reply to a ping during
+/// boot
+const BOOT_PING_TIMEOUT: u64 = 1_000_000;
+
+/// Number of pings sent to the gateway during boot to measure the round trip
+/// time to it
+const BOOT_PINGS: usize = 8;
+
 /// IPv4 address
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(C)]
@@ -37,8 +123,20 @@ impl Debug for Ipv4Addr {
     }
 }
 
-/// UDP bound port
-pub struct UDPBind<'a> {
+/// Maximum number of packets which can be queued on a UDP socket. Packets
+/// which arrive for a socket with a full queue are dropped, such that a socket
+/// which is not being read cannot consume all of the NIC's packets.
+const UDP_QUEUE_DEPTH: usize = 64;
+
+/// Ports used for UDP sockets bound to port 0
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// A UDP socket bound to a port on a network device
+///
+/// Packets are demultiplexed by their destination port, thus a socket only
+/// ever receives packets destined to its port, regardless of which socket
+/// happened to be polling the device when they arrived.
+pub struct UdpSocket<'a> {
     /// Reference to the network device we are a bound on
     device: &'a NetDevice,
 
@@ -46,15 +144,80 @@ pub struct UDPBind<'a> {
     port: u16,
 }
 
-impl<'a> UDPBind<'a> {
-    /// Atetmpt to receive a UDP packet on the bound port
+impl<'a> UdpSocket<'a> {
+    /// Bind a socket to `port` on `device`. If `port` is zero, an unused
+    /// ephemeral port is picked. Returns `None` if the port is already bound.
+    pub fn bind(device: &'a NetDevice, port: u16) -> Option<Self> {
+        // Get access to the UDP binds
+        let mut udp_binds = device.udp_binds.lock();
+
+        // Pick an ephemeral port if requested
+        let port = if port == 0 {
+            EPHEMERAL_PORTS.clone().find(|x| !udp_binds.contains_key(x))?
+        } else {
+            port
+        };
+
+        // Check to see if someone already is listening on this port
+        if udp_binds.contains_key(&port) {
+            return None;
+        }
+
+        // Nobody is listening, allocate a new queue for the socket
+        udp_binds.insert(port, VecDeque::with_capacity(UDP_QUEUE_DEPTH));
+
+        Some(UdpSocket { device, port })
+    }
+
+    /// Get the port this socket is bound to
+    #[allow(dead_code)]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send a UDP packet from the bound port to `dst_port` on `dst_ip`.
+    /// `func` is invoked with an empty packet to append the payload to, and
+    /// the headers are then prepended in place.
+    pub fn send_to<F>(&self, dst_mac: [u8; 6], dst_ip: Ipv4Addr,
+                      dst_port: u16, func: F)
+            where F: FnOnce(&mut Packet) {
+        self.send_to_batched(dst_mac, dst_ip, dst_port, func);
+        self.device.flush();
+    }
+
+    /// Queue a UDP packet for transmit like `send_to()`, without flushing the
+    /// transmit queue of this core. Used to get many packets out in a single
+    /// batch, the caller is responsible for calling `flush()` when done.
+    pub fn send_to_batched<F>(&self, dst_mac: [u8; 6], dst_ip: Ipv4Addr,
+                              dst_port: u16, func: F)
+            where F: FnOnce(&mut Packet) {
+        // Get a packet with room for the headers
+        let mut packet = self.device.allocate_packet();
+        packet.reserve(UDP_HEADROOM);
+
+        // Let the caller fill in the payload
+        func(&mut packet);
+
+        // Put the headers in front of the payload and send it
+        let src_ip = self.device.ip.lock().unwrap_or(Ipv4Addr(0));
+        packet.push_udp_headers(self.device.mac, dst_mac, src_ip, dst_ip,
+                                self.port, dst_port);
+        self.device.send_batched(packet);
+    }
+
+    /// Send all packets queued for transmit on this core
+    pub fn flush(&self) {
+        self.device.flush();
+    }
+
+    /// Attempt to receive a UDP packet on the bound port
     pub fn recv<T, F>(&self, func: F) -> Option<T>
             where F: FnOnce(&Packet, Udp) -> Option<T> {
         self.device.recv_udp(self.port, func)
     }
 }
 
-impl<'a> Drop for UDPBind<'a> {
+impl<'a> Drop for UdpSocket<'a> {
     fn drop(&mut self) {
         // Unbind from the UDP port
         self.device.unbind_udp(self.port);
@@ -70,6 +233,43 @@ pub struct NetDevice {
     /// MAC address for the network card
     mac: [u8; 6],
 
+    /// Capabilities which were negotiated between the driver and the stack
+    capabilities: Capabilities,
+
+    /// Current MTU of the device
+    mtu: AtomicUsize,
+
+    /// Identification to use for the next IP packet we fragment
+    next_ip_id: AtomicUsize,
+
+    /// Reassembly of IP fragments we've received
+    reassembly: LockCell<Reassembler, LockInterrupts>,
+
+    /// Link state as of the last time it was checked
+    link: LockCell<LinkState, LockInterrupts>,
+
+    /// TSC value after which the link state should be checked again
+    next_link_check: AtomicU64,
+
+    /// IP address assigned to this device, once it has been configured
+    ip: LockCell<Option<Ipv4Addr>, LockInterrupts>,
+
+    /// MAC addresses of other hosts on the network, learned from ARP
+    neighbors: LockCell<BTreeMap<Ipv4Addr, [u8; 6]>, LockInterrupts>,
+
+    /// Outstanding pings, keyed by their (identifier, sequence number). The
+    /// value is set to `true` once the echo reply was received.
+    pings: LockCell<BTreeMap<(u16, u16), bool>, LockInterrupts>,
+
+    /// Round trip times of the pings we sent, in microseconds
+    rtt: LockCell<Histogram, LockInterrupts>,
+
+    /// Per-core queues of packets waiting to be transmitted, indexed by core
+    /// ID. These are handed to the driver in batches, such that the driver
+    /// lock is taken and the doorbell is rung once per batch rather than once
+    /// per packet.
+    tx_queues: Vec<LockCell<Vec<Packet>, LockInterrupts>>,
+
     /// Packet queues for bound UDP ports
     ///
     /// When packets are parsed and they're valid UDP packets to existing bound
@@ -78,42 +278,84 @@ pub struct NetDevice {
 }
 
 impl NetDevice {
-    /// Wrap up a driver in a `NetDevice`
-    pub fn new(driver: Box<dyn NetDriver>) -> Self {
+    /// Wrap up a driver in a `NetDevice`, enabling the features which both
+    /// the driver and the stack support
+    pub fn new(mut driver: Box<dyn NetDriver>) -> Self {
+        // Negotiate the capabilities to use and let the driver know
+        let capabilities =
+            driver.capabilities().intersect(STACK_CAPABILITIES);
+        driver.enable(capabilities);
+
         let device = NetDevice {
-            mac: driver.mac(),
-            udp_binds: LockCell::new(BTreeMap::new()),
-            driver: LockCell::new(driver),
+            mac:             driver.mac(),
+            capabilities,
+            link:            LockCell::new(driver.link_state()),
+            next_link_check: AtomicU64::new(0),
+            mtu:             AtomicUsize::new(ETHERNET_MTU),
+            next_ip_id:      AtomicUsize::new(0),
+            reassembly:      LockCell::new(Reassembler::new()),
+            ip:              LockCell::new(None),
+            neighbors:       LockCell::new(BTreeMap::new()),
+            pings:           LockCell::new(BTreeMap::new()),
+            rtt:             LockCell::new(Histogram::new()),
+            tx_queues:       (0..MAX_CORES)
+                .map(|_| LockCell::new(Vec::new())).collect(),
+            udp_binds:       LockCell::new(BTreeMap::new()),
+            driver:          LockCell::new(driver),
         };
 
-        {
-            let lease = crate::dhcp::get_lease(&device);
-            print!("{:#?}\n", lease);
-        }
-
+        // Use the default MTU until we're told otherwise
+        device.set_mtu(MTU.get() as usize);
         device
     }
 
-    /// Bind to listen for all UDP packets destined to `port`
-    pub fn bind_udp<'a, 'b: 'a>(&'b self, port: u16)
-            -> Option<UDPBind<'a>> {
-        // Get access to the UDP binds
-        let mut udp_binds = self.udp_binds.lock();
+    /// Configure the device on the network using DHCP, returning the address
+    /// of the DHCP server if we got a lease
+    pub fn configure(&self) -> Option<Ipv4Addr> {
+        print!("Network device {:x?} | link {:?} | mtu {} | {:?}\n",
+               self.mac, self.link_state(), self.mtu(), self.capabilities());
 
-        // Check to see if someone already is listening on this port
-        if !udp_binds.contains_key(&port) {
-            // Nobody is listening, allocate a new bind queue
-            udp_binds.insert(port, VecDeque::new());
-
-            // Return out the UDP bind
-            Some(UDPBind {
-                device: self,
-                port,
-            })
-        } else {
-            // Someone already is bound to this port
-            None
+        // The first device we configure gives the node its identity
+        let node_id = crate::node::set_from_mac(self.mac);
+        print!("Node ID {:016x}\n", node_id);
+
+        let lease = crate::dhcp::get_lease(self);
+        print!("{:#?}\n", lease);
+
+        if let Some(lease) = lease {
+            // Use the MTU of the network, if the DHCP server told us it
+            if let Some(mtu) = lease.mtu {
+                self.set_mtu(mtu as usize);
+            }
+
+            // Start answering ARP and pings for our address
+            *self.ip.lock() = Some(lease.client_ip);
+
+            // Make sure we can actually reach the network through the
+            // gateway, and measure how far away it is. Without a gateway the
+            // DHCP server is the only host we know is on the network.
+            let (name, target) = match lease.router {
+                Some(router) => ("Gateway", router),
+                None         => ("DHCP server", lease.server_ip),
+            };
+            for _ in 0..BOOT_PINGS {
+                if self.ping(target, BOOT_PING_TIMEOUT).is_none() {
+                    break;
+                }
+            }
+            let rtt = self.rtt();
+            match rtt.count {
+                0 => print!("{} {:?} is not reachable\n", name, target),
+                _ => print!("{} {:?} is reachable, rtt (us) {}\n",
+                            name, target, rtt),
+            }
+
+            // Let the server know we're here and pick up our configuration
+            crate::beacon::announce(self, lease.server_ip, node_id);
+            return Some(lease.server_ip);
         }
+
+        None
     }
 
     /// Unbind from a UDP port
@@ -133,49 +375,282 @@ impl NetDevice {
     /// Receive a UDP packet destined to a specific port
     fn recv_udp<T, F>(&self, port: u16, func: F) -> Option<T>
             where F: FnOnce(&Packet, Udp) -> Option<T> {
+        // Demultiplex everything the driver has received
+        self.poll();
+
+        // Get the next packet queued for this port
+        let packet = self.udp_binds.lock().get_mut(&port).unwrap()
+            .pop_front()?;
+
+        let ret = func(&packet, packet.udp().unwrap());
+        self.driver.lock().release_packet(packet);
+        ret
+    }
+
+    /// Process all packets the driver has received. UDP packets are queued on
+    /// the socket bound to their destination port, ARP and ICMP packets are
+    /// handled by the stack, and everything else is dropped.
+    pub fn poll(&self) {
+        // Handle link changes before touching the datapath
+        self.check_link();
+
+        // Send out anything this core has queued
+        self.flush();
+
         // Get access to the UDP binds
         let mut udp_binds = self.udp_binds.lock();
-        
+
         // Get access to the driver
         let mut driver = self.driver.lock();
 
-        {
-            // Get access to the UDP queue for this port
-            let ent = udp_binds.get_mut(&port).unwrap();
-            if !ent.is_empty() {
-                let packet = ent.pop_front().unwrap();
-                let ret = func(&packet, packet.udp().unwrap());
-                driver.release_packet(packet);
-                return ret;
+        loop {
+            // Take ownership of the next received packet, if any
+            let packet = match driver.recv() {
+                Some(lease) => PacketLease::take(lease),
+                None        => break,
+            };
+
+            // Collect IP fragments until we have the whole packet
+            let packet = match packet.ip_fragment() {
+                Some(frag) if frag.more || frag.offset != 0 => {
+                    let whole = self.reassembly.lock().insert(&packet);
+                    driver.release_packet(packet);
+                    match whole {
+                        Some(whole) => whole,
+                        None        => continue,
+                    }
+                }
+                _ => packet,
+            };
+
+            if let Some(dst_port) = packet.udp().map(|x| x.dst_port) {
+                // Save the packet to the socket bound to the port. If there
+                // is no such socket or its queue is full, the packet is
+                // dropped.
+                match udp_binds.get_mut(&dst_port) {
+                    Some(queue) if queue.len() < UDP_QUEUE_DEPTH => {
+                        queue.push_back(packet);
+                    }
+                    _ => driver.release_packet(packet),
+                }
+                continue;
             }
+
+            // Not UDP, let the stack handle it
+            self.handle_control(&mut **driver, packet);
         }
+    }
 
-        // Recv a packet, it could be any raw packet
-        let packet = driver.recv()?;
-
-        // Attempt to parse the packet as UDP
-        if let Some(udp) = packet.udp() {
-            // Packet was UDP
-            if udp.dst_port == port {
-                func(&*packet, udp)
-            } else {
-                // Wasn't for us, attempt to save it to an existing bind
-                udp_binds.get_mut(&udp.dst_port).map(|x| {
-                    x.push_back(PacketLease::take(packet));
-                });
-                None
+    /// Check if the link state changed since we last looked, and if so bring
+    /// the datapath down or back up. This is rate limited by
+    /// `LINK_POLL_INTERVAL`.
+    fn check_link(&self) {
+        // Check if it's time to look at the link again
+        let now = cpu::rdtsc();
+        if now < self.next_link_check.load(Ordering::Relaxed) {
+            return;
+        }
+        self.next_link_check.store(
+            crate::time::future(LINK_POLL_INTERVAL.get()), Ordering::Relaxed);
+
+        // Get the current link state and update our view of it
+        let state = self.driver.lock().link_state();
+        let old = core::mem::replace(&mut *self.link.lock(), state);
+        if old == state {
+            return;
+        }
+
+        print!("[{:16.8}] Network device {:x?} link {:?} -> {:?}\n",
+               crate::time::uptime(), self.mac, old, state);
+
+        // Nothing to do until the link comes back up
+        if state == LinkState::Down {
+            return;
+        }
+
+        // The link came up or changed speed. The hardware may have dropped
+        // or stalled its queues while the link was unstable, thus start over
+        // with a fresh datapath.
+        self.driver.lock().reset();
+
+        // We may have moved to a different part of the network, so forget
+        // what we knew about our neighbors
+        self.neighbors.lock().clear();
+
+        // Announce our address with a gratuitous ARP such that others update
+        // their caches to the new location
+        if let Some(our_ip) = *self.ip.lock() {
+            let mut packet = self.allocate_packet();
+            packet.create_arp(ARP_REQUEST, self.mac, our_ip,
+                              BROADCAST_MAC, [0; 6], our_ip);
+            self.send(packet);
+        }
+    }
+
+    /// Handle ARP and ICMP packets, replying to them in place if needed
+    fn handle_control(&self, driver: &mut dyn NetDriver, mut packet: Packet) {
+        // Get our IP address, if we have one
+        let our_ip = *self.ip.lock();
+
+        if let Some(arp) = packet.arp() {
+            // Learn the MAC address of the sender
+            let (sender_mac, sender_ip) = (arp.sender_mac, arp.sender_ip);
+            if u32::from(sender_ip) != 0 {
+                self.neighbors.lock().insert(sender_ip, sender_mac);
+            }
+
+            // Reply to requests for our address
+            if let Some(our_ip) = our_ip {
+                if arp.opcode == ARP_REQUEST && arp.target_ip == our_ip {
+                    packet.create_arp(ARP_REPLY, self.mac, our_ip,
+                                      sender_mac, sender_mac, sender_ip);
+                    driver.send(packet);
+                    return;
+                }
+            }
+        } else if let Some(icmp) = packet.icmp() {
+            let id  = (icmp.header >> 16) as u16;
+            let seq = (icmp.header >>  0) as u16;
+
+            match icmp.typ {
+                ICMP_ECHO_REQUEST if Some(icmp.ip.dst_ip) == our_ip => {
+                    // Reply to the ping using the same packet
+                    let data     = icmp.payload.to_vec();
+                    let src_ip   = icmp.ip.src_ip;
+                    let src_mac  = icmp.ip.eth.src_mac;
+                    packet.create_icmp_echo(self.mac, src_mac,
+                                            our_ip.unwrap(), src_ip,
+                                            ICMP_ECHO_REPLY, id, seq, &data);
+                    driver.send(packet);
+                    return;
+                }
+                ICMP_ECHO_REPLY => {
+                    // Complete the ping if it's one of ours
+                    if let Some(done) = self.pings.lock().get_mut(&(id, seq)) {
+                        *done = true;
+                    }
+                }
+                _ => {}
             }
-        } else {
-            // Packet was not UDP, drop it
-            None
         }
+
+        driver.release_packet(packet);
     }
-    
+
+    /// Get the MAC address for `ip`, sending ARP requests for up to `timeout`
+    /// microseconds if it is not yet known
+    pub fn resolve(&self, ip: Ipv4Addr, timeout: u64) -> Option<[u8; 6]> {
+        // We need an address to send ARP requests from
+        let our_ip = (*self.ip.lock())?;
+
+        let deadline = crate::time::future(timeout);
+        loop {
+            // Check if we already know the MAC address
+            if let Some(&mac) = self.neighbors.lock().get(&ip) {
+                return Some(mac);
+            }
+
+            if cpu::rdtsc() >= deadline {
+                return None;
+            }
+
+            // Ask who has the address
+            let mut packet = self.allocate_packet();
+            packet.create_arp(ARP_REQUEST, self.mac, our_ip,
+                              BROADCAST_MAC, [0; 6], ip);
+            self.send(packet);
+
+            // Give the host some time to reply
+            let retry = crate::time::future(timeout / 4);
+            while cpu::rdtsc() < retry && cpu::rdtsc() < deadline &&
+                    !self.neighbors.lock().contains_key(&ip) {
+                self.poll();
+            }
+        }
+    }
+
+    /// Send an ICMP echo request to `ip` and wait up to `timeout`
+    /// microseconds for the reply. Returns the round trip time in seconds if
+    /// a reply was received.
+    pub fn ping(&self, ip: Ipv4Addr, timeout: u64) -> Option<f64> {
+        // Data carried in the ping
+        const DATA: &[u8] = b"chocolate milk ping";
+
+        let deadline = crate::time::future(timeout);
+
+        // Get the addresses to send the ping from and to
+        let our_ip  = (*self.ip.lock())?;
+        let dst_mac = self.resolve(ip, timeout)?;
+
+        // Register the ping with a "unique" identifier
+        let key = (cpu::rdtsc() as u16, 0);
+        self.pings.lock().insert(key, false);
+
+        // Send the echo request
+        let start = cpu::rdtsc();
+        let mut packet = self.allocate_packet();
+        packet.create_icmp_echo(self.mac, dst_mac, our_ip, ip,
+                                ICMP_ECHO_REQUEST, key.0, key.1, DATA);
+        self.send(packet);
+
+        // Wait for the reply
+        let mut rtt = None;
+        while cpu::rdtsc() < deadline {
+            self.poll();
+            if self.pings.lock().get(&key) == Some(&true) {
+                let elapsed = crate::time::rdtsc_elapsed(start);
+                self.rtt.lock().record((elapsed * 1_000_000.) as u64);
+                rtt = Some(elapsed);
+                break;
+            }
+        }
+
+        self.pings.lock().remove(&key);
+        rtt
+    }
+
     /// Send a raw frame over the network containing the bytes `packet`. This
     /// `packet` does not include the FCS, that must be computed or inserted
     /// by the driver.
+    ///
+    /// Any packets queued on this core are sent first, in the same batch.
     pub fn send(&self, packet: Packet) {
-        self.driver.lock().send(packet);
+        self.send_batched(packet);
+        self.flush();
+    }
+
+    /// Queue `packet` for transmit on this core. The queue is sent once it
+    /// reaches `TX_BATCH_SIZE` packets, or when it is flushed by a `send()`,
+    /// `flush()`, or `poll()` on this core.
+    pub fn send_batched(&self, packet: Packet) {
+        // Split up IP packets which are too large for the MTU
+        let mtu = self.mtu();
+        if packet.raw().len() > 14 + mtu {
+            let id = self.next_ip_id.fetch_add(1, Ordering::Relaxed) as u16;
+            for fragment in ipfrag::fragment(&packet, mtu, id) {
+                self.send_batched(fragment);
+            }
+            return;
+        }
+
+        // Make sure the device can access the packet, reassembled packets
+        // are not in DMA memory
+        let packet = packet.into_dma();
+
+        let mut queue = self.tx_queues[core!().id as usize].lock();
+        queue.push(packet);
+
+        if queue.len() as u64 >= TX_BATCH_SIZE.get() {
+            self.driver.lock().send_batch(&mut queue);
+        }
+    }
+
+    /// Send all packets queued for transmit on this core
+    pub fn flush(&self) {
+        let mut queue = self.tx_queues[core!().id as usize].lock();
+        if !queue.is_empty() {
+            self.driver.lock().send_batch(&mut queue);
+        }
     }
 
     /// Allocate a new packet for use
@@ -187,11 +662,109 @@ impl NetDevice {
     pub fn mac(&self) -> [u8; 6] {
         self.mac
     }
+
+    /// Get the distribution of the round trip times of pings sent from this
+    /// device, in microseconds
+    pub fn rtt(&self) -> Summary {
+        self.rtt.lock().summary()
+    }
+
+    /// Get the current link state of this network device
+    pub fn link_state(&self) -> LinkState {
+        self.check_link();
+        *self.link.lock()
+    }
+
+    /// Get the capabilities in use by this network device
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Get the MTU of this network device
+    pub fn mtu(&self) -> usize {
+        self.mtu.load(Ordering::Relaxed)
+    }
+
+    /// Set the MTU of this network device, limited to what the driver
+    /// supports
+    pub fn set_mtu(&self, mtu: usize) {
+        let mtu = core::cmp::min(core::cmp::max(mtu, MIN_MTU),
+                                 self.capabilities.max_mtu);
+        self.driver.lock().set_mtu(mtu);
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
 }
 
 impl Device for NetDevice {
     unsafe fn purge(&mut self) {
-        panic!("Implement purge for net device");
+        // Stop the device from touching any of our packets. The driver lock
+        // may have been held by a core which was disabled, thus take it
+        // regardless.
+        (*self.driver.shatter()).purge();
+    }
+
+    fn registered(&'static self) {
+        register(self);
+    }
+}
+
+/// Get a registered network device on the network and start handling
+/// commands and sending reports on it
+pub fn register(device: &'static NetDevice) {
+    let server_ip = device.configure();
+    crate::command::listen(device);
+
+    // Send reports to the server which gave us our lease
+    if let Some(server_ip) = server_ip {
+        crate::report::connect(device, server_ip);
+    }
+}
+
+/// Features of the network stack which drivers may offload to hardware. The
+/// stack currently computes and validates all checksums itself and uses a
+/// single queue.
+const STACK_CAPABILITIES: Capabilities = Capabilities {
+    checksum: false,
+    tso:      false,
+    queues:   1,
+    max_mtu:  MAX_MTU,
+};
+
+/// State of the physical link of a network device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkState {
+    /// No link is established
+    Down,
+
+    /// The link is up at the specified speed in Mbit/s
+    Up(u32),
+}
+
+/// Optional features a network driver can offload to hardware
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Capabilities {
+    /// IPv4 and UDP checksum insertion on TX and validation on RX
+    pub checksum: bool,
+
+    /// TCP segmentation offload
+    pub tso: bool,
+
+    /// Number of independent RX and TX queue pairs
+    pub queues: usize,
+
+    /// Largest MTU the device can send and receive
+    pub max_mtu: usize,
+}
+
+impl Capabilities {
+    /// Get the capabilities which are present in both `self` and `other`
+    pub fn intersect(self, other: Capabilities) -> Capabilities {
+        Capabilities {
+            checksum: self.checksum && other.checksum,
+            tso:      self.tso      && other.tso,
+            queues:   core::cmp::min(self.queues, other.queues),
+            max_mtu:  core::cmp::min(self.max_mtu, other.max_mtu),
+        }
     }
 }
 
@@ -200,6 +773,44 @@ pub trait NetDriver {
     /// Gets the MAC address of the hardware
     fn mac(&self) -> [u8; 6];
 
+    /// Gets the current state of the physical link
+    fn link_state(&self) -> LinkState;
+
+    /// Gets the features the hardware and driver are able to offload
+    fn capabilities(&self) -> Capabilities {
+        // By default, nothing is offloaded, there is a single queue, and
+        // there's no support for jumbo frames
+        Capabilities {
+            queues:  1,
+            max_mtu: ETHERNET_MTU,
+            ..Default::default()
+        }
+    }
+
+    /// Enable the subset of `capabilities()` which the stack will use. This
+    /// is invoked once before any packets are sent or received.
+    fn enable(&mut self, _capabilities: Capabilities) {
+        // By default, there is nothing to enable
+    }
+
+    /// Set the MTU of the device. This is never larger than the `max_mtu` the
+    /// driver reported.
+    fn set_mtu(&mut self, _mtu: usize) {
+        // By default, the device always handles standard ethernet frames
+    }
+
+    /// Reinitialize the datapath of the device, discarding any frames which
+    /// are in flight. This is invoked when the link comes back up.
+    fn reset(&mut self) {
+        // By default, there is no datapath state to reset
+    }
+
+    /// Stop all DMA and interrupts of the device, in preparation for a soft
+    /// reboot. See `Device::purge()`.
+    unsafe fn purge(&mut self) {
+        // By default, the device does no DMA
+    }
+
     /// Recv a raw frame from the network and return ownership of the raw
     /// physical buffer that was used for the DMA of the packet
     ///
@@ -211,25 +822,31 @@ pub trait NetDriver {
     /// `packet` does not include the FCS, that must be computed or inserted
     /// by the driver.
     fn send(&mut self, packet: Packet);
+
+    /// Send all of `packets`, in order, leaving `packets` empty. Drivers
+    /// should fill as many descriptors as they can before notifying the
+    /// hardware, such that a batch costs a single doorbell write.
+    fn send_batch(&mut self, packets: &mut Vec<Packet>) {
+        // By default, send the packets one by one
+        for packet in packets.drain(..) {
+            self.send(packet);
+        }
+    }
     
     /// Get a packet from the NIC's packet free list. This allows us to give
     /// ownership of a packet during the `send` process, which the NIC can then
     /// use for whatever it needs. And we can get back packets from the NIC
     /// when we need a packet.
-    ///
-    /// It is strongly recommended that a NIC implements it's own packet free
-    /// list as creating and freeing packets requires physical memory
-    /// allocations and virtual memory mappings
     fn allocate_packet(&mut self) -> Packet {
-        // By default, create a new packet out of the global allocator
+        // By default, get a packet from the global packet pool
         Packet::new()
     }
 
     /// When the network stack is done with a packet lease, it will give it
     /// back to the NIC that it got the packet from.
     fn release_packet(&mut self, _packet: Packet) {
-        // By default, do nothing with the packet, causing it to get freed back
-        // to the global allocator
+        // By default, do nothing with the packet, causing it to get returned
+        // to the global packet pool
     }
 }
 
@@ -268,6 +885,22 @@ pub struct Ip<'a> {
     pub payload: &'a [u8],
 }
 
+/// A parsed IP header + payload of a packet which may be a fragment
+#[derive(Debug)]
+pub struct IpFragment<'a> {
+    /// IP header and the payload of the fragment
+    pub ip: Ip<'a>,
+
+    /// Identification of the IP packet the fragment belongs to
+    pub id: u16,
+
+    /// Offset of the fragment payload in the IP packet payload, in bytes
+    pub offset: usize,
+
+    /// Set if more fragments follow this one
+    pub more: bool,
+}
+
 /// A parsed UDP header + payload
 #[derive(Debug)]
 pub struct Udp<'a> {
@@ -284,32 +917,201 @@ pub struct Udp<'a> {
     pub payload: &'a [u8],
 }
 
+/// A parsed ARP packet for IPv4 over ethernet
+#[derive(Debug)]
+pub struct Arp<'a> {
+    /// Ethernet header for the packet
+    pub eth: Ethernet<'a>,
+
+    /// Operation of the packet, a request or a reply
+    pub opcode: u16,
+
+    /// MAC address of the sender
+    pub sender_mac: [u8; 6],
+
+    /// IP address of the sender
+    pub sender_ip: Ipv4Addr,
+
+    /// MAC address of the target, unused for requests
+    pub target_mac: [u8; 6],
+
+    /// IP address of the target
+    pub target_ip: Ipv4Addr,
+}
+
+/// A parsed ICMP header + payload
+#[derive(Debug)]
+pub struct Icmp<'a> {
+    /// IP header for the packet
+    pub ip: Ip<'a>,
+
+    /// Type of the ICMP message
+    pub typ: u8,
+
+    /// Code of the ICMP message
+    pub code: u8,
+
+    /// Type specific remainder of the header (eg. for echo messages, the
+    /// identifier in the high 16 bits and the sequence number in the low 16
+    /// bits)
+    pub header: u32,
+
+    /// Raw payload following the ICMP header
+    pub payload: &'a [u8],
+}
+
 /// A physically and virtually allocated packet that can easily be put into
 /// and taken from DMA buffers directly from NICs.
 /// 
 /// The memory will always be 4 KiB aligned, and contiguous in physical memory.
+///
+/// The contents of the packet start `start` bytes into the buffer, leaving
+/// headroom such that headers can be prepended without copying the payload.
+///
+/// The buffer is reference counted. Cloning a packet shares the buffer rather
+/// than copying it, and the buffer is only copied once one of the packets
+/// sharing it is written to. The buffer goes back to the pool once the last
+/// packet using it is dropped.
 pub struct Packet {
-    /// Physically contiguous allocation which can hold a packet. This must be
-    /// large enough for all of our network drivers to place directly in
-    /// their ring buffers. This is a 4 KiB aligned allocation and should work
-    /// in any NIC DMA
-    raw: PhysContig<[u8; 4096]>,
+    /// Allocation which holds the packet
+    ///
+    /// This is only ever taken out on drop, when it is returned to the pool
+    raw: ManuallyDrop<Arc<PacketBuffer>>,
 
-    /// Size of the `raw` member, in bytes
+    /// Offset of the packet contents into `raw`, in bytes
+    start: usize,
+
+    /// Size of the packet contents, in bytes
     length: usize,
 }
 
+/// Storage backing a packet
+enum PacketBuffer {
+    /// DMA accessible allocation which can hold a frame. This must be large
+    /// enough for all of our network drivers to place directly in their ring
+    /// buffers. This is a 4 KiB aligned allocation and should work in any NIC
+    /// DMA.
+    Dma(DmaBuffer<[u8; PACKET_BUFFER_SIZE]>),
+
+    /// Allocation which can hold an IP packet larger than a frame, used for
+    /// reassembled packets. Devices never access these, such packets are
+    /// fragmented again when they are sent.
+    Large(Box<[u8]>),
+}
+
+impl PacketBuffer {
+    /// Allocate a buffer for a reassembled IP packet
+    fn new_large() -> Self {
+        PacketBuffer::Large(vec![0u8; LARGE_PACKET_BUFFER_SIZE]
+            .into_boxed_slice())
+    }
+
+    /// Get the maximum number of bytes a packet in this buffer can hold
+    fn max_len(&self) -> usize {
+        match self {
+            PacketBuffer::Dma(_)   => MAX_FRAME_SIZE,
+            PacketBuffer::Large(_) => LARGE_PACKET_BUFFER_SIZE,
+        }
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            PacketBuffer::Dma(buf)   => &**buf,
+            PacketBuffer::Large(buf) => buf,
+        }
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            PacketBuffer::Dma(buf)   => &mut **buf,
+            PacketBuffer::Large(buf) => buf,
+        }
+    }
+}
+
 impl Packet {
-    /// Creates new physical storage for a packet
+    /// Gets storage for a packet, reusing a free buffer from the pool if
+    /// there is one
     pub fn new() -> Packet {
         Packet {
-            raw:    PhysContig::new([0u8; 4096]),
+            raw:    ManuallyDrop::new(Arc::new(
+                PacketBuffer::Dma(Self::new_buffer()))),
+            start:  0,
             length: 0,
         }
     }
 
+    /// Gets storage for an IP packet which may be larger than a frame, up to
+    /// the 64 KiB limit of IPv4. This is used for reassembled packets, which
+    /// can't be given to devices as-is.
+    pub fn new_large() -> Packet {
+        Packet {
+            raw:    ManuallyDrop::new(Arc::new(PacketBuffer::new_large())),
+            start:  0,
+            length: 0,
+        }
+    }
+
+    /// Get a packet with the same contents as this one which devices can
+    /// access, copying the contents if this packet isn't in DMA memory. The
+    /// packet must fit in a frame.
+    fn into_dma(self) -> Packet {
+        if let PacketBuffer::Dma(_) = **self.raw {
+            return self;
+        }
+
+        let mut packet = Packet::new();
+        packet.put(self.length).copy_from_slice(self.raw());
+        packet
+    }
+
+    /// Get a buffer for a packet from the pool, or allocate a new one if the
+    /// pool is empty
+    fn new_buffer() -> DmaBuffer<[u8; PACKET_BUFFER_SIZE]> {
+        let raw = PACKET_POOL.lock().pop();
+        raw.unwrap_or_else(|| DmaBuffer::new([0u8; PACKET_BUFFER_SIZE]))
+    }
+
+    /// Drop a reference to the buffer `raw`. If this was the last reference
+    /// the buffer is returned to the pool if there's room for it, otherwise
+    /// it is freed.
+    fn release_buffer(raw: Arc<PacketBuffer>) {
+        if let Ok(PacketBuffer::Dma(raw)) = Arc::try_unwrap(raw) {
+            let mut pool = PACKET_POOL.lock();
+            if pool.len() < PACKET_POOL_SIZE {
+                pool.push(raw);
+            }
+        }
+    }
+
+    /// Get mutable access to the buffer of the packet. If the buffer is
+    /// shared with other packets, the contents are first copied into a
+    /// buffer of our own, such that the other packets never see our writes.
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        if Arc::get_mut(&mut self.raw).is_none() {
+            let range = self.start..self.start + self.length;
+            let mut buffer = match **self.raw {
+                PacketBuffer::Dma(_)   =>
+                    PacketBuffer::Dma(Self::new_buffer()),
+                PacketBuffer::Large(_) => PacketBuffer::new_large(),
+            };
+            buffer[range.clone()].copy_from_slice(&self.raw[range]);
+
+            let shared = core::mem::replace(&mut *self.raw, Arc::new(buffer));
+            Self::release_buffer(shared);
+        }
+
+        Arc::get_mut(&mut self.raw).unwrap()
+    }
+
     /// Compute a ones-complement checksum
-    fn checksum(mut checksum: u32, bytes: &[u8]) -> u16 {
+    pub fn checksum(mut checksum: u32, bytes: &[u8]) -> u16 {
         // Go through each 2-byte pair in the payload
         for ii in (0..bytes.len() & !1).step_by(2) {
             checksum = checksum.wrapping_add(u16::from_ne_bytes(
@@ -342,8 +1144,21 @@ impl Packet {
         })
     }
     
-    /// Parse the IP header
+    /// Parse the IP header, rejecting fragments
     pub fn ip(&self) -> Option<Ip> {
+        let fragment = self.ip_fragment()?;
+
+        // Fragments must be reassembled before they can be used
+        if fragment.more || fragment.offset != 0 {
+            return None;
+        }
+
+        Some(fragment.ip)
+    }
+
+    /// Parse the IP header of a packet which may be a fragment of a larger
+    /// IP packet
+    pub fn ip_fragment(&self) -> Option<IpFragment> {
         // Parse the ethernet information from the header
         let eth = self.eth()?;
         
@@ -373,16 +1188,17 @@ impl Packet {
         // Bit 0 is reserved as zero
         // Bit 1 is don't fragment
         // Bit 2 is more fragments
-        // Make sure that the reserved bit and more fragments are clear as we
-        // do not support fragmentation.
-        if (flags & 0b101) != 0 {
+        // Make sure that the reserved bit is clear
+        if (flags & 0b100) != 0 {
             return None;
         }
 
-        // Get the fragment offset
+        // Get the identification, used to group fragments together
+        let id = u16::from_be_bytes(header[4..6].try_into().ok()?);
+
+        // Get the fragment offset, in 8-byte units
         let frag_offset = u16::from_be_bytes(
             header[6..8].try_into().ok()?) & 0x1fff;
-        if frag_offset != 0 { return None; }
 
         // Get the protocol
         let protocol = header[9];
@@ -404,12 +1220,17 @@ impl Packet {
         }
 
         // Return out the parsed IP information
-        Some(Ip {
-            src_ip,
-            dst_ip,
-            protocol,
-            payload: &eth.payload[20..total_length as usize],
-            eth,
+        Some(IpFragment {
+            ip: Ip {
+                src_ip,
+                dst_ip,
+                protocol,
+                payload: &eth.payload[20..total_length as usize],
+                eth,
+            },
+            id,
+            offset: frag_offset as usize * 8,
+            more:   (flags & 0b001) != 0,
         })
     }
 
@@ -479,6 +1300,140 @@ impl Packet {
         })
     }
 
+    /// Parse the ARP packet
+    pub fn arp(&self) -> Option<Arp> {
+        // Parse the ethernet information from the header
+        let eth = self.eth()?;
+
+        // If the ethernet frame wasn't indicating an ARP packet, return
+        // `None`
+        if eth.typ != ETHTYPE_ARP {
+            return None;
+        }
+
+        // ARP for IPv4 over ethernet is always 28 bytes
+        let arp = eth.payload.get(..28)?;
+
+        // Validate the hardware type (ethernet), protocol type (IPv4), and
+        // their address sizes
+        if arp[0..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return None;
+        }
+
+        Some(Arp {
+            opcode:     u16::from_be_bytes(arp[6..8].try_into().ok()?),
+            sender_mac: arp[8..14].try_into().ok()?,
+            sender_ip:
+                u32::from_be_bytes(arp[14..18].try_into().ok()?).into(),
+            target_mac: arp[18..24].try_into().ok()?,
+            target_ip:
+                u32::from_be_bytes(arp[24..28].try_into().ok()?).into(),
+            eth,
+        })
+    }
+
+    /// Extract the ICMP information from the payload, validating all layers
+    pub fn icmp(&self) -> Option<Icmp> {
+        // Parse the IP information from the header
+        let ip = self.ip()?;
+        if ip.protocol != IPPROTO_ICMP {
+            return None;
+        }
+
+        // Get the ICMP header
+        let header = ip.payload.get(0..8)?;
+
+        // Check the checksum of the header + payload
+        if Self::checksum(0, ip.payload) != 0 {
+            return None;
+        }
+
+        Some(Icmp {
+            typ:     header[0],
+            code:    header[1],
+            header:  u32::from_be_bytes(header[4..8].try_into().ok()?),
+            payload: &ip.payload[8..],
+            ip,
+        })
+    }
+
+    /// Create a new raw ARP packet for IPv4 over ethernet, sent to `dst_eth`
+    pub fn create_arp(&mut self, opcode: u16,
+                      sender_mac: [u8; 6], sender_ip: Ipv4Addr,
+                      dst_eth:    [u8; 6],
+                      target_mac: [u8; 6], target_ip: Ipv4Addr) {
+        // Build the packet from the start of the buffer
+        self.start = 0;
+
+        {
+            // Set up the ethernet header
+            let eth = &mut self.buffer_mut()[..14];
+            eth[0x0..0x6].copy_from_slice(&dst_eth);
+            eth[0x6..0xc].copy_from_slice(&sender_mac);
+            eth[0xc..0xe].copy_from_slice(&ETHTYPE_ARP.to_be_bytes());
+        }
+
+        {
+            // Set up the ARP packet
+            let arp = &mut self.buffer_mut()[14..14 + 28];
+
+            // Ethernet hardware, IPv4 protocol, and their address sizes
+            arp[0..6].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+
+            // Copy in the opcode and addresses
+            arp[6..8].copy_from_slice(&opcode.to_be_bytes());
+            arp[8..14].copy_from_slice(&sender_mac);
+            arp[14..18].copy_from_slice(&sender_ip.0.to_be_bytes());
+            arp[18..24].copy_from_slice(&target_mac);
+            arp[24..28].copy_from_slice(&target_ip.0.to_be_bytes());
+        }
+
+        // Pad the packet out to the minimum ethernet frame size
+        self.buffer_mut()[14 + 28..60].copy_from_slice(&[0; 60 - 14 - 28]);
+        self.set_len(60);
+    }
+
+    /// Create a new ICMP echo request or reply carrying `data`
+    pub fn create_icmp_echo(&mut self,
+                            src_eth: [u8; 6],  dst_eth: [u8; 6],
+                            src_ip:  Ipv4Addr, dst_ip:  Ipv4Addr,
+                            typ: u8, id: u16, seq: u16, data: &[u8]) {
+        // Set up the ethernet and IP headers
+        let offset = self.create_ip_raw(src_eth, dst_eth, src_ip, dst_ip,
+                                        IPPROTO_ICMP, 8 + data.len());
+
+        // Set up the ICMP header and payload
+        let icmp = &mut self.buffer_mut()[offset..offset + 8 + data.len()];
+        icmp[0] = typ;
+        icmp[1] = 0;
+        icmp[2..4].copy_from_slice(&[0; 2]);
+        icmp[4..6].copy_from_slice(&id.to_be_bytes());
+        icmp[6..8].copy_from_slice(&seq.to_be_bytes());
+        icmp[8..].copy_from_slice(data);
+
+        // Compute the checksum and fill in the checksum field
+        let checksum = Self::checksum(0, icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Create a new raw IPv4 packet with a `payload_len` byte payload
+    /// Returns the index into the packet where the payload should be placed.
+    fn create_ip_raw(&mut self,
+                     src_eth: [u8; 6],  dst_eth: [u8; 6],
+                     src_ip:  Ipv4Addr, dst_ip:  Ipv4Addr,
+                     protocol: u8, payload_len: usize) -> usize {
+        // Make room for the payload after the headers
+        self.start = 0;
+        self.reserve(14 + 20);
+        self.set_len(payload_len);
+
+        // Set up the ethernet and IP headers
+        self.push_ip_headers(src_eth, dst_eth, src_ip, dst_ip, protocol);
+
+        // Return the index of where to populate the payload
+        14 + 20
+    }
+
     /// Create a new raw UDP packet
     /// Returns the index into the packet where the message should be placed.
     pub fn create_udp_raw(&mut self,
@@ -486,17 +1441,31 @@ impl Packet {
                           src_ip:   Ipv4Addr, dst_ip:   Ipv4Addr,
                           src_port: u16,      dst_port: u16,
                           message_len: usize) -> usize {
-        {
-            // Set up the ethernet header
-            let eth = &mut self.raw[..14];
-            eth[0x0..0x6].copy_from_slice(&dst_eth);
-            eth[0x6..0xc].copy_from_slice(&src_eth);
-            eth[0xc..0xe].copy_from_slice(&ETHTYPE_IPV4.to_be_bytes());
-        }
-        
+        // Make room for the message after the headers
+        self.start = 0;
+        self.reserve(UDP_HEADROOM);
+        self.set_len(message_len);
+
+        // Set up the ethernet, IP, and UDP headers
+        self.push_udp_headers(src_eth, dst_eth, src_ip, dst_ip,
+                              src_port, dst_port);
+
+        // Return the index of where to populate the message payload
+        UDP_HEADROOM
+    }
+
+    /// Prepend the ethernet and IP headers to the current contents of the
+    /// packet, which become the IP payload
+    fn push_ip_headers(&mut self,
+                       src_eth: [u8; 6],  dst_eth: [u8; 6],
+                       src_ip:  Ipv4Addr, dst_ip:  Ipv4Addr,
+                       protocol: u8) {
+        // Get the size of the IP packet including the header
+        let ip_size = (20 + self.length) as u16;
+
         {
             // Set up the IP header
-            let ip = &mut self.raw[14..14 + 20];
+            let ip = self.push(20);
 
             // Set IPv4 as version and 20 byte header
             ip[0] = 0x45;
@@ -505,7 +1474,6 @@ impl Packet {
             ip[1] = 0;
 
             // Copy in the total length of the IP packet
-            let ip_size = (20 + 8 + message_len) as u16;
             ip[2..4].copy_from_slice(&ip_size.to_be_bytes());
 
             // Identification, flags, and fragment offset are all zero
@@ -514,8 +1482,8 @@ impl Packet {
             // TTL is set to 64 (seems to be standard)
             ip[8] = 64;
 
-            // Protocol is UDP
-            ip[9] = IPPROTO_UDP;
+            // Copy in the protocol
+            ip[9] = protocol;
 
             // Initialize the checksum to zero
             ip[10..12].copy_from_slice(&[0; 2]);
@@ -529,55 +1497,155 @@ impl Packet {
             ip[10..12].copy_from_slice(&checksum.to_be_bytes());
         }
 
+        {
+            // Set up the ethernet header
+            let eth = self.push(14);
+            eth[0x0..0x6].copy_from_slice(&dst_eth);
+            eth[0x6..0xc].copy_from_slice(&src_eth);
+            eth[0xc..0xe].copy_from_slice(&ETHTYPE_IPV4.to_be_bytes());
+        }
+    }
+
+    /// Prepend the ethernet, IP, and UDP headers to the current contents of
+    /// the packet, which become the UDP payload
+    pub fn push_udp_headers(&mut self,
+                            src_eth:  [u8; 6],  dst_eth:  [u8; 6],
+                            src_ip:   Ipv4Addr, dst_ip:   Ipv4Addr,
+                            src_port: u16,      dst_port: u16) {
+        // Get the size of the UDP header + payload
+        let udp_size = (8 + self.length) as u16;
+
         {
             // Set up the UDP header
-            let udp = &mut self.raw[14 + 20..14 + 20 + 8];
+            let udp = self.push(8);
 
             // Copy in the source and dest ports
             udp[0..2].copy_from_slice(&src_port.to_be_bytes());
             udp[2..4].copy_from_slice(&dst_port.to_be_bytes());
 
-            // Compute and copy in the UDP size + header
-            let udp_size = (8 + message_len) as u16;
+            // Copy in the UDP size + header
             udp[4..6].copy_from_slice(&udp_size.to_be_bytes());
 
             // No checksum (not required for IPv4)
             udp[6..8].copy_from_slice(&[0; 2]);
         }
 
-        // Set the length of the packet
-        self.set_len(14 + 20 + 8 + message_len);
+        self.push_ip_headers(src_eth, dst_eth, src_ip, dst_ip, IPPROTO_UDP);
+    }
 
-        // Return the index of where to populate the message payload
-        14 + 20 + 8
+    /// Set the amount of headroom of an empty packet, such that `headroom`
+    /// bytes of headers can later be prepended with `push()`
+    pub fn reserve(&mut self, headroom: usize) {
+        assert!(self.length == 0 && headroom <= MAX_FRAME_SIZE,
+            "reserve() on non-empty packet or with too much headroom");
+        self.start = headroom;
+    }
+
+    /// Grow the packet contents by `len` bytes at the front, returning the
+    /// new bytes
+    pub fn push(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.start, "push() on packet without headroom");
+        self.start -= len;
+        self.set_len(self.length + len);
+        &mut self.raw_mut()[..len]
+    }
+
+    /// Remove `len` bytes from the front of the packet contents, eg. to strip
+    /// off a header which was processed
+    #[allow(dead_code)]
+    pub fn pull(&mut self, len: usize) {
+        assert!(len <= self.length, "pull() on packet OOB");
+        self.start  += len;
+        self.length -= len;
+    }
+
+    /// Grow the packet contents by `len` bytes at the end, returning the new
+    /// bytes
+    pub fn put(&mut self, len: usize) -> &mut [u8] {
+        let old_len = self.length;
+        self.set_len(old_len + len);
+        &mut self.raw_mut()[old_len..]
     }
 
-    /// Gets the physical address for the packet
-    pub fn phys_addr(&self) -> PhysAddr {
-        self.raw.phys_addr()
+    /// Get the number of bytes which can be prepended to the packet
+    #[allow(dead_code)]
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Get the number of bytes which can be appended to the packet
+    #[allow(dead_code)]
+    pub fn tailroom(&self) -> usize {
+        core::cmp::min(self.raw.max_len(), self.raw.len() - self.start) -
+            self.length
+    }
+
+    /// Gets the address devices use to DMA to and from the packet. Devices
+    /// must only receive into packets which were freshly allocated, as the
+    /// buffer of any other packet may be shared.
+    pub fn dma_addr(&self) -> u64 {
+        match &**self.raw {
+            PacketBuffer::Dma(raw) => raw.dma_addr() + self.start as u64,
+            PacketBuffer::Large(_) =>
+                panic!("Attempted to DMA a packet larger than a frame"),
+        }
     }
 
     /// Get the raw packet contents
     #[inline]
     pub fn raw(&self) -> &[u8] {
-        &self.raw[..self.length]
+        &self.raw[self.start..self.start + self.length]
     }
     
     /// Get the raw packet contents as mutable
     #[inline]
     pub fn raw_mut(&mut self) -> &mut [u8] {
-        &mut self.raw[..self.length]
+        let range = self.start..self.start + self.length;
+        &mut self.buffer_mut()[range]
     }
 
     /// Set the length of the internally held bytes
     #[inline]
     pub fn set_len(&mut self, len: usize) {
-        assert!(len <= 1514 && len <= self.raw.len(),
+        assert!(len <= self.raw.max_len() &&
+                self.start + len <= self.raw.len(),
             "set_len() on packet OOB");
         self.length = len;
     }
 }
 
+impl Clone for Packet {
+    fn clone(&self) -> Self {
+        // Share the buffer, it is copied once either packet is written to
+        Packet {
+            raw:    ManuallyDrop::new(Arc::clone(&self.raw)),
+            start:  self.start,
+            length: self.length,
+        }
+    }
+}
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        // Take the buffer out of the packet, this is the only place it is
+        // taken
+        let raw = unsafe { ManuallyDrop::take(&mut self.raw) };
+
+        // Give up our reference to the buffer
+        Self::release_buffer(raw);
+    }
+}
+
+/// Free all buffers in the packet pool, revoking device access to them. This
+/// is used during the teardown for a soft reboot, as the DMA mappings of the
+/// buffers don't survive it while the pool does.
+///
+/// This takes the pool regardless of its lock, thus it must only be used once
+/// all other cores have been disabled.
+pub unsafe fn drain_pool() {
+    (*PACKET_POOL.shatter()).clear();
+}
+
 /// A lease of a packet
 ///
 /// This allows Rust-based drop handling to allow a NIC to get access back to
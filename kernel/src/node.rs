@@ -0,0 +1,37 @@
+//! Persistent identity of this node
+//!
+//! DHCP may hand a node a different address on every boot, thus nodes are
+//! identified by an ID derived from the MAC address of their first network
+//! device, which stays the same across reboots.
+
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// ID of this node, zero until it has been derived
+static NODE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Get the ID of this node, `None` if no network device has been configured
+/// yet
+pub fn id() -> Option<u64> {
+    match NODE_ID.load(Ordering::SeqCst) {
+        0 => None,
+        x => Some(x),
+    }
+}
+
+/// Derive the ID of this node from the MAC address `mac` of a network device.
+/// Only the first device to call this picks the ID, the ID of the node is
+/// returned either way.
+pub fn set_from_mac(mac: [u8; 6]) -> u64 {
+    // Hash the MAC such that IDs are spread evenly, making sure we never end
+    // up with zero as that means the ID is not set
+    let digest = hashes::blake3(&mac);
+    let id = core::cmp::max(
+        u64::from_le_bytes(digest[..8].try_into().unwrap()), 1);
+
+    match NODE_ID.compare_exchange(0, id, Ordering::SeqCst,
+                                   Ordering::SeqCst) {
+        Ok(_)         => id,
+        Err(existing) => existing,
+    }
+}
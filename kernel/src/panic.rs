@@ -21,6 +21,27 @@ static PANIC_PENDING: AtomicPtr<PanicInfo> =
 /// soft reboot as soon as we can.
 static SOFT_REBOOT_REQUESTED: AtomicBool = AtomicBool::new(false);
 
+/// Fixed size buffer which a panic message is formatted into, such that we
+/// don't have to allocate while panicking. Anything which does not fit is
+/// truncated.
+struct PanicRecord {
+    /// Formatted bytes
+    buf: [u8; 1024],
+
+    /// Number of bytes used in `buf`
+    len: usize,
+}
+
+impl core::fmt::Write for PanicRecord {
+    fn write_str(&mut self, st: &str) -> core::fmt::Result {
+        let len = core::cmp::min(st.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + len]
+            .copy_from_slice(&st.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
 /// Attempt a soft reboot by checking to see if there is a command on the
 /// serial port to soft reboot.
 pub unsafe fn attempt_soft_reboot() {
@@ -30,14 +51,19 @@ pub unsafe fn attempt_soft_reboot() {
 
     // Check if we got a 'Z' from the serial port.
     if let Some(b'Z') = byte {
-        // Request a soft reboot
-        SOFT_REBOOT_REQUESTED.store(true, Ordering::SeqCst);
-
-        // Force a panic
-        panic!("Soft reboot requested from timer");
+        request_soft_reboot("timer");
     }
 }
 
+/// Soft reboot the system by panicking, `source` describes who requested it
+pub fn request_soft_reboot(source: &str) -> ! {
+    // Request a soft reboot
+    SOFT_REBOOT_REQUESTED.store(true, Ordering::SeqCst);
+
+    // Force a panic
+    panic!("Soft reboot requested from {}", source);
+}
+
 /// Disable all cores on the system, making sure they check in when they stop
 pub unsafe fn disable_all_cores(apic: &mut Apic) {
     // Make sure we're on the BSP
@@ -50,8 +76,9 @@ pub unsafe fn disable_all_cores(apic: &mut Apic) {
             // Don't NMI ourself
             if apic_id == our_apic_id { continue; }
 
+            // Parked cores still take NMIs, thus they're halted the same way
             let state = acpi::core_state(apic_id);
-            if state == ApicState::Online {
+            if state == ApicState::Online || state == ApicState::Parked {
                 // Send this core an NMI to cause it to halt
                 apic.ipi(apic_id, (1 << 14) | (4 << 8));
                 while acpi::core_state(apic_id) != ApicState::Halted {}
@@ -77,9 +104,26 @@ pub unsafe fn soft_reboot(apic: &mut Apic) -> ! {
     // Disable all other cores
     disable_all_cores(apic);
 
+    // Nothing which held a lock will ever run again, release all of them
+    // such that the teardown below can't deadlock
+    crate::locks::release_all();
+
+    // Stop sending reports and listening for commands on the devices we're
+    // about to tear down
+    #[cfg(feature = "network")]
+    {
+        crate::report::disconnect();
+        crate::command::disconnect();
+    }
+
     // Destroy all devices which are handled by drivers
     crate::pci::destroy_devices();
 
+    // Stop translating DMA, such that the firmware drivers used by the
+    // bootloader can DMA again
+    #[cfg(feature = "iommu")]
+    crate::iommu::disable();
+
     // Destroy all the core locals, this will drop anything we've initialized
     // for this core, like the APIC. Causing it to get reset to the original
     // boot state.
@@ -175,8 +219,40 @@ pub fn panic(info: &PanicInfo) -> ! {
             if let Some(msg) = info.message() {
                 let _ = write!(eserial, "{}\n", msg);
             }
+
+            // Stage the panic in the crash ring such that it survives the
+            // soft reboot
+            let mut record = PanicRecord { buf: [0; 1024], len: 0 };
+            if let Some(loc) = info.location() {
+                let _ = write!(record, "At {}:{}:{}\n",
+                    loc.file(), loc.line(), loc.column());
+            }
+            if let Some(msg) = info.message() {
+                let _ = write!(record, "{}", msg);
+            }
+            unsafe {
+                crate::crash_ring::stage_panic(&record.buf[..record.len]);
+            }
         }
 
+        // Print the KASLR slide such that addresses in the panic can be
+        // symbolized against the kernel image
+        let _ = write!(eserial, "Kernel slide {:#x}\n",
+            core!().boot_args.kernel_slide.load(Ordering::SeqCst));
+
+        // Print the locks which were held when the cores were stopped
+        crate::locks::for_each_held(|reg, holder| {
+            match holder {
+                Some(core) => {
+                    let _ = write!(eserial, "Lock {} held by core {}\n",
+                                   reg.name, core);
+                }
+                None => {
+                    let _ = write!(eserial, "Lock {} held\n", reg.name);
+                }
+            }
+        });
+
         // Wait for a soft reboot to be requested
         while SOFT_REBOOT_REQUESTED.load(Ordering::SeqCst) != true {
             if eserial.0.read_byte() == Some(b'Z') {
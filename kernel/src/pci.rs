@@ -5,7 +5,10 @@ use core::mem::size_of;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 use lockcell::LockCell;
+use page_table::{PhysAddr, VirtAddr};
 
+use crate::mm;
+use crate::tunables::Tunable;
 use crate::core_locals::LockInterrupts;
 
 /// An driver for a device. There are multiple instances of a driver for each
@@ -27,10 +30,16 @@ pub trait Device: Any {
     /// device needs to be able to handle that a previous user of the device
     /// may have been interrupted mid-use.
     unsafe fn purge(&mut self);
+
+    /// Invoked once the device has been saved in the device list, where it
+    /// lives until the soft reboot. This is where a device can start work
+    /// which needs to hold on to a reference to it, like listening for
+    /// packets.
+    fn registered(&'static self) {}
 }
 
-/// If `true` verbose PCI device enumeration will be displayed
-const DEBUG_PCI_DEVICES: bool = true;
+/// If non-zero verbose PCI device enumeration will be displayed
+pub static DEBUG_PCI_DEVICES: Tunable = Tunable::new("debug_pci_devices", 1);
 
 /// Different types for PCI BARs
 #[derive(Clone, Copy)]
@@ -56,11 +65,25 @@ impl From<u32> for BarType {
 /// Type used for PCI device probes to attempt to handle a device
 type ProbeFunction = fn(&PciDevice) -> Option<Box<dyn Device>>;
 
-/// List of all driver probe routines on the system. If they return `Some` then
-/// we successfully found a driver and thus we'll register it in the
-/// `DEVICES` database
-const DRIVERS: &[ProbeFunction] = &[
-    crate::e1000::probe,
+/// A driver for PCI devices
+pub struct Driver {
+    /// Name of the driver, used for logging
+    pub name: &'static str,
+
+    /// (vendor ID, device ID) pairs of all devices this driver supports
+    pub ids: &'static [(u16, u16)],
+
+    /// Routine to initialize a device with one of the supported IDs. If this
+    /// returns `None` the driver failed to handle the device.
+    pub probe: ProbeFunction,
+}
+
+/// List of all drivers on the system. Devices are probed by the first driver
+/// which lists their IDs, and if the probe returns `Some` then we register the
+/// device in the `DEVICES` database
+const DRIVERS: &[Driver] = &[
+    #[cfg(feature = "network")]
+    crate::e1000::DRIVER,
 ];
 
 /// I/O port for the PCI configuration space window address
@@ -78,6 +101,155 @@ const PCI_ADDRESS_ENABLE: u32 = 1 << 31;
 /// `probe` routines from the `DRIVERS` list.
 static DEVICES: LockCell<Vec<Box<dyn Device>>, LockInterrupts> =
     LockCell::new(Vec::new());
+register_lock!(DEVICES);
+
+/// Table of every PCI function found during enumeration, regardless of
+/// whether a driver handled it
+static PCI_DEVICES: LockCell<Vec<(PciAddress, PciDevice)>, LockInterrupts> =
+    LockCell::new(Vec::new());
+register_lock!(PCI_DEVICES);
+
+/// Regions of configuration space which are accessible via ECAM (memory
+/// mapped configuration space). If empty, the legacy I/O port mechanism is
+/// used instead.
+static ECAM_REGIONS: LockCell<Vec<EcamRegion>, LockInterrupts> =
+    LockCell::new(Vec::new());
+register_lock!(ECAM_REGIONS);
+
+/// Location of a PCI function
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PciAddress {
+    /// PCI segment group, always 0 unless ECAM describes more segments
+    pub segment: u16,
+
+    /// Bus number
+    pub bus: u8,
+
+    /// Device number (0-31)
+    pub device: u8,
+
+    /// Function number (0-7)
+    pub function: u8,
+}
+
+/// A memory mapped region of PCI configuration space, described by the ACPI
+/// MCFG table
+#[derive(Clone, Copy, Debug)]
+struct EcamRegion {
+    /// Virtual address of the configuration space for `start_bus`
+    vaddr: VirtAddr,
+
+    /// PCI segment group this region describes
+    segment: u16,
+
+    /// First bus described by this region
+    start_bus: u8,
+
+    /// Last bus (inclusive) described by this region
+    end_bus: u8,
+}
+
+/// Find the virtual address of the configuration space of `addr` at `offset`
+/// through ECAM, if there is an ECAM region covering this function
+fn ecam_address(addr: PciAddress, offset: u16) -> Option<*mut u32> {
+    let regions = ECAM_REGIONS.lock();
+    let region = regions.iter().find(|x| {
+        x.segment == addr.segment &&
+            (x.start_bus..=x.end_bus).contains(&addr.bus)
+    })?;
+
+    // Compute the offset of this function's configuration space
+    let func_offset = ((addr.bus - region.start_bus) as u64) << 20 |
+        (addr.device as u64) << 15 | (addr.function as u64) << 12;
+
+    Some((region.vaddr.0 + func_offset + offset as u64) as *mut u32)
+}
+
+/// Compute the `0xcf8` window address for the legacy configuration access
+/// mechanism
+fn legacy_address(addr: PciAddress, offset: u16) -> u32 {
+    assert!(addr.segment == 0 && offset < 256,
+        "Legacy PCI config access out of range");
+
+    PCI_ADDRESS_ENABLE | (addr.bus as u32) << 16 |
+        (addr.device as u32) << 11 | (addr.function as u32) << 8 |
+        offset as u32
+}
+
+/// Read the 32-bit configuration register at `offset` for the function at
+/// `addr`. This uses ECAM if available, otherwise the legacy I/O ports.
+pub unsafe fn read_config(addr: PciAddress, offset: u16) -> u32 {
+    assert!((offset & 3) == 0, "Unaligned PCI config access");
+
+    if let Some(ptr) = ecam_address(addr, offset) {
+        core::ptr::read_volatile(ptr)
+    } else {
+        cpu::out32(PCI_CONFIG_ADDRESS, legacy_address(addr, offset));
+        cpu::in32(PCI_CONFIG_DATA)
+    }
+}
+
+/// Write the 32-bit configuration register at `offset` for the function at
+/// `addr`. This uses ECAM if available, otherwise the legacy I/O ports.
+pub unsafe fn write_config(addr: PciAddress, offset: u16, val: u32) {
+    assert!((offset & 3) == 0, "Unaligned PCI config access");
+
+    if let Some(ptr) = ecam_address(addr, offset) {
+        core::ptr::write_volatile(ptr, val);
+    } else {
+        cpu::out32(PCI_CONFIG_ADDRESS, legacy_address(addr, offset));
+        cpu::out32(PCI_CONFIG_DATA, val);
+    }
+}
+
+/// Get a copy of the table of all PCI functions found during enumeration
+pub fn devices() -> Vec<(PciAddress, PciDevice)> {
+    PCI_DEVICES.lock().clone()
+}
+
+/// Parse the ACPI MCFG table, if present, and map in all ECAM regions it
+/// describes as uncacheable memory
+unsafe fn init_ecam() {
+    // Get the MCFG table, the payload starts with 8 reserved bytes followed
+    // by 16-byte allocation entries
+    let (payload, payload_len) =
+        if let Some(mcfg) = crate::acpi::find_table(b"MCFG") {
+            mcfg
+        } else {
+            return;
+        };
+
+    let mut regions = ECAM_REGIONS.lock();
+    for entry in (8..payload_len).step_by(16) {
+        // Bail out on truncated entries
+        if entry + 16 > payload_len { break; }
+
+        // Parse the allocation entry
+        let entry     = payload.0 + entry as u64;
+        let base: u64 = mm::read_phys(PhysAddr(entry));
+        let segment   = mm::read_phys::<u16>(PhysAddr(entry +  8));
+        let start_bus = mm::read_phys::<u8>(PhysAddr(entry + 10));
+        let end_bus   = mm::read_phys::<u8>(PhysAddr(entry + 11));
+
+        // Skip nonsensical entries
+        if end_bus < start_bus { continue; }
+
+        // Compute the physical address and size of this region. Each bus has
+        // 32 devices with 8 functions each.
+        let paddr = base + ((start_bus as u64) << 20);
+        let size  = ((end_bus - start_bus) as u64 + 1) << 20;
+
+        // Map in the configuration space as uncacheable
+        let vaddr = mm::map_mmio("PCI ECAM", PhysAddr(paddr), size);
+
+        if DEBUG_PCI_DEVICES.get() != 0 {
+            print!("PCI ECAM   | segment {:#06x} | buses {:#04x}-{:#04x} | \
+                    {:#x}\n", segment, start_bus, end_bus, paddr);
+        }
+
+        regions.push(EcamRegion { vaddr, segment, start_bus, end_bus });
+    }
+}
 
 /// Common PCI header for the PCI configuration space of any device or bridge
 #[derive(Clone, Copy, Debug)]
@@ -122,83 +294,123 @@ pub struct PciDevice {
     pub max_latency:           u8,
 }
 
-/// Enumerate all PCI devices on the system and initialize drivers for any
-/// supported devices.
-pub unsafe fn init() {
-    // For each possible bus ID
-    for bus in 0..256 {
-        // For each possible device ID
-        for device in 0..32 {
-            // For each possible function ID
-            'next_function: for function in 0..8 {
-                // Compute the address to select this BDF combination
-                let addr = PCI_ADDRESS_ENABLE | (bus << 16) | (device << 11) |
-                    (function << 8);
-
-                // Select the address and read the device and vendor ID
-                cpu::out32(PCI_CONFIG_ADDRESS, addr);
-                let did_vid = cpu::in32(PCI_CONFIG_DATA);
-
-                // Extract the vendor and device ID
-                let vendor_id = (did_vid >>  0) as u16;
-                let device_id = (did_vid >> 16) as u16;
-
-                // If the device is not present, the VID and DID will be
-                // all `f`s, thus we can skip to the next device
-                if vendor_id == 0xffff && device_id == 0xffff {
-                    continue;
-                }
+/// Read the configuration space of the function at `addr` into a `T`
+unsafe fn read_config_struct<T>(addr: PciAddress) -> T {
+    // Read the configuration registers
+    let mut raw = [0u32; 64];
+    let raw = &mut raw[..size_of::<T>() / size_of::<u32>()];
+    for (rid, register) in raw.iter_mut().enumerate() {
+        *register = read_config(addr, (rid * size_of::<u32>()) as u16);
+    }
 
-                // Read the PCI configuration header
-                let mut header =
-                    [0u32; size_of::<PciHeader>() / size_of::<u32>()];
-                for (rid, register) in header.iter_mut().enumerate() { 
-                    // Set the window to the register we want to read and read
-                    // the value
-                    cpu::out32(PCI_CONFIG_ADDRESS,
-                               addr | (rid * size_of::<u32>()) as u32);
-                    *register = cpu::in32(PCI_CONFIG_DATA);
-                }
+    // Convert the registers to the structure
+    core::ptr::read_unaligned(raw.as_ptr() as *const T)
+}
 
-                // Convert the header to our `PciHeader` structure
-                let header: PciHeader = core::ptr::read_unaligned(
-                    header.as_ptr() as *const PciHeader);
+/// Probe the function at `addr`, returning whether a function was present
+/// and whether the device has multiple functions
+unsafe fn probe_function(addr: PciAddress) -> Option<bool> {
+    // Read the device and vendor ID
+    let did_vid = read_config(addr, 0);
 
-                // Skip non-device PCI entries (skips things like PCI bridges)
-                if header.header_type != 0 {
-                    continue;
-                }
+    // Extract the vendor and device ID
+    let vendor_id = (did_vid >>  0) as u16;
+    let device_id = (did_vid >> 16) as u16;
 
-                // Read the PCI configuration
-                let mut device =
-                    [0u32; size_of::<PciDevice>() / size_of::<u32>()];
-                for (rid, register) in device.iter_mut().enumerate() { 
-                    // Set the window to the register we want to read and read
-                    // the value
-                    cpu::out32(PCI_CONFIG_ADDRESS,
-                               addr | (rid * size_of::<u32>()) as u32);
-                    *register = cpu::in32(PCI_CONFIG_DATA);
-                }
+    // If the device is not present, the VID and DID will be all `f`s
+    if vendor_id == 0xffff && device_id == 0xffff {
+        return None;
+    }
 
-                // Convert the device to our `PciDevice` structure
-                let device: PciDevice = core::ptr::read_unaligned(
-                    device.as_ptr() as *const PciDevice);
+    // Read the PCI configuration header
+    let header: PciHeader = read_config_struct(addr);
 
-                if DEBUG_PCI_DEVICES {
-                    print!("PCI device | {:#06x}:{:#06x} | {:#06x}:{:#06x}\n",
-                           device.header.vendor_id,
-                           device.header.device_id,
-                           device.subsystem_vendor_id,
-                           device.subsystem_device_id);
-                }
+    // Determine if this is a multi-function device
+    let multi_function = (header.header_type & 0x80) != 0;
+
+    // Skip non-device PCI entries (skips things like PCI bridges)
+    if (header.header_type & 0x7f) != 0 {
+        return Some(multi_function);
+    }
+
+    // Read the PCI configuration
+    let device: PciDevice = read_config_struct(addr);
+
+    if DEBUG_PCI_DEVICES.get() != 0 {
+        print!("PCI device | {:04x}:{:02x}:{:02x}.{} | \
+                {:#06x}:{:#06x} | {:#06x}:{:#06x}\n",
+               addr.segment, addr.bus, addr.device, addr.function,
+               device.header.vendor_id,
+               device.header.device_id,
+               device.subsystem_vendor_id,
+               device.subsystem_device_id);
+    }
+
+    // Save the device in the device table
+    PCI_DEVICES.lock().push((addr, device));
+
+    // Attempt to find a driver for this device, if this node uses drivers
+    let id = (device.header.vendor_id, device.header.device_id);
+    let driver = DRIVERS.iter().find(|x| x.ids.contains(&id));
+    #[cfg(feature = "network")]
+    let driver = driver.filter(|_| crate::role::subsystems().network);
+    if let Some(driver) = driver {
+        // Allow the device to DMA to `DmaBuffer`s
+        #[cfg(feature = "iommu")]
+        crate::iommu::attach(addr);
+
+        if let Some(handled) = (driver.probe)(&device) {
+            // Devices live until the soft reboot, after which nothing which
+            // could be using this reference runs anymore. The device is boxed
+            // thus it doesn't move when the device list grows.
+            let registered: &'static dyn Device =
+                &*(&*handled as *const dyn Device);
+            DEVICES.lock().push(handled);
+
+            // Let the device get to work, without holding the device list
+            registered.registered();
+        } else {
+            print!("PCI driver {} failed to handle {:#06x}:{:#06x}\n",
+                   driver.name, id.0, id.1);
+        }
+    }
 
-                // Attempt to find a driver for this device
-                for probe in DRIVERS {
-                    if let Some(driver) = probe(&device) {
-                        // Found a handler, go to the next function during the
-                        // PCI enumeration
-                        DEVICES.lock().push(driver);
-                        continue 'next_function;
+    Some(multi_function)
+}
+
+/// Enumerate all PCI devices on the system and initialize drivers for any
+/// supported devices.
+pub unsafe fn init() {
+    // Set up memory mapped configuration space access, if available
+    init_ecam();
+
+    // Determine the segments and bus ranges to scan. Without ECAM we can only
+    // access segment 0 through the legacy mechanism.
+    let ranges: Vec<(u16, u8, u8)> = {
+        let regions = ECAM_REGIONS.lock();
+        if regions.is_empty() {
+            vec![(0, 0, 255)]
+        } else {
+            regions.iter().map(|x| (x.segment, x.start_bus, x.end_bus))
+                .collect()
+        }
+    };
+
+    for (segment, start_bus, end_bus) in ranges {
+        // For each possible bus ID
+        for bus in start_bus..=end_bus {
+            // For each possible device ID
+            for device in 0..32 {
+                // For each possible function ID
+                for function in 0..8 {
+                    let addr = PciAddress { segment, bus, device, function };
+
+                    // Probe the function, if function 0 is not present or it
+                    // is not a multi-function device, skip the remaining
+                    // functions
+                    let multi_function = probe_function(addr);
+                    if function == 0 && multi_function != Some(true) {
+                        break;
                     }
                 }
             }
@@ -217,5 +429,10 @@ pub unsafe fn destroy_devices() {
     for device in devices {
         device.purge();
     }
+
+    // Now that no device uses them anymore, free the pooled packet buffers
+    // as their DMA mappings won't survive the soft reboot
+    #[cfg(feature = "network")]
+    crate::net::drain_pool();
 }
 
@@ -0,0 +1,127 @@
+//! Registry of the physical address space
+//!
+//! Every subsystem which owns part of the physical address space outside of
+//! the general purpose allocators (device MMIO, firmware tables, memory used
+//! by the bootloader) reserves it here by name. A reservation which overlaps
+//! another reservation is a conflict, as is device MMIO which overlaps RAM
+//! according to the firmware memory map. Both are fatal, as they mean two
+//! owners would be using the same memory.
+
+use alloc::vec::Vec;
+
+use lockcell::LockCell;
+use rangeset::Range;
+use page_table::PhysAddr;
+
+use crate::core_locals::LockInterrupts;
+use crate::tunables::Tunable;
+
+/// If non-zero, the physical address space is dumped once the BSP is done
+/// initializing
+pub static DUMP_PHYS_REGIONS: Tunable = Tunable::new("dump_phys_regions", 0);
+
+/// Physical memory which the bootloader and BIOS data structures live in
+const BOOTLOADER_REGION: Range = Range { start: 0, end: 1024 * 1024 - 1 };
+
+/// The kind of physical memory a region is
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionType {
+    /// Memory, such as RAM or firmware tables
+    Memory,
+
+    /// Memory mapped device registers
+    Mmio,
+}
+
+/// A reserved region of the physical address space
+struct Region {
+    /// Name of the owner of the region
+    name: &'static str,
+
+    /// Kind of memory in the region
+    typ: RegionType,
+
+    /// Physical addresses of the region
+    range: Range,
+}
+
+/// All reserved regions of the physical address space
+static REGIONS: LockCell<Vec<Region>, LockInterrupts> =
+    LockCell::new(Vec::new());
+register_lock!(REGIONS);
+
+/// Reserve `size` bytes of the physical address space at `paddr` for `name`.
+///
+/// Reserving the exact same region with the same name multiple times is
+/// allowed, such that per-core devices can be mapped on every core.
+pub fn reserve(name: &'static str, typ: RegionType, paddr: PhysAddr,
+               size: u64) {
+    // Compute the inclusive range of the reservation
+    let range = Range {
+        start: paddr.0,
+        end:   size.checked_sub(1).and_then(|x| x.checked_add(paddr.0))
+            .expect("Invalid physical region reservation"),
+    };
+
+    // Device registers should never be backed by RAM
+    if typ == RegionType::Mmio {
+        let memory_map = core!().boot_args.memory_map.lock();
+        if let Some(overlap) = memory_map.as_ref()
+                .and_then(|x| x.overlap(range)) {
+            panic!("Physical region conflict: {} MMIO overlaps RAM at \
+                    {:#x}-{:#x}", name, overlap.start, overlap.end);
+        }
+    }
+
+    let mut regions = REGIONS.lock();
+
+    for region in regions.iter() {
+        // Skip regions we don't overlap with
+        if range.start > region.range.end || region.range.start > range.end {
+            continue;
+        }
+
+        // Allow duplicate reservations of the same region
+        if region.name == name && region.typ == typ &&
+                region.range.start == range.start &&
+                region.range.end == range.end {
+            return;
+        }
+
+        panic!("Physical region conflict: {} {:#x}-{:#x} overlaps \
+                {} {:#x}-{:#x}", name, range.start, range.end,
+               region.name, region.range.start, region.range.end);
+    }
+
+    regions.push(Region { name, typ, range });
+}
+
+/// Print the RAM reported by the firmware and all reserved regions of the
+/// physical address space, sorted by address
+pub fn dump() {
+    print!("Physical address space:\n");
+
+    if let Some(memory_map) = core!().boot_args.memory_map.lock().as_ref() {
+        let mut ram = memory_map.entries().to_vec();
+        ram.sort_by_key(|x| x.start);
+        for range in ram {
+            print!("    {:#018x}-{:#018x} | RAM\n", range.start, range.end);
+        }
+    }
+
+    let mut regions = REGIONS.lock();
+    regions.sort_by_key(|x| x.range.start);
+    for region in regions.iter() {
+        print!("    {:#018x}-{:#018x} | {:?} | {}\n",
+               region.range.start, region.range.end, region.typ,
+               region.name);
+    }
+}
+
+/// Reserve the regions of physical memory which were in use before the
+/// kernel started
+pub fn init() {
+    reserve("bootloader", RegionType::Memory,
+            PhysAddr(BOOTLOADER_REGION.start),
+            BOOTLOADER_REGION.end - BOOTLOADER_REGION.start + 1);
+}
@@ -0,0 +1,161 @@
+//! Sampling profiler for the kernel
+//!
+//! When the `profile` feature is enabled, every APIC timer interrupt records
+//! the interrupted `rip` into a per-core sample buffer. Once the buffer fills
+//! up it is handed off as a snapshot and a new profiling period starts. A
+//! task on every core picks up the snapshot and reports the hottest addresses
+//! over the serial port and to the server.
+//!
+//! The interrupt side never allocates or prints, it only copies the samples
+//! into the snapshot. If the task has not picked up the previous snapshot yet
+//! by the time the next one is ready, the new one is dropped.
+//!
+//! Addresses are reported with the KASLR slide removed, such that they can be
+//! symbolized offline against the kernel image.
+
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use alloc::string::String;
+
+use crate::{executor, report};
+
+/// Number of samples collected by each core before it reports them. At the
+/// default APIC timer tick rate, this is a report roughly every 5 seconds.
+const SAMPLES_PER_REPORT: usize = 512;
+
+/// Number of the hottest addresses to include in a report
+const REPORT_ENTRIES: usize = 10;
+
+/// Interval at which every core checks for a snapshot to report, in
+/// microseconds
+const REPORT_INTERVAL: u64 = 100_000;
+
+/// Samples collected on a core during the current profiling period
+pub struct Samples {
+    /// Sampled instruction pointers
+    rips: [u64; SAMPLES_PER_REPORT],
+
+    /// Number of valid entries in `rips`
+    len: usize,
+
+    /// Samples of the last full profiling period, waiting to be reported
+    snapshot: [u64; SAMPLES_PER_REPORT],
+
+    /// Set when `snapshot` holds samples which were not reported yet
+    snapshot_ready: bool,
+
+    /// Number of profiling periods which were dropped as the previous
+    /// snapshot was not reported yet
+    dropped: u64,
+}
+
+impl Samples {
+    /// Create a new, empty set of samples
+    pub const fn new() -> Self {
+        Samples {
+            rips:           [0; SAMPLES_PER_REPORT],
+            len:            0,
+            snapshot:       [0; SAMPLES_PER_REPORT],
+            snapshot_ready: false,
+            dropped:        0,
+        }
+    }
+}
+
+/// Record a sample of the current core executing at `rip`. This is invoked
+/// from the APIC timer interrupt, thus it must not allocate.
+pub fn sample(rip: u64) {
+    let mut samples = core!().profile.lock();
+
+    // Record the sample
+    let len = samples.len;
+    samples.rips[len] = rip;
+    samples.len += 1;
+
+    // Hand off the samples once we've filled up the buffer
+    if samples.len == samples.rips.len() {
+        if samples.snapshot_ready {
+            samples.dropped += 1;
+        } else {
+            samples.snapshot       = samples.rips;
+            samples.snapshot_ready = true;
+        }
+        samples.len = 0;
+    }
+}
+
+/// Start reporting the profiles of the current core. This must be called on
+/// every core once the executor is ready.
+pub fn init() {
+    executor::spawn_periodic("profile", REPORT_INTERVAL, || {
+        // Take the snapshot, if there is one. The lock disables interrupts,
+        // thus it is only held for the copy.
+        let (mut rips, dropped) = {
+            let mut samples = core!().profile.lock();
+            if !samples.snapshot_ready {
+                return;
+            }
+            samples.snapshot_ready = false;
+            (samples.snapshot, core::mem::replace(&mut samples.dropped, 0))
+        };
+
+        let text = summarize(&mut rips, dropped);
+        print!("{}", text);
+        report::send(report::Kind::Profile, text.as_bytes());
+    });
+}
+
+/// Summarize the hottest addresses in `rips`, noting that `dropped`
+/// profiling periods were lost before them. This sorts `rips` in place.
+fn summarize(rips: &mut [u64], dropped: u64) -> String {
+    // Get the KASLR slide such that we can report linked addresses
+    let slide = core!().boot_args.kernel_slide.load(Ordering::SeqCst);
+
+    // Sort the samples to group identical addresses together
+    rips.sort_unstable();
+
+    // Hottest (count, rip) pairs, hottest first
+    let mut hottest = [(0usize, 0u64); REPORT_ENTRIES];
+
+    // Count the samples for each unique address
+    for run in RipRuns(rips) {
+        let entry = (run.len(), run[0]);
+
+        // Insert the address into the hottest list, if it's hot enough
+        if let Some(idx) = hottest.iter().position(|x| entry.0 > x.0) {
+            hottest[idx..].rotate_right(1);
+            hottest[idx] = entry;
+        }
+    }
+
+    let mut text = String::new();
+    let _ = write!(text, "Profile of core {} | {} samples | {} dropped\n",
+                   core!().id, rips.len(), dropped);
+    for &(count, rip) in hottest.iter().filter(|x| x.0 > 0) {
+        let _ = write!(text, "    {:#018x} | {:6} | {:8.4} %\n",
+                       rip.wrapping_sub(slide), count,
+                       count as f64 / rips.len() as f64 * 100.);
+    }
+
+    text
+}
+
+/// Iterator over runs of identical addresses in a sorted slice of addresses
+struct RipRuns<'a>(&'a [u64]);
+
+impl<'a> Iterator for RipRuns<'a> {
+    type Item = &'a [u64];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Get the address of this run
+        let rip = *self.0.first()?;
+
+        // Find the end of the run and split it off
+        let len = self.0.iter().position(|&x| x != rip)
+            .unwrap_or(self.0.len());
+        let (run, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Some(run)
+    }
+}
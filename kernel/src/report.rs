@@ -0,0 +1,216 @@
+//! Reports to the server
+//!
+//! Once the first network device has a lease, results and crash artifacts are
+//! sent to the server which handed it out, such that they can be collected
+//! without watching the serial port of every node. Reports are sent to the
+//! beacon port of the server. Reports larger than a packet are split into
+//! chunks which the server puts back together by their offset.
+//!
+//! Crash artifacts staged in the crash ring, eg. panics from previous boots,
+//! are shipped as soon as the server is known, and are only removed from the
+//! ring once they have been sent.
+//!
+//! Report: `CMREPORT` | node ID (u64 LE) | kind (u8) | offset (u32 LE)
+//!         | total length (u32 LE) | data
+
+use crate::crash_ring::RecordKind;
+
+#[cfg(feature = "network")]
+use lockcell::LockCell;
+#[cfg(feature = "network")]
+use crate::core_locals::LockInterrupts;
+#[cfg(feature = "network")]
+use crate::net::{NetDevice, UdpSocket, Ipv4Addr};
+
+/// UDP port the server listens for reports on
+#[cfg(feature = "network")]
+const REPORT_PORT: u16 = 1338;
+
+/// Magic at the start of a report
+#[cfg(feature = "network")]
+const REPORT_MAGIC: &[u8; 8] = b"CMREPORT";
+
+/// Size of the magic, node ID, kind, offset, and total length
+#[cfg(feature = "network")]
+const HEADER_SIZE: usize = 8 + 8 + 1 + 4 + 4;
+
+/// Maximum number of bytes of data in a single report packet
+#[cfg(feature = "network")]
+const MAX_CHUNK: usize = 8 * 1024;
+
+/// Time to wait for the server to resolve, in microseconds
+#[cfg(feature = "network")]
+const RESOLVE_TIMEOUT: u64 = 500_000;
+
+/// The server we send reports to, `None` until a device has a lease
+#[cfg(feature = "network")]
+static SERVER: LockCell<Option<Server>, LockInterrupts> = LockCell::new(None);
+#[cfg(feature = "network")]
+register_lock!(SERVER);
+
+/// Kinds of reports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Kind {
+    /// Panic message of a kernel
+    Panic = 1,
+
+    /// Input which caused a crash
+    CrashInput = 2,
+
+    /// Report describing a crash
+    CrashReport = 3,
+
+    /// Results of the boot-time memory test
+    MemTest = 4,
+
+    /// Results of the boot-time self tests
+    SelfTest = 5,
+
+    /// Hottest addresses of a core, from the sampling profiler
+    #[allow(dead_code)]
+    Profile = 6,
+}
+
+impl From<RecordKind> for Kind {
+    fn from(kind: RecordKind) -> Self {
+        match kind {
+            RecordKind::Panic       => Kind::Panic,
+            RecordKind::CrashInput  => Kind::CrashInput,
+            RecordKind::CrashReport => Kind::CrashReport,
+        }
+    }
+}
+
+/// Where reports are sent to
+#[cfg(feature = "network")]
+#[derive(Clone, Copy)]
+struct Server {
+    /// Device the server is reachable over
+    device: &'static NetDevice,
+
+    /// Address of the server
+    ip: Ipv4Addr,
+
+    /// MAC address the server is reachable at
+    mac: [u8; 6],
+}
+
+/// Start sending reports to `server_ip` over `device`, and ship all crash
+/// artifacts which are staged in the crash ring. Only the first device to
+/// call this is used for reports.
+#[cfg(feature = "network")]
+pub fn connect(device: &'static NetDevice, server_ip: Ipv4Addr) {
+    // Don't bother resolving the server if we already have one
+    if SERVER.lock().is_some() {
+        return;
+    }
+
+    // Resolve the server without holding the lock, as interrupts are disabled
+    // while it's held
+    let mac = match device.resolve(server_ip, RESOLVE_TIMEOUT) {
+        Some(mac) => mac,
+        None => {
+            print!("Report server {:?} is not reachable\n", server_ip);
+            return;
+        }
+    };
+
+    {
+        // Another device may have connected while we were resolving
+        let mut server = SERVER.lock();
+        if server.is_some() {
+            return;
+        }
+        *server = Some(Server { device, ip: server_ip, mac });
+    }
+
+    print!("Sending reports to {:?}\n", server_ip);
+    ship_crash_ring();
+}
+
+/// Stop sending reports, as the device they're sent over is about to be torn
+/// down by a soft reboot. This is only to be used once all other cores have
+/// been halted.
+#[cfg(feature = "network")]
+pub unsafe fn disconnect() {
+    *SERVER.shatter() = None;
+}
+
+/// Ship all crash artifacts staged in the crash ring to the server, removing
+/// them from the ring once they have been queued for transmit. All artifacts
+/// go out in as few batches as possible.
+#[cfg(feature = "network")]
+fn ship_crash_ring() {
+    let server = match *SERVER.lock() {
+        Some(server) => server,
+        None         => return,
+    };
+
+    let socket = UdpSocket::bind(server.device, 0)
+        .expect("Could not bind to a port for reports");
+    crate::crash_ring::drain(|kind, data| {
+        queue(&server, &socket, kind.into(), data);
+        true
+    });
+    socket.flush();
+}
+
+/// Queue a report of `kind` containing `data` for transmit to `server` from
+/// `socket`. The report is split into chunks which fit in a packet, empty
+/// reports still get a single packet.
+#[cfg(feature = "network")]
+fn queue(server: &Server, socket: &UdpSocket, kind: Kind, data: &[u8]) {
+    let node_id = crate::node::id().unwrap_or(0);
+
+    let mut offset = 0;
+    loop {
+        let chunk = &data[offset..
+            core::cmp::min(data.len(), offset + MAX_CHUNK)];
+
+        socket.send_to_batched(server.mac, server.ip, REPORT_PORT,
+                               |packet| {
+            let payload = packet.put(HEADER_SIZE + chunk.len());
+            payload[..8].copy_from_slice(REPORT_MAGIC);
+            payload[8..16].copy_from_slice(&node_id.to_le_bytes());
+            payload[16] = kind as u8;
+            payload[17..21].copy_from_slice(&(offset as u32).to_le_bytes());
+            payload[21..25].copy_from_slice(
+                &(data.len() as u32).to_le_bytes());
+            payload[HEADER_SIZE..].copy_from_slice(chunk);
+        });
+
+        offset += chunk.len();
+        if offset >= data.len() {
+            break;
+        }
+    }
+}
+
+/// Send a report of `kind` containing `data` to the server. Returns `false`
+/// if there is no server to send reports to.
+pub fn send(kind: Kind, data: &[u8]) -> bool {
+    assert!(!core!().in_interrupt(),
+        "Attempted to send a report in an interrupt");
+
+    #[cfg(feature = "network")]
+    {
+        let server = match *SERVER.lock() {
+            Some(server) => server,
+            None         => return false,
+        };
+
+        let socket = UdpSocket::bind(server.device, 0)
+            .expect("Could not bind to a port for reports");
+        queue(&server, &socket, kind, data);
+        socket.flush();
+
+        true
+    }
+
+    #[cfg(not(feature = "network"))]
+    {
+        let _ = (kind, data);
+        false
+    }
+}
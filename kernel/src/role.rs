@@ -0,0 +1,49 @@
+//! Node roles and the subsystems they initialize
+//!
+//! The same kernel image is deployed to every kind of node. Cargo features
+//! select which subsystems are compiled into the image, and the role given to
+//! us by the bootloader selects which of those are initialized at boot. The
+//! bootloader takes the role from the DHCP boot arguments, or from the role
+//! file.
+
+use core::sync::atomic::Ordering;
+
+pub use boot_args::Role;
+
+/// Subsystems which are optionally initialized based on the node role
+#[derive(Clone, Copy, Debug)]
+pub struct Subsystems {
+    /// PCI device drivers, all of which are currently network drivers
+    pub network: bool,
+
+    /// DMA remapping through the IOMMU
+    pub iommu: bool,
+
+    /// Microcode updates
+    pub microcode: bool,
+}
+
+/// Get the role of this node
+pub fn get() -> Role {
+    Role::from_raw(core!().boot_args.role.load(Ordering::SeqCst))
+        .expect("Invalid node role in boot arguments")
+}
+
+/// Get the subsystems which should be initialized on this node. A subsystem
+/// is only initialized if it was compiled in and the node role uses it.
+pub fn subsystems() -> Subsystems {
+    // Everything which was compiled in
+    let all = Subsystems {
+        network:   cfg!(feature = "network"),
+        iommu:     cfg!(feature = "iommu"),
+        microcode: cfg!(feature = "microcode"),
+    };
+
+    match get() {
+        Role::FuzzWorker | Role::Triage => all,
+
+        // Microcode is only updated to make sure fuzz workers and triage
+        // nodes behave identically, net test rigs don't need it
+        Role::NetTest => Subsystems { microcode: false, ..all },
+    }
+}
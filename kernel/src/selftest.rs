@@ -0,0 +1,219 @@
+//! Boot-time self tests of core kernel subsystems
+//!
+//! When enabled, every core runs a set of stress tests of the locks, the
+//! allocator, and the page table code once all cores have checked in. The
+//! network stack is not tested here, as the loopback test already runs on
+//! every boot before any network devices are probed. The BSP reports the
+//! result of every test over serial and to the server, and panics if any of
+//! them failed, such that a broken node never gets to run any work.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+use alloc::vec::Vec;
+use alloc::string::String;
+
+use lockcell::LockCell;
+use page_table::{PageTable, PageType, PhysAddr, PhysMem, VirtAddr};
+
+use crate::{acpi, report};
+use crate::mm::{self, PhysicalMemory};
+use crate::core_locals::LockInterrupts;
+use crate::tunables::Tunable;
+
+/// If non-zero, the self tests are run at boot
+pub static SELFTEST: Tunable = Tunable::new("selftest", 0);
+
+/// Number of times every core acquires the test lock
+const LOCK_ITERS: u64 = 10_000;
+
+/// Number of allocations every core makes during the allocator test
+const ALLOC_ITERS: usize = 10_000;
+
+/// Maximum number of live allocations per core during the allocator test
+const ALLOC_LIVE: usize = 64;
+
+/// Virtual address the page table test maps memory at
+const PAGE_TEST_VADDR: VirtAddr = VirtAddr(0x1337_0000_0000);
+
+/// Number of bytes the page table test maps
+const PAGE_TEST_SIZE: u64 = 64 * 4096;
+
+/// All of the self tests, by name. Every test is run on every core, tests
+/// which don't need all cores only do work on the BSP.
+const TESTS: &[(&str, fn() -> bool)] = &[
+    ("LockCell mutual exclusion", lock_stress),
+    ("LockCell acquisition count", lock_count),
+    ("Allocator",                 allocator),
+    ("Page tables",               page_tables),
+];
+
+/// Lock shared between all cores for the lock tests. Both values are always
+/// equal when the lock is not held.
+static TEST_LOCK: LockCell<(u64, u64), LockInterrupts> =
+    LockCell::new((0, 0));
+register_lock!(TEST_LOCK);
+
+/// Number of cores on which the current test failed
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Number of times cores have entered `barrier()`
+static BARRIER: AtomicU32 = AtomicU32::new(0);
+
+/// Wait for all cores to reach the barrier for the `round`th time
+fn barrier(round: u32) {
+    BARRIER.fetch_add(1, Ordering::SeqCst);
+    while BARRIER.load(Ordering::SeqCst) < round * acpi::num_cores() {}
+}
+
+/// Reset the state of the tests left behind by a previous boot, as statics
+/// survive soft reboots. This must be called on the BSP before the other
+/// cores are launched.
+pub fn reset() {
+    *TEST_LOCK.lock() = (0, 0);
+    FAILURES.store(0, Ordering::SeqCst);
+    BARRIER.store(0, Ordering::SeqCst);
+}
+
+/// Hammer on a lock from all cores, making sure nobody ever observes the
+/// protected data in the middle of an update
+fn lock_stress() -> bool {
+    let mut passed = true;
+
+    for _ in 0..LOCK_ITERS {
+        let mut pair = TEST_LOCK.lock();
+        passed &= pair.0 == pair.1;
+
+        // Update the values separately such that another core holding the
+        // lock at the same time would see them differ
+        pair.0 += 1;
+        cpu::delay(10);
+        pair.1 += 1;
+    }
+
+    passed
+}
+
+/// Make sure no lock acquisitions were lost during `lock_stress()`
+fn lock_count() -> bool {
+    if core!().id != 0 { return true; }
+
+    let expected = LOCK_ITERS * acpi::num_cores() as u64;
+    *TEST_LOCK.lock() == (expected, expected)
+}
+
+/// Make random allocations from all cores, making sure the memory of every
+/// allocation is left intact until it is freed
+fn allocator() -> bool {
+    let mut passed = true;
+
+    // Seed a xorshift per core
+    let mut seed = cpu::rdtsc() ^ ((core!().id as u64 + 1) << 32);
+    let mut rand = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 43;
+        seed
+    };
+
+    let mut live: Vec<Vec<u8>> = Vec::with_capacity(ALLOC_LIVE);
+    for ii in 0..ALLOC_ITERS {
+        // Free a random allocation once we have enough of them, checking it
+        // still holds the pattern it was filled with
+        if live.len() == ALLOC_LIVE {
+            let alloc = live.swap_remove(rand() as usize % ALLOC_LIVE);
+            let pattern = alloc[0];
+            passed &= alloc.iter().all(|&x| x == pattern);
+        }
+
+        // Mostly small allocations, with the occasional large one
+        let size = match rand() % 16 {
+            0 => 1 + rand() as usize % (1024 * 1024),
+            _ => 1 + rand() as usize % 4096,
+        };
+        live.push(vec![ii as u8; size]);
+    }
+
+    // Check all remaining allocations
+    passed && live.iter().all(|alloc| alloc.iter().all(|&x| x == alloc[0]))
+}
+
+/// Map, translate, and free memory in a new page table
+fn page_tables() -> bool {
+    if core!().id != 0 { return true; }
+
+    let mut pmem  = PhysicalMemory;
+    let mut table = PageTable::new(&mut pmem);
+    let pattern   = |offset: u64| (offset as u8) ^ 0x5a;
+
+    // Map memory with known contents
+    if table.map_init(&mut pmem, PAGE_TEST_VADDR, PageType::Page4K,
+            PAGE_TEST_SIZE, true, true, false, Some(pattern)).is_none() {
+        return false;
+    }
+
+    // Every page must be backed by memory with the contents we gave it
+    let mut passed = (0..PAGE_TEST_SIZE).step_by(4096).all(|offset| {
+        table.translate(&mut pmem, VirtAddr(PAGE_TEST_VADDR.0 + offset))
+            .and_then(|x| x.page)
+            .map(|(page, _)| {
+                let byte: u8 = unsafe { mm::read_phys(PhysAddr(page.0 + 17)) };
+                byte == pattern(offset + 17)
+            }) == Some(true)
+    });
+
+    // Mapping over an existing mapping must fail
+    passed &= table.map(&mut pmem, PAGE_TEST_VADDR, PageType::Page4K, 4096,
+                        true, true, false).is_none();
+
+    // Free the memory and make sure none of it is mapped anymore
+    unsafe { table.free(&mut pmem, PAGE_TEST_VADDR, PAGE_TEST_SIZE); }
+    passed &= (0..PAGE_TEST_SIZE).step_by(4096).all(|offset| {
+        table.translate(&mut pmem, VirtAddr(PAGE_TEST_VADDR.0 + offset))
+            .and_then(|x| x.page).is_none()
+    });
+
+    // The intermediate tables were freed with the mappings, free the root
+    pmem.free_phys(table.table(), 4096);
+
+    passed
+}
+
+/// Run all self tests, this must be called on every core once all cores have
+/// checked in
+pub fn run() {
+    let mut failed  = 0;
+    let mut results = String::new();
+
+    for (ii, &(name, test)) in TESTS.iter().enumerate() {
+        if !test() {
+            FAILURES.fetch_add(1, Ordering::SeqCst);
+        }
+
+        // Wait for all cores to finish the test
+        barrier(ii as u32 * 2 + 1);
+
+        // Report the result
+        if core!().id == 0 {
+            let failures = FAILURES.swap(0, Ordering::SeqCst);
+            let start = results.len();
+            let _ = if failures == 0 {
+                write!(results, "Self test {:<28} PASS\n", name)
+            } else {
+                failed += 1;
+                write!(results, "Self test {:<28} FAIL on {} cores\n",
+                       name, failures)
+            };
+            print!("{}", &results[start..]);
+        }
+
+        // Don't start the next test until the result has been reported
+        barrier(ii as u32 * 2 + 2);
+    }
+
+    // Let the server know how this node did before giving up on it
+    if core!().id == 0 {
+        report::send(report::Kind::SelfTest, results.as_bytes());
+    }
+
+    assert!(failed == 0, "{} of {} self tests failed", failed, TESTS.len());
+}
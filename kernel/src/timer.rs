@@ -0,0 +1,107 @@
+//! Per-core deadline timers driven by the APIC timer
+//!
+//! Every core has a list of pending deadlines. The APIC timer wakes the core
+//! for every tick, and when the TSC-deadline timer mode is available the
+//! APIC timer is programmed to fire exactly at the earliest deadline rather
+//! than waiting for the next tick.
+//!
+//! Callbacks are never invoked from the interrupt handler itself, as they are
+//! boxed closures which may allocate and which have to be freed once run.
+//! Instead the interrupt only wakes the core, and the executor loop of the
+//! core invokes the expired callbacks outside of interrupt context.
+
+use alloc::boxed::Box;
+
+use crate::time;
+use crate::tunables::Tunable;
+
+/// Interval between APIC timer ticks in microseconds. This bounds the
+/// resolution of deadlines when the TSC-deadline timer mode is not available.
+pub static TICK_MICROSECONDS: Tunable =
+    Tunable::new("tick_microseconds", 10_000);
+
+/// A callback to invoke once the TSC has reached a given value
+pub struct Deadline {
+    /// TSC value at which this deadline expires
+    tsc: u64,
+
+    /// Callback to invoke when the deadline expires
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+/// Register a `callback` to be invoked on the current core once
+/// `microseconds` have elapsed.
+///
+/// Callbacks are invoked by the executor loop of the core, thus they run in
+/// between tasks and should be short. Code which spins without returning to
+/// the executor can call `run_expired()` to dispatch deadlines itself.
+pub fn set_deadline<F>(microseconds: u64, callback: F)
+        where F: FnOnce() + Send + 'static {
+    assert!(!core!().in_interrupt(),
+            "Attempted to set a deadline in an interrupt");
+
+    // Compute the TSC value at which this deadline expires
+    let tsc = time::future(microseconds);
+
+    {
+        // Box the callback before taking the lock, which keeps interrupts
+        // disabled while it is held
+        let callback: Box<dyn FnOnce() + Send> = Box::new(callback);
+
+        // Insert the deadline sorted by expiry, earliest first
+        let mut deadlines = core!().deadlines.lock();
+        let idx = deadlines.iter().position(|x| x.tsc > tsc)
+            .unwrap_or(deadlines.len());
+        deadlines.insert(idx, Deadline { tsc, callback });
+    }
+
+    // Make sure the APIC timer fires in time for this deadline
+    rearm();
+}
+
+/// Invoke the callbacks of all deadlines on the current core which have
+/// expired. This must not be called from an interrupt, as the callbacks are
+/// freed once they were invoked.
+pub fn run_expired() {
+    assert!(!core!().in_interrupt(),
+            "Attempted to run deadlines in an interrupt");
+
+    loop {
+        // Pop off the earliest deadline if it has expired. The lock must be
+        // released before invoking the callback such that the callback can
+        // register new deadlines.
+        let deadline = {
+            let mut deadlines = core!().deadlines.lock();
+            match deadlines.first() {
+                Some(x) if x.tsc <= cpu::rdtsc() => deadlines.remove(0),
+                _ => break,
+            }
+        };
+
+        (deadline.callback)();
+    }
+}
+
+/// Program the APIC timer to fire at the earlier of the next tick and the
+/// earliest pending deadline. This has no effect if the APIC timer is in
+/// periodic mode.
+fn rearm() {
+    // Compute when the next tick should occur
+    let next_tick = time::future(TICK_MICROSECONDS.get());
+
+    // Get the earliest pending deadline
+    let next = core!().deadlines.lock().first()
+        .map(|x| core::cmp::min(x.tsc, next_tick))
+        .unwrap_or(next_tick);
+
+    if let Some(apic) = core!().apic.lock().as_mut() {
+        unsafe { apic.set_timer_deadline(next); }
+    }
+}
+
+/// Handle an APIC timer interrupt by programming the timer for the next tick.
+/// Expired deadlines are left for the executor loop, which the interrupt woke
+/// up, to invoke.
+pub unsafe fn tick() {
+    rearm();
+}
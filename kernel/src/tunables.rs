@@ -0,0 +1,125 @@
+//! Registry of runtime tunable values
+//!
+//! Tunables are named atomic values which replace compile-time constants for
+//! behaviors we want to adjust without rebuilding the kernel. Their defaults
+//! can be overridden at boot by placing a `chocolate_milk.tunables` file next
+//! to the kernel, which contains lines of `name = value`. Values may be
+//! decimal or `0x` prefixed hex, and `#` starts a comment.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+
+/// A named value which can be changed at runtime
+pub struct Tunable {
+    /// Name of the tunable, used to set it
+    name: &'static str,
+
+    /// Current value of the tunable
+    value: AtomicU64,
+}
+
+impl Tunable {
+    /// Create a new tunable named `name` with a default of `value`
+    pub const fn new(name: &'static str, value: u64) -> Self {
+        Tunable { name, value: AtomicU64::new(value) }
+    }
+
+    /// Get the current value of the tunable
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Update the value of the tunable
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+}
+
+/// All tunables on the system
+static TUNABLES: &[&Tunable] = &[
+    &crate::timer::TICK_MICROSECONDS,
+    &crate::pci::DEBUG_PCI_DEVICES,
+    &crate::physmap::DUMP_PHYS_REGIONS,
+    &crate::memtest::MEMTEST,
+    &crate::selftest::SELFTEST,
+    &crate::acpi::CORE_LIMIT,
+    &crate::acpi::SMT_POLICY,
+    &crate::hardening::FAULT_DIAGNOSTICS,
+    #[cfg(feature = "lock_hold_check")]
+    &crate::core_locals::LOCK_HOLD_BUDGET,
+    #[cfg(feature = "network")]
+    &crate::dhcp::RETRANSMIT_TIMEOUT,
+    #[cfg(feature = "network")]
+    &crate::net::LINK_POLL_INTERVAL,
+    #[cfg(feature = "network")]
+    &crate::net::TX_BATCH_SIZE,
+    #[cfg(feature = "network")]
+    &crate::net::MTU,
+    #[cfg(feature = "network")]
+    &crate::beacon::BEACON_TIMEOUT,
+];
+
+/// Look up a tunable by `name`
+pub fn find(name: &str) -> Option<&'static Tunable> {
+    TUNABLES.iter().find(|x| x.name == name).copied()
+}
+
+/// Parse a decimal or `0x` prefixed hex value
+fn parse_value(value: &str) -> Option<u64> {
+    if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// Apply the tunable overrides provided by the bootloader, if any
+pub unsafe fn init() {
+    // Get the tunables file provided by the bootloader
+    let addr = core!().boot_args.tunables_addr.load(Ordering::SeqCst);
+    let size = core!().boot_args.tunables_size.load(Ordering::SeqCst);
+    if addr == 0 || size == 0 {
+        return;
+    }
+
+    // Make sure the file is in our physical window
+    let end = size.checked_sub(1).and_then(|x| x.checked_add(addr))
+        .expect("Integer overflow on tunables range");
+    assert!(end < KERNEL_PHYS_WINDOW_SIZE,
+        "Tunables outside of physical window");
+
+    // Get access to the file contents
+    let contents = core::slice::from_raw_parts(
+        (KERNEL_PHYS_WINDOW_BASE + addr) as *const u8,
+        size as usize);
+    let contents = core::str::from_utf8(contents)
+        .expect("Tunables file is not valid UTF-8");
+
+    apply(contents);
+}
+
+/// Apply the tunable overrides in `contents`, which holds lines of
+/// `name = value`
+pub fn apply(contents: &str) {
+    for line in contents.lines() {
+        // Strip off comments and whitespace, skipping empty lines
+        let line = line.splitn(2, '#').next().unwrap().trim();
+        if line.is_empty() { continue; }
+
+        // Split the line into the name and value
+        let mut split = line.splitn(2, '=');
+        let name  = split.next().unwrap().trim();
+        let value = split.next().map(|x| x.trim()).and_then(parse_value);
+
+        match (find(name), value) {
+            (Some(tunable), Some(value)) => {
+                print!("Tunable {} = {:#x}\n", name, value);
+                tunable.set(value);
+            }
+            (None, _) => print!("Unknown tunable {}, ignoring\n", name),
+            (_, None) => print!("Invalid value for tunable {}, ignoring\n",
+                                name),
+        }
+    }
+}
@@ -36,6 +36,28 @@ pub const KERNEL_STACK_PAD: u64 = 32 * 1024;
 /// Size of the kernel physical window (in bytes)
 pub const KERNEL_PHYS_WINDOW_SIZE: u64 = 64 * 1024 * 1024 * 1024;
 
+/// Size of the crash ring which persists crash artifacts across soft reboots
+pub const CRASH_RING_SIZE: u64 = 1024 * 1024;
+
+/// Alignment of all randomized virtual bases
+pub const KASLR_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// Lowest virtual address the kernel image may be randomly loaded at
+pub const KERNEL_IMAGE_RANDOM_BASE: u64 = 0x0000_1000_0000_0000;
+
+/// Size of the region which the kernel image base is randomized within
+pub const KERNEL_IMAGE_RANDOM_SIZE: u64 = 0x0000_3000_0000_0000;
+
+/// Lowest virtual address for the randomized kernel stacks base
+pub const KERNEL_STACKS_RANDOM_BASE: u64 = 0x0000_4000_0000_0000;
+
+/// Size of the region which the kernel stacks base is randomized within
+pub const KERNEL_STACKS_RANDOM_SIZE: u64 = 0x0000_3000_0000_0000;
+
+/// Size of the region above `KERNEL_VMEM_BASE` which the base of dynamic
+/// virtual allocations is randomized within
+pub const KERNEL_VMEM_RANDOM_SIZE: u64 = 0x0000_1000_0000_0000;
+
 /// Structures to pass between both the 32-bit and 64-bit modes. This structure
 /// MUST be identical in both modes. Thus, no using pointers, references, or
 /// usizes. Also, make sure everything is marked `#[repr(C)]` otherwise the
@@ -83,5 +105,97 @@ pub struct BootArgs<I: InterruptState> {
 
     /// Address of the soft reboot entry point (0 means uninitialized)
     pub soft_reboot_addr: AtomicU64,
+
+    /// Physical address of the microcode update blob downloaded by the
+    /// bootloader (0 means no microcode was provided)
+    pub microcode_addr: AtomicU64,
+
+    /// Size of the microcode update blob in bytes
+    pub microcode_size: AtomicU64,
+
+    /// Difference between the address the kernel was loaded at and the base
+    /// address it was linked at. Add this to a linked address to get the
+    /// runtime address, subtract it from a runtime address to get an address
+    /// which can be symbolized against the kernel image.
+    pub kernel_slide: AtomicU64,
+
+    /// Randomized base address for dynamic virtual allocations in the kernel
+    pub vmem_base: AtomicU64,
+
+    /// Virtual address of the start of the loaded kernel image
+    pub kernel_image_base: AtomicU64,
+
+    /// Size of the loaded kernel image in bytes, from the start of the first
+    /// section to the end of the last section
+    pub kernel_image_size: AtomicU64,
+
+    /// Role of this node, as a raw `Role`
+    pub role: AtomicU64,
+
+    /// Physical address of the tunables file downloaded by the bootloader
+    /// (0 means no tunables were provided)
+    pub tunables_addr: AtomicU64,
+
+    /// Size of the tunables file in bytes
+    pub tunables_size: AtomicU64,
+
+    /// Physical address of the key authenticating remote commands, downloaded
+    /// by the bootloader (0 means no key was provided)
+    pub command_key_addr: AtomicU64,
+
+    /// Size of the command key in bytes
+    pub command_key_size: AtomicU64,
+
+    /// All usable RAM as reported by the firmware memory map, regardless of
+    /// whether it has been allocated
+    pub memory_map: LockCell<Option<RangeSet>, I>,
+
+    /// Physical address of the crash ring, `CRASH_RING_SIZE` bytes of memory
+    /// which is placed at the same address on every boot
+    pub crash_ring_addr: AtomicU64,
 }
 
+/// Site-specific DHCP option in which the DHCP server can give a node the name
+/// of its role as part of its boot arguments
+pub const ROLE_DHCP_OPTION: u8 = 224;
+
+/// The role of a node. The same kernel image is deployed to all nodes, and
+/// the role selects which subsystems the kernel initializes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u64)]
+pub enum Role {
+    /// Node which runs fuzz cases
+    FuzzWorker = 0,
+
+    /// Node which reproduces and triages crashes
+    Triage = 1,
+
+    /// Rig for testing the network drivers and stack
+    NetTest = 2,
+}
+
+impl Role {
+    /// Parse a role from its `name`, ignoring surrounding whitespace
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        // Strip off leading and trailing whitespace
+        let start = name.iter().position(|x| !x.is_ascii_whitespace())?;
+        let end   = name.iter().rposition(|x| !x.is_ascii_whitespace())?;
+
+        match &name[start..=end] {
+            b"fuzz_worker" => Some(Role::FuzzWorker),
+            b"triage"      => Some(Role::Triage),
+            b"net_test"    => Some(Role::NetTest),
+            _              => None,
+        }
+    }
+
+    /// Convert a raw role, as stored in `BootArgs`, into a `Role`
+    pub fn from_raw(raw: u64) -> Option<Self> {
+        match raw {
+            0 => Some(Role::FuzzWorker),
+            1 => Some(Role::Triage),
+            2 => Some(Role::NetTest),
+            _ => None,
+        }
+    }
+}
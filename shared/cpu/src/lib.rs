@@ -86,6 +86,21 @@ pub fn rdtsc() -> u64 {
     ((val_hi as u64) << 32) | val_lo as u64
 }
 
+/// Get a random 32-bit number from the hardware random number generator.
+/// Returns `None` if the generator did not have a random number available.
+///
+/// It's up to the caller to make sure `rdrand` is supported.
+#[inline]
+pub unsafe fn rdrand32() -> Option<u32> {
+    let val: u32;
+    let success: u8;
+
+    asm!("rdrand $0 ; setc $1" : "=r"(val), "={cl}"(success) :: "cc" :
+         "volatile", "intel");
+
+    if success != 0 { Some(val) } else { None }
+}
+
 /// Get the GS base
 #[inline]
 pub unsafe fn gs_base() -> u64 {
@@ -110,6 +125,16 @@ pub fn halt() -> ! {
     }
 }
 
+/// Halt until the next interrupt arrives
+#[inline]
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!(r#"
+            hlt
+        "# :::: "volatile", "intel");
+    }
+}
+
 /// Canonicalize an address
 #[inline]
 pub fn canonicalize_address(addr: u64) -> u64 {
@@ -129,6 +154,22 @@ pub unsafe fn cpuid(eax: u32, ecx: u32) -> (u32, u32, u32, u32) {
     (oeax, oebx, oecx, oedx)
 }
 
+/// Read `cr0`
+#[inline]
+pub fn read_cr0() -> u64 {
+    let val: u64;
+    unsafe {
+        asm!("mov $0, cr0" : "=r"(val) :: "memory" : "volatile", "intel");
+    }
+    val
+}
+
+/// Write to `cr0`
+#[inline]
+pub unsafe fn write_cr0(val: u64) {
+    asm!("mov cr0, $0" :: "r"(val) : "memory" : "volatile", "intel");
+}
+
 /// Read `cr2`
 #[inline]
 pub fn read_cr2() -> u64 {
@@ -161,6 +202,35 @@ pub unsafe fn write_cr3(val: u64) {
     asm!("mov cr3, $0" :: "r"(val) : "memory" : "volatile", "intel");
 }
 
+/// Read `cr4`
+#[inline]
+pub fn read_cr4() -> u64 {
+    let val: u64;
+    unsafe {
+        asm!("mov $0, cr4" : "=r"(val) :: "memory" : "volatile", "intel");
+    }
+    val
+}
+
+/// Write to `cr4`
+#[inline]
+pub unsafe fn write_cr4(val: u64) {
+    asm!("mov cr4, $0" :: "r"(val) : "memory" : "volatile", "intel");
+}
+
+/// Write back and invalidate all caches
+#[inline]
+pub unsafe fn wbinvd() {
+    asm!("wbinvd" ::: "memory" : "volatile", "intel");
+}
+
+/// Write back and invalidate the cache line containing `addr`
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn clflush(addr: usize) {
+    asm!("clflush [$0]" :: "r"(addr) : "memory" : "volatile", "intel");
+}
+
 /// Get the current flags
 #[inline]
 #[cfg(target_arch = "x86_64")]
@@ -186,9 +256,138 @@ pub fn delay(cycles: u64) {
     }
 }
 
+/// MSR reporting basic VMX capabilities
+const IA32_VMX_BASIC: u32 = 0x480;
+
+/// MSR reporting allowed settings for pin-based VM-execution controls
+const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+
+/// MSR reporting allowed settings for primary processor-based VM-execution
+/// controls
+const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+
+/// MSR reporting allowed settings for VM-exit controls
+const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+
+/// MSR reporting allowed settings for VM-entry controls
+const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+
+/// MSR reporting miscellaneous VMX information
+const IA32_VMX_MISC: u32 = 0x485;
+
+/// MSR reporting allowed settings for secondary processor-based VM-execution
+/// controls
+const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+
+/// MSR reporting EPT and VPID capabilities
+const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+
+/// Raw VMX capability MSRs, only valid to read when VMX is supported
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxCapabilities {
+    /// Raw `IA32_VMX_BASIC`
+    pub basic: u64,
+
+    /// Raw `IA32_VMX_PINBASED_CTLS`
+    pub pinbased_ctls: u64,
+
+    /// Raw `IA32_VMX_PROCBASED_CTLS`
+    pub procbased_ctls: u64,
+
+    /// Raw `IA32_VMX_PROCBASED_CTLS2`, zero if secondary controls are not
+    /// supported
+    pub procbased_ctls2: u64,
+
+    /// Raw `IA32_VMX_EXIT_CTLS`
+    pub exit_ctls: u64,
+
+    /// Raw `IA32_VMX_ENTRY_CTLS`
+    pub entry_ctls: u64,
+
+    /// Raw `IA32_VMX_MISC`
+    pub misc: u64,
+
+    /// Raw `IA32_VMX_EPT_VPID_CAP`, zero if neither EPT nor VPID are
+    /// supported
+    pub ept_vpid_cap: u64,
+}
+
+impl VmxCapabilities {
+    /// Read the VMX capability MSRs. It's up to the caller to make sure VMX
+    /// is supported, otherwise these reads will #GP.
+    unsafe fn read() -> Self {
+        let mut caps = VmxCapabilities {
+            basic:          rdmsr(IA32_VMX_BASIC),
+            pinbased_ctls:  rdmsr(IA32_VMX_PINBASED_CTLS),
+            procbased_ctls: rdmsr(IA32_VMX_PROCBASED_CTLS),
+            exit_ctls:      rdmsr(IA32_VMX_EXIT_CTLS),
+            entry_ctls:     rdmsr(IA32_VMX_ENTRY_CTLS),
+            misc:           rdmsr(IA32_VMX_MISC),
+            ..Default::default()
+        };
+
+        // Secondary controls only exist if the allowed-1 setting for
+        // "activate secondary controls" (bit 31) is set
+        if (caps.procbased_ctls >> 63) & 1 == 1 {
+            caps.procbased_ctls2 = rdmsr(IA32_VMX_PROCBASED_CTLS2);
+
+            // The EPT/VPID capability MSR only exists if EPT (bit 1) or VPID
+            // (bit 5) can be enabled
+            if (caps.procbased_ctls2 >> 33) & 1 == 1 ||
+                    (caps.procbased_ctls2 >> 37) & 1 == 1 {
+                caps.ept_vpid_cap = rdmsr(IA32_VMX_EPT_VPID_CAP);
+            }
+        }
+
+        caps
+    }
+
+    /// Returns `true` if extended page tables can be enabled
+    pub fn ept(&self) -> bool {
+        (self.procbased_ctls2 >> 33) & 1 == 1
+    }
+
+    /// Returns `true` if virtual processor IDs can be enabled
+    pub fn vpid(&self) -> bool {
+        (self.procbased_ctls2 >> 37) & 1 == 1
+    }
+
+    /// Returns `true` if unrestricted guests can be enabled
+    pub fn unrestricted_guest(&self) -> bool {
+        (self.procbased_ctls2 >> 39) & 1 == 1
+    }
+
+    /// Returns `true` if the VMX-preemption timer can be enabled
+    pub fn preemption_timer(&self) -> bool {
+        (self.pinbased_ctls >> 38) & 1 == 1
+    }
+
+    /// Get the rate of the VMX-preemption timer relative to the TSC. The
+    /// preemption timer counts down by 1 every time bit `X` of the TSC
+    /// changes, where `X` is the value returned here.
+    pub fn preemption_timer_shift(&self) -> u32 {
+        (self.misc & 0x1f) as u32
+    }
+
+    /// Returns `true` if the guest TSC can be offset from the host TSC
+    pub fn tsc_offsetting(&self) -> bool {
+        (self.procbased_ctls >> 35) & 1 == 1
+    }
+
+    /// Returns `true` if the guest TSC can be scaled relative to the host TSC
+    pub fn tsc_scaling(&self) -> bool {
+        (self.procbased_ctls2 >> 57) & 1 == 1
+    }
+
+    /// Returns `true` if the EPT supports accessed and dirty flags
+    pub fn ept_accessed_dirty(&self) -> bool {
+        (self.ept_vpid_cap >> 21) & 1 == 1
+    }
+}
+
 /// Structure representing the various CPU features which are supported on this
 /// system. These can be detected with the `get_cpu_features` function
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct CPUFeatures {
     pub max_cpuid: u32,
     pub max_extended_cpuid: u32,
@@ -208,8 +407,10 @@ pub struct CPUFeatures {
     pub sse4_1: bool,
     pub sse4_2: bool,
     pub x2apic: bool,
+    pub tsc_deadline: bool,
     pub xsave: bool,
     pub avx: bool,
+    pub rdrand: bool,
     pub apic: bool,
 
     pub vmx: bool,
@@ -217,6 +418,7 @@ pub struct CPUFeatures {
     pub lahf: bool,
     pub lzcnt: bool,
     pub prefetchw: bool,
+    pub svm: bool,
 
     pub syscall: bool,
     pub xd: bool,
@@ -224,7 +426,36 @@ pub struct CPUFeatures {
     pub rdtscp: bool,
     pub bits64: bool,
 
+    pub smep: bool,
     pub avx512f: bool,
+    pub smap: bool,
+    pub erms: bool,
+    pub rtm: bool,
+    pub waitpkg: bool,
+
+    pub invariant_tsc: bool,
+
+    /// Number of low bits of an APIC ID which select the hardware thread
+    /// within a physical core, from CPUID leaf 0xb. `None` if the leaf is not
+    /// supported.
+    pub smt_shift: Option<u32>,
+
+    /// VMX capabilities, `None` if VMX is not supported
+    pub vmx_caps: Option<VmxCapabilities>,
+}
+
+impl CPUFeatures {
+    /// Get the ID of the physical core which the hardware thread with
+    /// `apic_id` belongs to
+    pub fn physical_core(&self, apic_id: u32) -> u32 {
+        apic_id >> self.smt_shift.unwrap_or(0)
+    }
+
+    /// Get the index of the hardware thread with `apic_id` within its
+    /// physical core
+    pub fn smt_thread(&self, apic_id: u32) -> u32 {
+        apic_id & ((1 << self.smt_shift.unwrap_or(0)) - 1)
+    }
 }
 
 /// Get set of CPU features
@@ -255,20 +486,28 @@ pub fn get_cpu_features() -> CPUFeatures {
             features.sse4_1  = ((cpuid_1.2 >> 19) & 1) == 1;
             features.sse4_2  = ((cpuid_1.2 >> 20) & 1) == 1;
             features.x2apic  = ((cpuid_1.2 >> 21) & 1) == 1;
+            features.tsc_deadline = ((cpuid_1.2 >> 24) & 1) == 1;
             features.xsave   = ((cpuid_1.2 >> 26) & 1) == 1;
             features.avx     = ((cpuid_1.2 >> 28) & 1) == 1;
+            features.rdrand  = ((cpuid_1.2 >> 30) & 1) == 1;
         }
 
-        // Detect AVX-512 support
+        // Detect AVX-512 and other structured extended features
         if features.max_cpuid >= 7 {
             let cpuid_7 = cpuid(7, 0);
+            features.smep    = ((cpuid_7.1 >>  7) & 1) == 1;
+            features.erms    = ((cpuid_7.1 >>  9) & 1) == 1;
+            features.rtm     = ((cpuid_7.1 >> 11) & 1) == 1;
             features.avx512f = ((cpuid_7.1 >> 16) & 1) == 1;
+            features.smap    = ((cpuid_7.1 >> 20) & 1) == 1;
+            features.waitpkg = ((cpuid_7.2 >>  5) & 1) == 1;
         }
 
         if features.max_extended_cpuid >= 0x80000001 {
             let cpuid_e1 = cpuid(0x80000001, 0);
 
             features.lahf      = ((cpuid_e1.2 >> 0) & 1) == 1;
+            features.svm       = ((cpuid_e1.2 >> 2) & 1) == 1;
             features.lzcnt     = ((cpuid_e1.2 >> 5) & 1) == 1;
             features.prefetchw = ((cpuid_e1.2 >> 8) & 1) == 1;
 
@@ -278,8 +517,28 @@ pub fn get_cpu_features() -> CPUFeatures {
             features.rdtscp      = ((cpuid_e1.3 >> 27) & 1) == 1;
             features.bits64      = ((cpuid_e1.3 >> 29) & 1) == 1;
         }
+
+        // Detect whether the TSC runs at a constant rate in all ACPI P-, C-,
+        // and T-states
+        if features.max_extended_cpuid >= 0x80000007 {
+            let cpuid_e7 = cpuid(0x80000007, 0);
+            features.invariant_tsc = ((cpuid_e7.3 >> 8) & 1) == 1;
+        }
+
+        // Get the SMT level of the processor topology. The first sub-leaf
+        // of leaf 0xb describes the SMT level, if the leaf is valid.
+        if features.max_cpuid >= 0xb {
+            let cpuid_b = cpuid(0xb, 0);
+            if cpuid_b.1 != 0 && ((cpuid_b.2 >> 8) & 0xff) == 1 {
+                features.smt_shift = Some(cpuid_b.0 & 0x1f);
+            }
+        }
+
+        // Read the VMX capabilities if VMX is supported
+        if features.vmx {
+            features.vmx_caps = Some(VmxCapabilities::read());
+        }
     }
 
     features
 }
-
@@ -0,0 +1,427 @@
+//! BLAKE3 in its default hashing mode, with a 32-byte output
+
+use core::convert::TryInto;
+
+/// Initial chaining value, shared with SHA-256
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Permutation of the message words applied between rounds
+const MSG_PERMUTATION: [usize; 16] =
+    [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+/// Flag set on the first block of a chunk
+const CHUNK_START: u32 = 1 << 0;
+
+/// Flag set on the last block of a chunk
+const CHUNK_END: u32 = 1 << 1;
+
+/// Flag set on parent nodes of the tree
+const PARENT: u32 = 1 << 2;
+
+/// Flag set on the root of the tree
+const ROOT: u32 = 1 << 3;
+
+/// Size of a block in bytes
+const BLOCK_LEN: usize = 64;
+
+/// Size of a chunk, the leaves of the tree, in bytes
+const CHUNK_LEN: usize = 1024;
+
+/// The mixing function, mixing a column or diagonal of the state with two
+/// message words
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize,
+     mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+/// A full round, mixing all columns and then all diagonals
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // Mix the columns
+    g(state, 0, 4,  8, 12, m[ 0], m[ 1]);
+    g(state, 1, 5,  9, 13, m[ 2], m[ 3]);
+    g(state, 2, 6, 10, 14, m[ 4], m[ 5]);
+    g(state, 3, 7, 11, 15, m[ 6], m[ 7]);
+
+    // Mix the diagonals
+    g(state, 0, 5, 10, 15, m[ 8], m[ 9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7,  8, 13, m[12], m[13]);
+    g(state, 3, 4,  9, 14, m[14], m[15]);
+}
+
+/// The compression function, using SSSE3 if the CPU has it
+fn compress(cv: &[u32; 8], block: &[u32; 16], counter: u64,
+            block_len: u32, flags: u32) -> [u32; 16] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if crate::x86::has_ssse3() {
+            return unsafe {
+                crate::x86::blake3_compress(cv, block, counter, block_len,
+                                            flags, &IV, &MSG_PERMUTATION)
+            };
+        }
+    }
+
+    compress_portable(cv, block, counter, block_len, flags)
+}
+
+/// The compression function
+fn compress_portable(cv: &[u32; 8], block: &[u32; 16], counter: u64,
+                     block_len: u32, flags: u32) -> [u32; 16] {
+    let mut state = [
+        cv[0], cv[1], cv[2], cv[3],
+        cv[4], cv[5], cv[6], cv[7],
+        IV[0], IV[1], IV[2], IV[3],
+        counter as u32, (counter >> 32) as u32, block_len, flags,
+    ];
+
+    // Perform the 7 rounds, permuting the message between each
+    let mut block = *block;
+    for ii in 0..7 {
+        round(&mut state, &block);
+
+        if ii != 6 {
+            let mut permuted = [0u32; 16];
+            for (dst, &src) in permuted.iter_mut().zip(MSG_PERMUTATION.iter()) {
+                *dst = block[src];
+            }
+            block = permuted;
+        }
+    }
+
+    // Fold the state
+    for ii in 0..8 {
+        state[ii]     ^= state[ii + 8];
+        state[ii + 8] ^= cv[ii];
+    }
+    state
+}
+
+/// Get the first 8 words of a compression output
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    let mut ret = [0u32; 8];
+    ret.copy_from_slice(&words[..8]);
+    ret
+}
+
+/// Read a block of bytes as little endian words
+fn words(block: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0u32; 16];
+    for (ii, word) in words.iter_mut().enumerate() {
+        *word = u32::from_le_bytes(
+            block[ii * 4..ii * 4 + 4].try_into().unwrap());
+    }
+    words
+}
+
+/// The inputs of a compression which has not been performed yet. This is
+/// deferred as the final compression of the root node gets the `ROOT` flag.
+struct Output {
+    input_cv:  [u32; 8],
+    block:     [u32; 16],
+    counter:   u64,
+    block_len: u32,
+    flags:     u32,
+}
+
+impl Output {
+    /// Compute the chaining value of a non-root node
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(compress(&self.input_cv, &self.block, self.counter,
+                         self.block_len, self.flags))
+    }
+
+    /// Compute the 32-byte digest of the root node
+    fn root_hash(&self) -> [u8; 32] {
+        let words = compress(&self.input_cv, &self.block, 0,
+                             self.block_len, self.flags | ROOT);
+
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+/// Output of a parent node with children `left` and `right`
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block = [0u32; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+
+    Output {
+        input_cv:  IV,
+        block,
+        counter:   0,
+        block_len: BLOCK_LEN as u32,
+        flags:     PARENT,
+    }
+}
+
+/// State of the chunk currently being hashed
+#[derive(Clone)]
+struct ChunkState {
+    /// Chaining value of the blocks compressed so far
+    cv: [u32; 8],
+
+    /// Index of this chunk
+    chunk_counter: u64,
+
+    /// Partially filled block
+    block: [u8; BLOCK_LEN],
+
+    /// Number of valid bytes in `block`
+    block_len: usize,
+
+    /// Number of blocks which have been compressed into `cv`
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    /// Start a new chunk with index `chunk_counter`
+    fn new(chunk_counter: u64) -> Self {
+        ChunkState {
+            cv: IV,
+            chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    /// Number of bytes hashed into this chunk
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    /// Flags for the current block, marking the first block of the chunk
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 { CHUNK_START } else { 0 }
+    }
+
+    /// Add `data` to the chunk. This must not exceed the size of a chunk.
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // Compress the block once it's full and there's more data. The
+            // last block is left uncompressed for `output()`.
+            if self.block_len == BLOCK_LEN {
+                self.cv = first_8(compress(&self.cv, &words(&self.block),
+                    self.chunk_counter, BLOCK_LEN as u32, self.start_flag()));
+                self.blocks_compressed += 1;
+                self.block     = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+
+            // Fill up the block
+            let to_copy = core::cmp::min(BLOCK_LEN - self.block_len,
+                                         data.len());
+            self.block[self.block_len..self.block_len + to_copy]
+                .copy_from_slice(&data[..to_copy]);
+            self.block_len += to_copy;
+            data = &data[to_copy..];
+        }
+    }
+
+    /// Get the output of the last block of the chunk
+    fn output(&self) -> Output {
+        Output {
+            input_cv:  self.cv,
+            block:     words(&self.block),
+            counter:   self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags:     self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+/// Incremental BLAKE3 hasher
+#[derive(Clone)]
+pub struct Blake3 {
+    /// Chunk currently being hashed
+    chunk: ChunkState,
+
+    /// Chaining values of the subtrees which are not yet complete. With 54
+    /// entries this can hash up to 2^64 bytes.
+    cv_stack: [[u32; 8]; 54],
+
+    /// Number of valid entries in `cv_stack`
+    cv_stack_len: usize,
+}
+
+impl Blake3 {
+    /// Create a new hasher
+    pub fn new() -> Self {
+        Blake3 {
+            chunk:        ChunkState::new(0),
+            cv_stack:     [[0; 8]; 54],
+            cv_stack_len: 0,
+        }
+    }
+
+    /// Add the chaining value of a completed chunk to the tree, merging
+    /// completed subtrees. `total_chunks` is the number of chunks completed
+    /// so far, including this one.
+    fn add_chunk_cv(&mut self, mut cv: [u32; 8], mut total_chunks: u64) {
+        // Each trailing zero bit of the chunk count is a subtree which this
+        // chunk completes
+        while (total_chunks & 1) == 0 {
+            self.cv_stack_len -= 1;
+            cv = parent_output(self.cv_stack[self.cv_stack_len], cv)
+                .chaining_value();
+            total_chunks >>= 1;
+        }
+
+        self.cv_stack[self.cv_stack_len] = cv;
+        self.cv_stack_len += 1;
+    }
+
+    /// Add `data` to the hash
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // Finish the current chunk if it's full and there's more data.
+            // The last chunk is left unfinished for `finish()`.
+            if self.chunk.len() == CHUNK_LEN {
+                let cv           = self.chunk.output().chaining_value();
+                let total_chunks = self.chunk.chunk_counter + 1;
+                self.add_chunk_cv(cv, total_chunks);
+                self.chunk = ChunkState::new(total_chunks);
+            }
+
+            // Add as much as we can to the current chunk
+            let to_copy = core::cmp::min(CHUNK_LEN - self.chunk.len(),
+                                         data.len());
+            self.chunk.update(&data[..to_copy]);
+            data = &data[to_copy..];
+        }
+    }
+
+    /// Finish the hash and get the digest
+    pub fn finish(&self) -> [u8; 32] {
+        // Merge the last chunk with all of the incomplete subtrees, from the
+        // right edge of the tree up to the root
+        let mut output = self.chunk.output();
+        for cv in self.cv_stack[..self.cv_stack_len].iter().rev() {
+            output = parent_output(*cv, output.chaining_value());
+        }
+
+        output.root_hash()
+    }
+}
+
+impl Default for Blake3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compute the BLAKE3 digest of `data`
+pub fn blake3(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake3::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Blake3, blake3};
+    use std::vec::Vec;
+
+    /// Parse a hex string into bytes
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2)
+            .map(|ii| u8::from_str_radix(&hex[ii..ii + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Get the input of the official test vectors, which is the repeating
+    /// byte sequence 0, 1, ..., 250 of `len` bytes
+    fn input(len: usize) -> Vec<u8> {
+        (0..len).map(|ii| (ii % 251) as u8).collect()
+    }
+
+    #[test]
+    fn blake3_known_answers() {
+        // Test vectors from the BLAKE3 repository, covering a partial chunk,
+        // a full chunk, and trees of multiple chunks
+        let vectors: &[(usize, &str)] = &[
+            (0,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+            (1,
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+            (1023,
+            "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"),
+            (1024,
+            "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+            (1025,
+            "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+            (2048,
+            "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"),
+            (102400,
+            "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+        ];
+
+        for &(len, digest) in vectors {
+            assert_eq!(&blake3(&input(len))[..], &hex(digest)[..],
+                       "input length {}", len);
+        }
+    }
+
+    #[test]
+    fn blake3_incremental() {
+        let data   = input(5 * 1024 + 1);
+        let digest = blake3(&data);
+
+        // Feed the data in pieces which straddle block and chunk boundaries
+        for &split in &[1, 63, 64, 65, 1023, 1024, 1025, 4097] {
+            let mut hasher = Blake3::new();
+            for piece in data.chunks(split) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finish(), digest);
+        }
+    }
+
+    /// The SSSE3 compression must give the same results as the portable code
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn blake3_ssse3() {
+        if !crate::x86::has_ssse3() {
+            return;
+        }
+
+        let mut seed = 0x243f6a8885a308d3u64;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 32) as u32
+        };
+
+        for _ in 0..1000 {
+            let mut cv    = [0u32; 8];
+            let mut block = [0u32; 16];
+            cv.iter_mut().chain(block.iter_mut()).for_each(|x| *x = next());
+            let counter = (next() as u64) << 32 | next() as u64;
+            let flags   = next() & 0xf;
+
+            let portable = super::compress_portable(&cv, &block, counter, 64,
+                                                    flags);
+            let ssse3 = unsafe {
+                crate::x86::blake3_compress(&cv, &block, counter, 64, flags,
+                    &super::IV, &super::MSG_PERMUTATION)
+            };
+            assert_eq!(portable, ssse3);
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! Cryptographic hash functions shared between the kernel, bootloader, and
+//! build tooling
+//!
+//! The portable implementations only use 32-bit integer operations, such
+//! that they work the same in the i586 bootloader, the kernel, and on the
+//! host. On x86_64 the compression functions use SHA-NI and SSSE3 instead if
+//! `cpuid` reports them.
+
+#![no_std]
+
+mod sha256;
+mod blake3;
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+pub use sha256::{Sha256, sha256, hmac_sha256};
+pub use blake3::{Blake3, blake3};
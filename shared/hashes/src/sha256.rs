@@ -0,0 +1,307 @@
+//! SHA-256 as specified by FIPS 180-4
+
+use core::convert::TryInto;
+
+/// Initial hash state
+const H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Round constants
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5,
+    0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
+    0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+    0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
+    0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5,
+    0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256 hasher
+#[derive(Clone)]
+pub struct Sha256 {
+    /// Current hash state
+    state: [u32; 8],
+
+    /// Partially filled block which has not been compressed yet
+    block: [u8; 64],
+
+    /// Number of valid bytes in `block`
+    block_len: usize,
+
+    /// Total number of bytes hashed
+    length: u64,
+}
+
+impl Sha256 {
+    /// Create a new hasher
+    pub fn new() -> Self {
+        Sha256 {
+            state:     H,
+            block:     [0; 64],
+            block_len: 0,
+            length:    0,
+        }
+    }
+
+    /// Compress a 64-byte block into the hash state, using the SHA extensions
+    /// if the CPU has them
+    fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if crate::x86::has_sha_ni() {
+                unsafe { crate::x86::sha256_compress(state, block, &K); }
+                return;
+            }
+        }
+
+        Self::compress_portable(state, block);
+    }
+
+    /// Compress a 64-byte block into the hash state
+    fn compress_portable(state: &mut [u32; 8], block: &[u8; 64]) {
+        // Compute the message schedule
+        let mut w = [0u32; 64];
+        for ii in 0..16 {
+            w[ii] = u32::from_be_bytes(
+                block[ii * 4..ii * 4 + 4].try_into().unwrap());
+        }
+        for ii in 16..64 {
+            let s0 = w[ii - 15].rotate_right(7) ^
+                w[ii - 15].rotate_right(18) ^ (w[ii - 15] >> 3);
+            let s1 = w[ii - 2].rotate_right(17) ^
+                w[ii - 2].rotate_right(19) ^ (w[ii - 2] >> 10);
+            w[ii] = w[ii - 16].wrapping_add(s0).wrapping_add(w[ii - 7])
+                .wrapping_add(s1);
+        }
+
+        // Perform the rounds
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for ii in 0..64 {
+            let s1  = e.rotate_right(6) ^ e.rotate_right(11) ^
+                e.rotate_right(25);
+            let ch  = (e & f) ^ (!e & g);
+            let t1  = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[ii])
+                .wrapping_add(w[ii]);
+            let s0  = a.rotate_right(2) ^ a.rotate_right(13) ^
+                a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2  = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        // Add the compressed block into the state
+        for (state, val) in state.iter_mut()
+                .zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*val);
+        }
+    }
+
+    /// Add `data` to the hash
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        while !data.is_empty() {
+            // Fill up the current block
+            let to_copy = core::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + to_copy]
+                .copy_from_slice(&data[..to_copy]);
+            self.block_len += to_copy;
+            data = &data[to_copy..];
+
+            // Compress the block once it's full
+            if self.block_len == 64 {
+                Self::compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Finish the hash and get the digest
+    pub fn finish(mut self) -> [u8; 32] {
+        // Get the length of the message in bits, before padding
+        let bits = self.length.wrapping_mul(8);
+
+        // Pad with a one bit, then zeros until there's room for the length
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        // Serialize the state
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compute the SHA-256 digest of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// Compute the HMAC-SHA256 of `data` with `key`, as specified by RFC 2104
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    // Keys longer than a block are hashed, shorter ones are zero padded
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    // Derive the inner and outer padded keys
+    let mut ipad = [0u8; 64];
+    let mut opad = [0u8; 64];
+    for ((ipad, opad), &key) in ipad.iter_mut().zip(opad.iter_mut())
+            .zip(block.iter()) {
+        *ipad = key ^ 0x36;
+        *opad = key ^ 0x5c;
+    }
+
+    // Hash the data with the inner padded key
+    let mut inner = Sha256::new();
+    inner.update(&ipad);
+    inner.update(data);
+    let inner = inner.finish();
+
+    // Hash the inner hash with the outer padded key
+    let mut outer = Sha256::new();
+    outer.update(&opad);
+    outer.update(&inner);
+    outer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Sha256, sha256, hmac_sha256};
+    use std::vec::Vec;
+
+    /// Parse a hex string into bytes
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2)
+            .map(|ii| u8::from_str_radix(&hex[ii..ii + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        // Test vectors from FIPS 180-4 and NIST's examples
+        let a_million = std::vec![b'a'; 1_000_000];
+        let vectors: &[(&[u8], &str)] = &[
+            (b"",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+            (&a_million,
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"),
+        ];
+
+        for &(input, digest) in vectors {
+            assert_eq!(&sha256(input)[..], &hex(digest)[..]);
+        }
+    }
+
+    #[test]
+    fn sha256_incremental() {
+        let data: Vec<u8> = (0..1000u32).map(|x| x as u8).collect();
+        let digest = sha256(&data);
+
+        // Feed the data in pieces which straddle block boundaries
+        for &split in &[1, 55, 56, 63, 64, 65, 127, 999] {
+            let mut hasher = Sha256::new();
+            for piece in data.chunks(split) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finish(), digest);
+        }
+    }
+
+    #[test]
+    fn hmac_sha256_known_answers() {
+        // Test cases 1-4, 6, and 7 from RFC 4231. Test case 5 only checks a
+        // truncated MAC, which is covered by the others.
+        let vectors: &[(Vec<u8>, &[u8], &str)] = &[
+            ([0x0b; 20].to_vec(), b"Hi There",
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe".to_vec(), b"what do ya want for nothing?",
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            ([0xaa; 20].to_vec(), &[0xdd; 50],
+            "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            ((1..=25).collect(), &[0xcd; 50],
+            "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+            ([0xaa; 131].to_vec(),
+             b"Test Using Larger Than Block-Size Key - Hash Key First",
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+            ([0xaa; 131].to_vec(),
+             b"This is a test using a larger than block-size key and a larger \
+               than block-size data. The key needs to be hashed before being \
+               used by the HMAC algorithm.",
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2"),
+        ];
+
+        for (key, data, mac) in vectors {
+            assert_eq!(&hmac_sha256(key, data)[..], &hex(mac)[..]);
+        }
+    }
+
+    /// The SHA extensions must give the same results as the portable code
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sha256_sha_ni() {
+        if !crate::x86::has_sha_ni() {
+            return;
+        }
+
+        let mut seed = 0x243f6a8885a308d3u64;
+        let mut portable = super::H;
+        let mut sha_ni   = super::H;
+        for _ in 0..1000 {
+            let mut block = [0u8; 64];
+            for byte in block.iter_mut() {
+                seed  = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                *byte = (seed >> 56) as u8;
+            }
+
+            Sha256::compress_portable(&mut portable, &block);
+            unsafe {
+                crate::x86::sha256_compress(&mut sha_ni, &block, &super::K);
+            }
+            assert_eq!(portable, sha_ni);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! SIMD implementations of the compression functions for x86_64
+//!
+//! These are only used if `cpuid` reports the CPU supports the instructions
+//! they need, otherwise the portable implementations are used. Only 128-bit
+//! legacy SSE encodings are used, the kernel saves `xmm0` through `xmm15` on
+//! interrupts but not the upper halves of the AVX registers.
+
+use core::arch::x86_64::*;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Value of `FEATURES` before the CPU has been queried
+const UNKNOWN: u32 = !0;
+
+/// SSSE3 is supported, used for BLAKE3
+const SSSE3: u32 = 1 << 0;
+
+/// The SHA extensions, SSSE3, and SSE4.1 are supported, used for SHA-256
+const SHA_NI: u32 = 1 << 1;
+
+/// Features of the CPU we are running on, cached from `cpuid`
+static FEATURES: AtomicU32 = AtomicU32::new(UNKNOWN);
+
+/// Get the features of the CPU relevant to us
+fn features() -> u32 {
+    let features = FEATURES.load(Ordering::Relaxed);
+    if features != UNKNOWN {
+        return features;
+    }
+
+    // `cpuid` is only safe to call in newer versions of `core`
+    let mut features = 0;
+    #[allow(unused_unsafe)]
+    unsafe {
+        let max_cpuid = __cpuid(0).eax;
+        if max_cpuid >= 1 {
+            let ecx   = __cpuid(1).ecx;
+            let ssse3 = ecx & (1 <<  9) != 0;
+            let sse41 = ecx & (1 << 19) != 0;
+            let sha   = max_cpuid >= 7 &&
+                __cpuid_count(7, 0).ebx & (1 << 29) != 0;
+
+            if ssse3 {
+                features |= SSSE3;
+            }
+            if sha && ssse3 && sse41 {
+                features |= SHA_NI;
+            }
+        }
+    }
+
+    // Racing cores compute the same value, so there's nothing to synchronize
+    FEATURES.store(features, Ordering::Relaxed);
+    features
+}
+
+/// Returns `true` if `sha256_compress` can be used
+pub fn has_sha_ni() -> bool {
+    features() & SHA_NI != 0
+}
+
+/// Returns `true` if `blake3_compress` can be used
+pub fn has_ssse3() -> bool {
+    features() & SSSE3 != 0
+}
+
+/// Compute the next 4 words of the SHA-256 message schedule from the
+/// previous 16
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+unsafe fn sha256_schedule(w0: __m128i, w1: __m128i, w2: __m128i,
+                          w3: __m128i) -> __m128i {
+    let tmp = _mm_add_epi32(_mm_sha256msg1_epu32(w0, w1),
+                            _mm_alignr_epi8(w3, w2, 4));
+    _mm_sha256msg2_epu32(tmp, w3)
+}
+
+/// Compress a 64-byte block into the SHA-256 hash state using the SHA
+/// extensions. The caller must make sure `has_sha_ni()` returned `true`.
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+pub unsafe fn sha256_compress(state: &mut [u32; 8], block: &[u8; 64],
+                              k: &[u32; 64]) {
+    // Shuffle mask to byte swap each 32-bit word of the message
+    let bswap = _mm_set_epi64x(0x0c0d0e0f08090a0b, 0x0405060700010203);
+
+    // The rounds instruction wants the state as `ABEF` and `CDGH`
+    let dcba = _mm_loadu_si128(state.as_ptr() as *const __m128i);
+    let hgfe = _mm_loadu_si128(state.as_ptr().add(4) as *const __m128i);
+    let cdab = _mm_shuffle_epi32(dcba, 0xb1);
+    let efgh = _mm_shuffle_epi32(hgfe, 0x1b);
+    let mut abef = _mm_alignr_epi8(cdab, efgh, 8);
+    let mut cdgh = _mm_blend_epi16(efgh, cdab, 0xf0);
+    let (abef_save, cdgh_save) = (abef, cdgh);
+
+    // Load the message, the last 4 entries of the schedule are kept in a ring
+    let mut w = [_mm_setzero_si128(); 4];
+    for (ii, w) in w.iter_mut().enumerate() {
+        *w = _mm_shuffle_epi8(_mm_loadu_si128(
+            block.as_ptr().add(ii * 16) as *const __m128i), bswap);
+    }
+
+    // Perform the rounds, 4 at a time
+    for ii in 0..16 {
+        if ii >= 4 {
+            w[ii % 4] = sha256_schedule(w[ii % 4], w[(ii + 1) % 4],
+                                        w[(ii + 2) % 4], w[(ii + 3) % 4]);
+        }
+
+        let wk = _mm_add_epi32(w[ii % 4],
+            _mm_loadu_si128(k.as_ptr().add(ii * 4) as *const __m128i));
+        cdgh = _mm_sha256rnds2_epu32(cdgh, abef, wk);
+        abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32(wk, 0x0e));
+    }
+
+    // Add the compressed block into the state
+    let abef = _mm_add_epi32(abef, abef_save);
+    let cdgh = _mm_add_epi32(cdgh, cdgh_save);
+
+    // Put the state back in order
+    let feba = _mm_shuffle_epi32(abef, 0x1b);
+    let dchg = _mm_shuffle_epi32(cdgh, 0xb1);
+    let dcba = _mm_blend_epi16(feba, dchg, 0xf0);
+    let hgfe = _mm_alignr_epi8(dchg, feba, 8);
+    _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, dcba);
+    _mm_storeu_si128(state.as_mut_ptr().add(4) as *mut __m128i, hgfe);
+}
+
+/// Rotate each 32-bit lane of `$x` right by `$amount` bits, using byte
+/// shuffles for the rotations which are a multiple of 8
+macro_rules! rotr {
+    ($x:expr, 16) => {
+        _mm_shuffle_epi8($x, _mm_set_epi64x(
+            0x0d0c0f0e09080b0a, 0x0504070601000302))
+    };
+    ($x:expr, 8) => {
+        _mm_shuffle_epi8($x, _mm_set_epi64x(
+            0x0c0f0e0d080b0a09, 0x0407060500030201))
+    };
+    ($x:expr, $amount:expr) => {{
+        let x = $x;
+        _mm_or_si128(_mm_srli_epi32(x, $amount),
+                     _mm_slli_epi32(x, 32 - $amount))
+    }};
+}
+
+/// The BLAKE3 mixing function applied to all 4 columns of the state at once,
+/// with the state held as one row per register
+#[target_feature(enable = "sse2,ssse3")]
+unsafe fn blake3_g(rows: &mut [__m128i; 4], mx: __m128i, my: __m128i) {
+    let [mut a, mut b, mut c, mut d] = *rows;
+
+    a = _mm_add_epi32(_mm_add_epi32(a, b), mx);
+    d = rotr!(_mm_xor_si128(d, a), 16);
+    c = _mm_add_epi32(c, d);
+    b = rotr!(_mm_xor_si128(b, c), 12);
+    a = _mm_add_epi32(_mm_add_epi32(a, b), my);
+    d = rotr!(_mm_xor_si128(d, a), 8);
+    c = _mm_add_epi32(c, d);
+    b = rotr!(_mm_xor_si128(b, c), 7);
+
+    *rows = [a, b, c, d];
+}
+
+/// Gather the 4 message words at `idxs` into a register
+#[target_feature(enable = "sse2")]
+unsafe fn blake3_msg(m: &[u32; 16], idxs: [usize; 4]) -> __m128i {
+    _mm_set_epi32(m[idxs[3]] as i32, m[idxs[2]] as i32,
+                  m[idxs[1]] as i32, m[idxs[0]] as i32)
+}
+
+/// The BLAKE3 compression function using SSSE3. The caller must make sure
+/// `has_ssse3()` returned `true`.
+#[target_feature(enable = "sse2,ssse3")]
+pub unsafe fn blake3_compress(cv: &[u32; 8], block: &[u32; 16],
+                              counter: u64, block_len: u32, flags: u32,
+                              iv: &[u32; 8], permutation: &[usize; 16])
+        -> [u32; 16] {
+    let cv_lo = _mm_loadu_si128(cv.as_ptr() as *const __m128i);
+    let cv_hi = _mm_loadu_si128(cv.as_ptr().add(4) as *const __m128i);
+    let mut rows = [
+        cv_lo,
+        cv_hi,
+        _mm_loadu_si128(iv.as_ptr() as *const __m128i),
+        _mm_set_epi32(flags as i32, block_len as i32,
+                      (counter >> 32) as i32, counter as i32),
+    ];
+
+    // Perform the 7 rounds, permuting the message between each
+    let mut m = *block;
+    for ii in 0..7 {
+        // Mix the columns
+        blake3_g(&mut rows, blake3_msg(&m, [0, 2, 4, 6]),
+                 blake3_msg(&m, [1, 3, 5, 7]));
+
+        // Rotate the rows such that the diagonals become columns, mix them,
+        // and rotate them back
+        rows[1] = _mm_shuffle_epi32(rows[1], 0x39);
+        rows[2] = _mm_shuffle_epi32(rows[2], 0x4e);
+        rows[3] = _mm_shuffle_epi32(rows[3], 0x93);
+        blake3_g(&mut rows, blake3_msg(&m, [8, 10, 12, 14]),
+                 blake3_msg(&m, [9, 11, 13, 15]));
+        rows[1] = _mm_shuffle_epi32(rows[1], 0x93);
+        rows[2] = _mm_shuffle_epi32(rows[2], 0x4e);
+        rows[3] = _mm_shuffle_epi32(rows[3], 0x39);
+
+        if ii != 6 {
+            let mut permuted = [0u32; 16];
+            for (dst, &src) in permuted.iter_mut().zip(permutation.iter()) {
+                *dst = m[src];
+            }
+            m = permuted;
+        }
+    }
+
+    // Fold the state
+    let folded = [
+        _mm_xor_si128(rows[0], rows[2]),
+        _mm_xor_si128(rows[1], rows[3]),
+        _mm_xor_si128(rows[2], cv_lo),
+        _mm_xor_si128(rows[3], cv_hi),
+    ];
+
+    let mut state = [0u32; 16];
+    for (ii, row) in folded.iter().enumerate() {
+        _mm_storeu_si128(state.as_mut_ptr().add(ii * 4) as *mut __m128i,
+                         *row);
+    }
+    state
+}
@@ -0,0 +1,323 @@
+//! Fixed-size histograms with logarithmic buckets
+//!
+//! Values are bucketed by their most significant bits, in the style of an HDR
+//! histogram. Every power of two range is split into `SUB_BUCKETS` linear
+//! buckets, thus any recorded value is known to within 1/16th of its
+//! magnitude, and the histogram covers the whole `u64` range in a fixed
+//! amount of memory.
+
+#![no_std]
+
+use core::fmt;
+
+/// Number of bits of precision kept for every value
+const SUB_BUCKET_BITS: u32 = 4;
+
+/// Number of linear buckets every power of two range is split into
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+
+/// Total number of buckets needed to cover every `u64`
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// A histogram of `u64` values
+#[derive(Clone)]
+pub struct Histogram {
+    /// Number of values recorded in every bucket
+    counts: [u64; BUCKETS],
+
+    /// Total number of values recorded
+    count: u64,
+
+    /// Smallest value recorded
+    min: u64,
+
+    /// Largest value recorded
+    max: u64,
+
+    /// Sum of all values recorded
+    sum: u128,
+}
+
+/// Summary of the distribution of values in a histogram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of values recorded
+    pub count: u64,
+
+    /// Smallest value recorded
+    pub min: u64,
+
+    /// Median value
+    pub p50: u64,
+
+    /// 99th percentile value
+    pub p99: u64,
+
+    /// Largest value recorded
+    pub max: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "n {} | min {} | p50 {} | p99 {} | max {}",
+               self.count, self.min, self.p50, self.p99, self.max)
+    }
+}
+
+/// Get the index of the bucket which holds `value`
+fn bucket(value: u64) -> usize {
+    // Small values get a bucket each
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+
+    // Keep the `SUB_BUCKET_BITS` bits below the most significant bit
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let sub   = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    (shift as usize + 1) * SUB_BUCKETS + sub
+}
+
+/// Get the largest value which falls into the bucket with index `index`
+fn bucket_max(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub   = (index % SUB_BUCKETS) as u64;
+    (((SUB_BUCKETS as u64 | sub) + 1) << shift).wrapping_sub(1)
+}
+
+impl Histogram {
+    /// Create a new, empty histogram
+    pub const fn new() -> Self {
+        Histogram {
+            counts: [0; BUCKETS],
+            count:  0,
+            min:    !0,
+            max:    0,
+            sum:    0,
+        }
+    }
+
+    /// Record `value` into the histogram
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket(value)] += 1;
+        self.count += 1;
+        self.min    = core::cmp::min(self.min, value);
+        self.max    = core::cmp::max(self.max, value);
+        self.sum   += value as u128;
+    }
+
+    /// Add all of the values recorded in `other` to this histogram
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.min    = core::cmp::min(self.min, other.min);
+        self.max    = core::cmp::max(self.max, other.max);
+        self.sum   += other.sum;
+    }
+
+    /// Remove all recorded values
+    pub fn clear(&mut self) {
+        *self = Histogram::new();
+    }
+
+    /// Get the number of values recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Get the smallest value recorded, `None` if nothing was recorded
+    pub fn min(&self) -> Option<u64> {
+        if self.count == 0 { None } else { Some(self.min) }
+    }
+
+    /// Get the largest value recorded, `None` if nothing was recorded
+    pub fn max(&self) -> Option<u64> {
+        if self.count == 0 { None } else { Some(self.max) }
+    }
+
+    /// Get the mean of the values recorded, `None` if nothing was recorded
+    pub fn mean(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some((self.sum / self.count as u128) as u64)
+        }
+    }
+
+    /// Get the value below which `percentile` percent of the recorded values
+    /// fall. This is the largest value of the bucket holding that value,
+    /// clamped to the range of values recorded. Returns `None` if nothing was
+    /// recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        // Get the number of values which must be at or below the result,
+        // rounding up and recording at least one
+        let exact  = self.count as f64 * percentile / 100.;
+        let mut target = exact as u64;
+        if (target as f64) < exact {
+            target += 1;
+        }
+        let target = core::cmp::min(core::cmp::max(target, 1), self.count);
+
+        // Find the bucket holding the `target`th value
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some(core::cmp::max(self.min,
+                    core::cmp::min(self.max, bucket_max(index))));
+            }
+        }
+
+        unreachable!();
+    }
+
+    /// Summarize the distribution of the recorded values
+    pub fn summary(&self) -> Summary {
+        if self.count == 0 {
+            return Summary::default();
+        }
+
+        Summary {
+            count: self.count,
+            min:   self.min,
+            p50:   self.percentile(50.).unwrap(),
+            p99:   self.percentile(99.).unwrap(),
+            max:   self.max,
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Histogram, Summary, bucket, bucket_max, BUCKETS, SUB_BUCKETS};
+
+    #[test]
+    fn bucket_boundaries() {
+        // Small values get a bucket each
+        for value in 0..SUB_BUCKETS as u64 {
+            assert_eq!(bucket(value), value as usize);
+            assert_eq!(bucket_max(value as usize), value);
+        }
+
+        // Every power of two range is split into `SUB_BUCKETS` buckets
+        assert_eq!(bucket(16), 16);
+        assert_eq!(bucket(31), 31);
+        assert_eq!(bucket(32), 32);
+        assert_eq!(bucket(33), 32);
+        assert_eq!(bucket_max(32), 33);
+        assert_eq!(bucket(34), 33);
+
+        // The last bucket ends at the largest `u64`
+        assert_eq!(bucket(!0), BUCKETS - 1);
+        assert_eq!(bucket_max(BUCKETS - 1), !0);
+
+        // Every value falls in the bucket whose range covers it, check around
+        // every power of two
+        for bit in 0..64 {
+            let pow = 1u64 << bit;
+            for &value in &[pow - 1, pow, pow + 1, pow | (pow / 3)] {
+                let index = bucket(value);
+                assert!(bucket_max(index) >= value, "value {}", value);
+                if index > 0 {
+                    assert!(bucket_max(index - 1) < value, "value {}", value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn percentile_rounding() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(50.), None);
+
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+
+        // Percentiles are the largest value of the bucket holding the target
+        // value, values below `SUB_BUCKETS` are exact
+        assert_eq!(histogram.percentile(1.),   Some(1));
+        assert_eq!(histogram.percentile(10.),  Some(10));
+        assert_eq!(histogram.percentile(50.),  Some(51));
+        assert_eq!(histogram.percentile(99.),  Some(99));
+        assert_eq!(histogram.percentile(100.), Some(100));
+
+        // Fractional targets round up, and at least one value is targeted
+        assert_eq!(histogram.percentile(10.5), Some(11));
+        assert_eq!(histogram.percentile(0.),   Some(1));
+
+        // Results are clamped to the recorded range
+        let mut histogram = Histogram::new();
+        histogram.record(1000);
+        assert_eq!(histogram.percentile(50.), Some(1000));
+    }
+
+    #[test]
+    fn merge() {
+        let mut low  = Histogram::new();
+        let mut high = Histogram::new();
+        for value in 0..50 {
+            low.record(value);
+            high.record(value + 50);
+        }
+
+        let mut merged = low.clone();
+        merged.merge(&high);
+        assert_eq!(merged.count(), 100);
+        assert_eq!(merged.min(),   Some(0));
+        assert_eq!(merged.max(),   Some(99));
+        assert_eq!(merged.mean(),  Some(49));
+
+        // Merging is the same as recording everything into one histogram
+        let mut all = Histogram::new();
+        for value in 0..100 {
+            all.record(value);
+        }
+        assert_eq!(merged.summary(), all.summary());
+
+        // Merging an empty histogram changes nothing
+        merged.merge(&Histogram::new());
+        assert_eq!(merged.summary(), all.summary());
+
+        merged.clear();
+        assert_eq!(merged.count(), 0);
+        assert_eq!(merged.min(),   None);
+    }
+
+    #[test]
+    fn summary() {
+        assert_eq!(Histogram::default().summary(), Summary::default());
+
+        let mut histogram = Histogram::new();
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        let summary = histogram.summary();
+        assert_eq!(summary, Summary {
+            count: 100,
+            min:   1,
+            p50:   51,
+            p99:   99,
+            max:   100,
+        });
+        assert_eq!(std::format!("{}", summary),
+                   "n 100 | min 1 | p50 51 | p99 99 | max 100");
+    }
+}
@@ -6,6 +6,8 @@
 use core::ops::{Deref, DerefMut};
 use core::cell::UnsafeCell;
 use core::marker::PhantomData;
+#[cfg(feature = "hold_budget")]
+use core::panic::Location;
 use core::sync::atomic::{AtomicU32, Ordering, spin_loop_hint};
 
 /// Trait that allows access to OS-level constructs defining interrupt state,
@@ -19,6 +21,10 @@ pub trait InterruptState {
     /// a lock cannot be held as we may have pre-empted a non-preemptable lock
     fn in_exception() -> bool;
 
+    /// Returns the number of interrupts which are currently nested on this
+    /// core, `0` if we're not in an interrupt
+    fn interrupt_depth() -> u32;
+
     /// Gets the ID of the running core. It's required that this core ID is
     /// unique to the core, and cannot be `!0`
     fn core_id() -> u32;
@@ -34,6 +40,45 @@ pub trait InterruptState {
     /// of the interrupt status. Eg. using a refcount of number of interrupt
     /// disable requests
     fn exit_lock();
+
+    /// Maximum number of timestamp units a lock which disables interrupts
+    /// may be held for before it's considered a bug, `None` if unlimited.
+    /// Only checked with the `hold_budget` feature.
+    fn hold_budget() -> Option<u64> { None }
+
+    /// Get the current timestamp, used to measure how long locks are held.
+    /// Only used with the `hold_budget` feature.
+    fn timestamp() -> u64 { 0 }
+
+    /// A lock was released after being waited on for `waited` and held for
+    /// `held` timestamp units. This is called after the lock is released,
+    /// thus it may take locks itself, but then it must not recurse on the
+    /// locks it takes. Only called with the `hold_budget` feature.
+    fn lock_released(_waited: u64, _held: u64) {}
+}
+
+/// Type-erased view of the state of a `LockCell`, such that locks guarding
+/// different types can be inspected together
+pub trait LockState: Sync {
+    /// Returns `true` if the lock is currently held
+    fn is_locked(&self) -> bool;
+
+    /// Returns the ID of the core holding the lock, `None` if the lock is not
+    /// held or the holder has not recorded itself yet
+    fn holder(&self) -> Option<u32>;
+
+    /// Forcibly release the lock, regardless of who holds it. This does not
+    /// re-enable interrupts on the holder, and anyone waiting on a ticket for
+    /// the lock will never get it. This is only for use once the holders and
+    /// waiters of the lock will never run again, eg. during a soft reboot.
+    unsafe fn force_release(&self);
+
+    /// Release the lock if it is held by the core with ID `core_id`,
+    /// returning `true` if it was. Unlike `force_release` this only releases
+    /// the holder's ticket, thus waiters still get the lock in order. This is
+    /// only for use once the holder will never release the lock itself, eg.
+    /// when it's parked with a lock it leaked.
+    unsafe fn release_holder(&self, core_id: u32) -> bool;
 }
 
 /// A spinlock-guarded variable
@@ -111,6 +156,10 @@ impl<T: ?Sized, I: InterruptState> LockCell<T, I> {
         // Get the core ID of the running core
         let core_id = I::core_id();
 
+        // Note when we started waiting for the lock
+        #[cfg(feature = "hold_budget")]
+        let requested = I::timestamp();
+
         // Disable interrupts if needed
         if self.disables_interrupts {
             I::enter_lock();
@@ -151,9 +200,20 @@ impl<T: ?Sized, I: InterruptState> LockCell<T, I> {
         // Note that this core owns the lock
         self.owner.store(core_id, Ordering::SeqCst);
 
+        // Note when we got the lock
+        #[cfg(feature = "hold_budget")]
+        let acquired = I::timestamp();
+
         // At this point we have exclusive access
         Some(LockCellGuard {
-            cell: self,
+            cell:            self,
+            interrupt_depth: I::interrupt_depth(),
+            #[cfg(feature = "hold_budget")]
+            waited:          acquired.wrapping_sub(requested),
+            #[cfg(feature = "hold_budget")]
+            acquired,
+            #[cfg(feature = "hold_budget")]
+            location:        Location::caller(),
         })
     }
 
@@ -170,6 +230,57 @@ impl<T: ?Sized, I: InterruptState> LockCell<T, I> {
         self.lock_int(true)
     }
 
+    /// Attempt to read a copy of the value without taking the lock. The
+    /// ticket counter doubles as a sequence count: if the lock is not held
+    /// before the read and no ticket was taken by the end of it, nobody
+    /// could have modified the value while we read it. Returns `None` if the
+    /// lock was held or taken during the read.
+    pub fn peek(&self) -> Option<T> where T: Copy {
+        // Make sure nobody holds the lock
+        let release = self.release.load(Ordering::SeqCst);
+        if self.ticket.load(Ordering::SeqCst) != release {
+            return None;
+        }
+
+        // Read the value, it may be torn if someone took the lock meanwhile
+        let val = unsafe { core::ptr::read_volatile(self.val.get()) };
+
+        // Make sure nobody took the lock while we were reading
+        if self.ticket.load(Ordering::SeqCst) != release {
+            return None;
+        }
+
+        Some(val)
+    }
+
+    /// Check the value with `check` without taking the lock, and only take
+    /// the lock if `check` returns `true`. This is for values which are
+    /// frequently checked but rarely need to be updated.
+    ///
+    /// If the value can't be read without the lock, the lock is taken to
+    /// perform the check. Once the lock is held, `check` is invoked again as
+    /// the value may have changed since it was read. Returns the guard if the
+    /// final check returned `true`, otherwise the lock is not held on return.
+    #[track_caller]
+    pub fn read_unlocked_then_lock_if<F>(&self, check: F)
+            -> Option<LockCellGuard<T, I>>
+            where T: Copy, F: Fn(&T) -> bool {
+        // Check the value without the lock if we can
+        if let Some(val) = self.peek() {
+            if !check(&val) {
+                return None;
+            }
+        }
+
+        // Take the lock and check again with the current value
+        let guard = self.lock();
+        if check(&*guard) {
+            Some(guard)
+        } else {
+            None
+        }
+    }
+
     /// Return a raw pointer to the internal locked value, regardless of the
     /// lock state. This bypasses the lock.
     pub unsafe fn shatter(&self) -> *mut T {
@@ -177,15 +288,85 @@ impl<T: ?Sized, I: InterruptState> LockCell<T, I> {
     }
 }
 
+impl<T: ?Sized, I: InterruptState> LockState for LockCell<T, I> {
+    fn is_locked(&self) -> bool {
+        self.ticket.load(Ordering::SeqCst) !=
+            self.release.load(Ordering::SeqCst)
+    }
+
+    fn holder(&self) -> Option<u32> {
+        // The owner is only valid while the lock is held
+        let owner = self.owner.load(Ordering::SeqCst);
+        if self.is_locked() && owner != !0 {
+            Some(owner)
+        } else {
+            None
+        }
+    }
+
+    unsafe fn force_release(&self) {
+        self.owner.store(!0, Ordering::SeqCst);
+        self.release.store(self.ticket.load(Ordering::SeqCst),
+                           Ordering::SeqCst);
+    }
+
+    unsafe fn release_holder(&self, core_id: u32) -> bool {
+        if self.holder() != Some(core_id) {
+            return false;
+        }
+
+        self.owner.store(!0, Ordering::SeqCst);
+        self.release.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+}
+
 /// A guard structure which can implement `Drop` such that locks can be
 /// automatically released based on scope.
 pub struct LockCellGuard<'a, T: ?Sized, I: InterruptState> {
     /// A reference to the value we currently have exclusive access to
     cell: &'a LockCell<T, I>,
+
+    /// Interrupt nesting depth at the time the lock was taken
+    interrupt_depth: u32,
+
+    /// Number of timestamp units spent waiting for the lock
+    #[cfg(feature = "hold_budget")]
+    waited: u64,
+
+    /// Timestamp at which the lock was taken
+    #[cfg(feature = "hold_budget")]
+    acquired: u64,
+
+    /// Location in the source which took the lock
+    #[cfg(feature = "hold_budget")]
+    location: &'static Location<'static>,
+}
+
+#[cfg(feature = "hold_budget")]
+impl<'a, T: ?Sized, I: InterruptState> LockCellGuard<'a, T, I> {
+    /// Panic if this guard disabled interrupts for `held` timestamp units,
+    /// which is longer than the budget
+    fn check_hold_budget(&self, held: u64) {
+        if !self.cell.disables_interrupts { return; }
+
+        if let Some(budget) = I::hold_budget() {
+            assert!(held <= budget,
+                "No-preempt lock taken at {} held for {} (budget {})",
+                self.location, held, budget);
+        }
+    }
 }
 
 impl<'a, T: ?Sized, I: InterruptState> Drop for LockCellGuard<'a, T, I> {
     fn drop(&mut self) {
+        // Get the interrupt nesting level we're releasing the lock at
+        let interrupt_depth = I::interrupt_depth();
+
+        // Get how long the lock was held for
+        #[cfg(feature = "hold_budget")]
+        let held = I::timestamp().wrapping_sub(self.acquired);
+
         // Set that there is no owner of the lock
         self.cell.owner.store(!0, Ordering::SeqCst);
 
@@ -196,6 +377,21 @@ impl<'a, T: ?Sized, I: InterruptState> Drop for LockCellGuard<'a, T, I> {
         if self.cell.disables_interrupts {
             I::exit_lock();
         }
+
+        // Make sure the lock is released at the same interrupt nesting level
+        // it was taken at. Otherwise the interrupt disable refcount taken on
+        // acquisition is released by someone else's context. This is checked
+        // after the lock is released as the panic may need the lock.
+        assert!(interrupt_depth == self.interrupt_depth,
+            "Lock released at a different interrupt depth than it was taken");
+
+        // Make sure interrupts weren't disabled for too long. This is checked
+        // after the lock is released as the panic may need the lock.
+        #[cfg(feature = "hold_budget")]
+        {
+            self.check_hold_budget(held);
+            I::lock_released(self.waited, held);
+        }
     }
 }
 
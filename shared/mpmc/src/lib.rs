@@ -0,0 +1,298 @@
+//! Bounded multi-producer multi-consumer queue
+//!
+//! This is Dmitry Vyukov's bounded MPMC queue. Every slot in the ring has a
+//! sequence number which tells producers and consumers whose turn it is to
+//! use the slot, thus a push or a pop only contends on a single atomic
+//! position and never takes a lock.
+
+#![no_std]
+
+extern crate alloc;
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// A slot in the ring
+struct Slot<T> {
+    /// Position the slot is ready for. If this equals the enqueue position
+    /// the slot is free for a producer, if this is one past the dequeue
+    /// position the slot holds a value for a consumer.
+    sequence: AtomicUsize,
+
+    /// Value in the slot, only initialized while the slot holds a value
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A fixed-capacity, lock-free queue with any number of producers and
+/// consumers
+pub struct Queue<T> {
+    /// Ring of slots
+    slots: Box<[Slot<T>]>,
+
+    /// Mask to turn a position into an index into `slots`
+    mask: usize,
+
+    /// Position of the next value to push
+    enqueue_pos: AtomicUsize,
+
+    /// Position of the next value to pop
+    dequeue_pos: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    /// Create a new queue which holds up to `capacity` values. `capacity`
+    /// must be a power of two of at least 2, with a single slot a full slot
+    /// would look free to the producer a lap later.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity >= 2 && capacity.is_power_of_two(),
+                "Queue capacity must be a power of two of at least 2");
+
+        let slots: Vec<Slot<T>> = (0..capacity).map(|ii| {
+            Slot {
+                sequence: AtomicUsize::new(ii),
+                value:    UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }).collect();
+
+        Queue {
+            slots:       slots.into_boxed_slice(),
+            mask:        capacity - 1,
+            enqueue_pos: AtomicUsize::new(0),
+            dequeue_pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the maximum number of values the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Attempt to push `val` to the queue. If the queue is full, `val` is
+    /// given back as the error.
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq  = slot.sequence.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos) as isize;
+
+            if diff == 0 {
+                // The slot is free, try to claim the position
+                match self.enqueue_pos.compare_exchange_weak(
+                        pos, pos.wrapping_add(1),
+                        Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // We own the slot, fill it and hand it to consumers
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(val); }
+                        slot.sequence.store(pos.wrapping_add(1),
+                                            Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // The slot still holds the value from a lap ago, we're full
+                return Err(val);
+            } else {
+                // Another producer claimed this position, catch up
+                pos = self.enqueue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Attempt to pop a value from the queue, returns `None` if the queue is
+    /// empty
+    pub fn try_pop(&self) -> Option<T> {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq  = slot.sequence.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_add(1)) as isize;
+
+            if diff == 0 {
+                // The slot holds a value, try to claim the position
+                match self.dequeue_pos.compare_exchange_weak(
+                        pos, pos.wrapping_add(1),
+                        Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // We own the value, take it and free the slot for
+                        // the producer a lap from now
+                        let val = unsafe {
+                            (*slot.value.get()).as_ptr().read()
+                        };
+                        slot.sequence.store(
+                            pos.wrapping_add(self.mask).wrapping_add(1),
+                            Ordering::Release);
+                        return Some(val);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // Nothing has been pushed to this slot yet, we're empty
+                return None;
+            } else {
+                // Another consumer claimed this position, catch up
+                pos = self.dequeue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Push `val` to the queue, spinning until there is room for it
+    pub fn push(&self, mut val: T) {
+        loop {
+            match self.try_push(val) {
+                Ok(())   => return,
+                Err(ret) => val = ret,
+            }
+            spin_loop();
+        }
+    }
+
+    /// Pop a value from the queue, spinning until there is one
+    pub fn pop(&self) -> T {
+        loop {
+            if let Some(val) = self.try_pop() {
+                return val;
+            }
+            spin_loop();
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // Drop all values which are still queued
+        while self.try_pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::Queue;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn fifo() {
+        let queue = Queue::new(4);
+        assert_eq!(queue.capacity(), 4);
+        assert_eq!(queue.try_pop(), None);
+
+        // Go around the ring a few times
+        for lap in 0..3 {
+            for ii in 0..4 {
+                assert_eq!(queue.try_push(lap * 4 + ii), Ok(()));
+            }
+            assert_eq!(queue.try_push(100), Err(100));
+
+            for ii in 0..4 {
+                assert_eq!(queue.try_pop(), Some(lap * 4 + ii));
+            }
+            assert_eq!(queue.try_pop(), None);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn capacity_one() {
+        Queue::<u32>::new(1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn capacity_not_power_of_two() {
+        Queue::<u32>::new(6);
+    }
+
+    #[test]
+    fn drop_queued() {
+        let value = Arc::new(());
+        {
+            let queue = Queue::new(8);
+            for _ in 0..5 {
+                queue.push(value.clone());
+            }
+            drop(queue.pop());
+            assert_eq!(Arc::strong_count(&value), 5);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn stress() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const PER_PRODUCER: usize = 20_000;
+
+        // A small queue, such that producers regularly find it full
+        let queue = Arc::new(Queue::new(16));
+        let popped = Arc::new(AtomicUsize::new(0));
+
+        let producers: Vec<_> = (0..PRODUCERS).map(|producer| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for ii in 0..PER_PRODUCER {
+                    let mut val = producer * PER_PRODUCER + ii;
+                    while let Err(ret) = queue.try_push(val) {
+                        val = ret;
+                        thread::yield_now();
+                    }
+                }
+            })
+        }).collect();
+
+        let consumers: Vec<_> = (0..CONSUMERS).map(|_| {
+            let queue  = queue.clone();
+            let popped = popped.clone();
+            thread::spawn(move || {
+                let mut seen = Vec::new();
+                while popped.load(Ordering::SeqCst) < PRODUCERS * PER_PRODUCER {
+                    match queue.try_pop() {
+                        Some(val) => {
+                            seen.push(val);
+                            popped.fetch_add(1, Ordering::SeqCst);
+                        }
+                        None => thread::yield_now(),
+                    }
+                }
+                seen
+            })
+        }).collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // Every value must have been popped exactly once, and values from the
+        // same producer must come out in the order they were pushed
+        let mut all = Vec::new();
+        for consumer in consumers {
+            let seen = consumer.join().unwrap();
+            for producer in 0..PRODUCERS {
+                let range = producer * PER_PRODUCER..
+                    (producer + 1) * PER_PRODUCER;
+                let ours: Vec<_> = seen.iter()
+                    .filter(|x| range.contains(x)).collect();
+                assert!(ours.windows(2).all(|x| x[0] < x[1]));
+            }
+            all.extend(seen);
+        }
+
+        all.sort();
+        assert_eq!(all, (0..PRODUCERS * PER_PRODUCER).collect::<Vec<_>>());
+        assert_eq!(queue.try_pop(), None);
+    }
+}
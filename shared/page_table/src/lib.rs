@@ -77,6 +77,16 @@ impl Mapping {
         if self.pte.is_none() { return Some(PageType::Page2M); }
         Some(PageType::Page4K)
     }
+
+    /// Get the physical address of the final page table entry which maps this
+    /// page
+    pub fn entry(&self) -> Option<PhysAddr> {
+        match self.size()? {
+            PageType::Page1G => self.pdpe,
+            PageType::Page2M => self.pde,
+            PageType::Page4K => self.pte,
+        }
+    }
 }
 
 /// A strongly typed physical address. This is effectively just an integer but
@@ -262,6 +272,23 @@ impl PageTable {
     /// the table and also freed.
     pub unsafe fn free<P: PhysMem>(&mut self, phys_mem: &mut P,
                                    vaddr: VirtAddr, size: u64) {
+        self.unmap_int(phys_mem, vaddr, size, true);
+    }
+
+    /// Unmap the virtual memory region indicated by `vaddr` and `size`. This
+    /// is identical to `free`, except the pages which backed the region are
+    /// not freed. This is used for mappings of memory which is owned by
+    /// someone else.
+    pub unsafe fn unmap<P: PhysMem>(&mut self, phys_mem: &mut P,
+                                    vaddr: VirtAddr, size: u64) {
+        self.unmap_int(phys_mem, vaddr, size, false);
+    }
+
+    /// Unmap the virtual memory region indicated by `vaddr` and `size`,
+    /// freeing the backing pages if `free_pages` is set
+    unsafe fn unmap_int<P: PhysMem>(&mut self, phys_mem: &mut P,
+                                    vaddr: VirtAddr, size: u64,
+                                    free_pages: bool) {
         // Determine the end of the mapping
         let end = vaddr.0.checked_add(
             size.checked_sub(1).expect("Virtual free of zero bytes"))
@@ -372,8 +399,10 @@ impl PageTable {
             }
 
             // Free the page
-            phys_mem.free_phys(
-                cur_page.page.unwrap().0, page_size as u64);
+            if free_pages {
+                phys_mem.free_phys(
+                    cur_page.page.unwrap().0, page_size as u64);
+            }
             
             // Accumulate the amount of virtual memory we're freeing
             freed += cur_page.size().unwrap() as u64;
@@ -424,6 +453,69 @@ impl PageTable {
         }
     }
 
+    /// Update the permissions of every page in the region starting at `vaddr`
+    /// for `size` bytes. `write` and `exec` will be used as the new
+    /// permission bits.
+    ///
+    /// If any part of the region is not mapped, or a page in the region is
+    /// not entirely contained in the region, this will return `None` and the
+    /// page table will not be modified.
+    pub unsafe fn protect<P: PhysMem>(&mut self, phys_mem: &mut P,
+            vaddr: VirtAddr, size: u64, write: bool, exec: bool)
+            -> Option<()> {
+        // Determine the end of the region
+        let end = vaddr.0.checked_add(size.checked_sub(1)?)?;
+
+        // Make two passes over the region. The first pass validates that the
+        // entire region can be updated, the second pass actually updates the
+        // page table entries. This ensures we never partially update the
+        // permissions of a region.
+        for &update in &[false, true] {
+            let mut cur = vaddr.0;
+
+            loop {
+                // Get the page which maps the current address
+                let mapping   = self.translate(phys_mem, VirtAddr(cur))?;
+                let page_base = mapping.virt_base()?;
+                let page_size = mapping.size()? as u64;
+
+                // Compute the last byte of this page
+                let page_end = page_base.0.checked_add(page_size - 1)?;
+
+                // Make sure the page is entirely within the region
+                if page_base.0 != cur || page_end > end {
+                    return None;
+                }
+
+                if update {
+                    // Get access to the entry which maps this page
+                    let ptr = phys_mem.translate(mapping.entry()?,
+                        size_of::<u64>()) as *mut u64;
+
+                    // Update the permission bits
+                    let ent = core::ptr::read(ptr) & !(PAGE_WRITE | PAGE_NX);
+                    let ent = ent |
+                        if write { PAGE_WRITE } else { 0 } |
+                        if exec  { 0 } else { PAGE_NX };
+                    core::ptr::write(ptr, ent);
+                }
+
+                // Stop when we've reached the end of the region
+                if page_end == end { break; }
+                cur = page_end + 1;
+            }
+        }
+
+        // If we modified the active page table, invalidate the TLB such that
+        // the new permissions take effect
+        let cur_cr3 = cpu::read_cr3();
+        if (cur_cr3 & !0xfff) == self.table().0 {
+            cpu::write_cr3(cur_cr3);
+        }
+
+        Some(())
+    }
+
     /// Translate a virtual address in the `self` page table into its
     /// components. This will include entries for every level in the table as
     /// well as the final page result if the page is mapped and present.
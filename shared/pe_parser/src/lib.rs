@@ -1,5 +1,4 @@
-//! PE parser for basic x86_64 and i386 support. Currently this does not
-//! support relocations.
+//! PE parser for basic x86_64 and i386 support
 
 #![no_std]
 
@@ -12,6 +11,31 @@ const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
 const IMAGE_SCN_MEM_READ:    u32 = 0x40000000;
 const IMAGE_SCN_MEM_WRITE:   u32 = 0x80000000;
 
+/// Characteristic indicating relocation information was stripped from the file
+const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+
+/// Index into the optional header data directories for base relocations
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+
+/// Base relocation which is skipped, used for padding
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+
+/// Base relocation applying the full 32-bit delta to a 32-bit field
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+
+/// Base relocation applying the full 64-bit delta to a 64-bit field
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// Different types of base relocations which we support
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Relocation {
+    /// Add the delta to the 32-bit value at the location
+    HighLow,
+
+    /// Add the delta to the 64-bit value at the location
+    Dir64,
+}
+
 /// A validated PE file that has had some basic information parsed out of it.
 /// You can use functions on this structure to extract things like sections.
 pub struct PeParser<'a> {
@@ -25,7 +49,11 @@ pub struct PeParser<'a> {
     section_off: usize,
 
     /// Base of the image
-    image_base: u64,
+    pub image_base: u64,
+
+    /// Relative virtual address and size of the base relocation directory.
+    /// `None` if the image has no relocations.
+    reloc_dir: Option<(u32, u32)>,
 
     /// Virtual address of the entry point
     pub entry_point: u64,
@@ -72,6 +100,10 @@ impl<'a> PeParser<'a> {
         let opt_header_size: usize = u16::from_le_bytes(
             bytes[pe_offset + 0x14..pe_offset + 0x16].try_into().ok()?)
             .try_into().ok()?;
+
+        // Get the file characteristics
+        let characteristics = u16::from_le_bytes(
+            bytes[pe_offset + 0x16..pe_offset + 0x18].try_into().ok()?);
         
         // Get the base for the program
         let image_base = if machine == IMAGE_FILE_MACHINE_I386 {
@@ -101,15 +133,149 @@ impl<'a> PeParser<'a> {
             return None;
         }
 
+        // Get the offset into the optional header of the data directories,
+        // and of the number of data directories
+        let (dirs_off, num_dirs_off) = if machine == IMAGE_FILE_MACHINE_I386 {
+            (0x60, 0x5c)
+        } else {
+            (0x70, 0x6c)
+        };
+
+        // Find the base relocation directory, if the image has one
+        let reloc_dir = if (characteristics & IMAGE_FILE_RELOCS_STRIPPED) == 0
+                && opt_header_size >= dirs_off {
+            // Get the number of data directories
+            let opt_header = &bytes[pe_offset + 0x18..
+                pe_offset + 0x18 + opt_header_size];
+            let num_dirs = u32::from_le_bytes(
+                opt_header.get(num_dirs_off..num_dirs_off + 4)?
+                .try_into().ok()?) as usize;
+
+            // Get the base relocation directory
+            let entry = dirs_off + IMAGE_DIRECTORY_ENTRY_BASERELOC * 8;
+            if num_dirs > IMAGE_DIRECTORY_ENTRY_BASERELOC {
+                let rva = u32::from_le_bytes(
+                    opt_header.get(entry..entry + 4)?.try_into().ok()?);
+                let size = u32::from_le_bytes(
+                    opt_header.get(entry + 4..entry + 8)?.try_into().ok()?);
+
+                if rva != 0 && size != 0 { Some((rva, size)) } else { None }
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
         Some(PeParser {
             bytes,
             image_base,
+            reloc_dir,
             num_sections,
             entry_point,
             section_off: pe_offset + 0x18 + opt_header_size,
         })
     }
 
+    /// Returns `true` if the image contains relocation information and thus
+    /// can be loaded at a base other than `image_base`
+    pub fn relocatable(&self) -> bool {
+        self.reloc_dir.is_some()
+    }
+
+    /// Convert a relative virtual address into an offset into the raw PE
+    /// file. Returns `None` if the RVA is not backed by raw bytes in the file
+    /// for at least `size` bytes.
+    fn rva_to_offset(&self, rva: u32, size: u32) -> Option<usize> {
+        let bytes = self.bytes;
+
+        for section in 0..self.num_sections {
+            let off = self.section_off + section * 0x28;
+
+            // Get the virtual address and raw size and offset
+            let virt_addr = u32::from_le_bytes(
+                bytes[off + 0xc..off + 0x10].try_into().ok()?);
+            let raw_size = u32::from_le_bytes(
+                bytes[off + 0x10..off + 0x14].try_into().ok()?);
+            let raw_off = u32::from_le_bytes(
+                bytes[off + 0x14..off + 0x18].try_into().ok()?);
+
+            // Check if the RVA falls within the raw bytes of this section
+            if rva >= virt_addr &&
+                    rva.checked_add(size)? <= virt_addr.checked_add(raw_size)? {
+                let offset: usize =
+                    raw_off.checked_add(rva - virt_addr)?.try_into().ok()?;
+
+                // Make sure the bytes are actually present in the file
+                if offset.checked_add(size as usize)? > bytes.len() {
+                    return None;
+                }
+
+                return Some(offset);
+            }
+        }
+
+        None
+    }
+
+    /// Invoke a closure with the format (offset into raw PE file, type) for
+    /// each base relocation in the PE file. Relocations are always applied to
+    /// initialized data, thus the returned offset is where the value to fix
+    /// up lives in the raw file bytes.
+    ///
+    /// Returns `None` if the relocation information was malformed or if the
+    /// closure returned `None`.
+    pub fn relocations<F>(&self, mut func: F) -> Option<()>
+            where F: FnMut(usize, Relocation) -> Option<()> {
+        // If there are no relocations, there is nothing to do
+        let (rva, size) = if let Some(dir) = self.reloc_dir {
+            dir
+        } else {
+            return Some(());
+        };
+
+        // Get the raw bytes of the relocation directory
+        let dir_off = self.rva_to_offset(rva, size)?;
+        let dir = &self.bytes[dir_off..dir_off + size as usize];
+
+        // Go through each relocation block
+        let mut block = dir;
+        while block.len() >= 8 {
+            // Parse the block header
+            let page_rva = u32::from_le_bytes(block[0..4].try_into().ok()?);
+            let block_size: usize = u32::from_le_bytes(
+                block[4..8].try_into().ok()?).try_into().ok()?;
+
+            // Get the entries in this block
+            let entries = block.get(8..block_size)?;
+
+            for entry in entries.chunks_exact(2) {
+                let entry = u16::from_le_bytes(entry.try_into().ok()?);
+
+                // Split the entry into the type and page offset
+                let typ    = entry >> 12;
+                let offset = (entry & 0xfff) as u32;
+
+                // Determine the relocation type and the size of the field
+                let (reloc, reloc_size) = match typ {
+                    IMAGE_REL_BASED_ABSOLUTE => continue,
+                    IMAGE_REL_BASED_HIGHLOW  => (Relocation::HighLow, 4),
+                    IMAGE_REL_BASED_DIR64    => (Relocation::Dir64,   8),
+                    _ => return None,
+                };
+
+                // Invoke the closure with the raw file offset
+                func(self.rva_to_offset(page_rva.checked_add(offset)?,
+                                        reloc_size)?, reloc)?;
+            }
+
+            // Go to the next block
+            block = block.get(block_size..)?;
+        }
+
+        Some(())
+    }
+
     /// Invoke a closure with the format
     /// (virtual addr, virtual size, raw initialize bytes,
     ///  read, write, execute) for each section in the PE file
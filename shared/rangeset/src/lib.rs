@@ -179,6 +179,12 @@ impl RangeSet {
         }
     }
 
+    /// Get the first range in the set which overlaps `range`, returning the
+    /// overlapping part. Returns `None` if nothing in the set overlaps.
+    pub fn overlap(&self, range: Range) -> Option<Range> {
+        self.entries().iter().find_map(|&ent| overlaps(ent, range))
+    }
+
     /// Compute the size of the range covered by this rangeset
     pub fn sum(&self) -> Option<u64> {
         self.entries().iter().try_fold(0u64, |acc, x| {
@@ -136,6 +136,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
+    // Get the kernel feature profile to build with, if one was specified
+    let profile = match args.get(1).map(|x| x.as_str()) {
+        None => "fuzz_worker",
+        Some(x @ "fuzz_worker") | Some(x @ "triage") |
+            Some(x @ "net_test") => x,
+        Some(_) => return Err("Unknown kernel profile, expected \
+            fuzz_worker, triage, or net_test".into()),
+    };
+
     // Check for nasm
     check_install("nasm", &["-v"], &["NASM version"])
         .ok_or("nasm not present in the path")?;
@@ -230,7 +239,8 @@ fn main() -> Result<(), Box<dyn Error>> {
             .current_dir("kernel")
             .args(&[
                 "build", "--release", "--target-dir",
-                kernel_build_dir.to_str().unwrap()
+                kernel_build_dir.to_str().unwrap(),
+                "--no-default-features", "--features", profile,
             ]).status()?.success() {
         return Err("Failed to kernel".into());
     }
@@ -238,7 +248,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Deploy the images to the PXE directory
     std::fs::create_dir_all("pxe")?;
     std::fs::copy(bootfile, Path::new("pxe").join("chocolate_milk.boot"))?;
-    std::fs::copy(kernel_exe, Path::new("pxe").join("chocolate_milk.kern"))?;
+    std::fs::copy(&kernel_exe,
+        Path::new("pxe").join("chocolate_milk.kern"))?;
+
+    // Deploy the SHA-256 of the kernel such that the bootloader can verify
+    // the kernel it downloaded
+    std::fs::write(Path::new("pxe").join("chocolate_milk.kern.sha256"),
+        hashes::sha256(&std::fs::read(&kernel_exe)?))?;
 
     Ok(())
 }